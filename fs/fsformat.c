@@ -7,6 +7,7 @@
 #include <string.h>
 #include <fcntl.h>
 #include <assert.h>
+#include <getopt.h>
 
 #define stat xv6_stat  // avoid clash with host struct stat
 // #include <inc/types.h>
@@ -21,33 +22,42 @@ typedef unsigned int uint32_t;
 #define static_assert(a, b) do { switch (0) case 0: case (a): ; } while (0)
 #endif
 
-#define NINODES 200
+#define DEFAULT_FSSIZE 16384
+#define DEFAULT_NINODES 200
+#define DEFAULT_BSIZE 512
 
 // Disk layout:
 // name (# of blocks)
-// [ boot block (1) | sb block (1) | log (sb.nlog = LOGSIZE) |
+// [ boot block (1) | sb block (1) | log (sb.nlog = 1 + LOGSIZE) |
 //   inode blocks (sb.ninodes) | free bit map (nbitmap) | data blocks (sb.nblocks) ]
-
-int nbitmap = FSSIZE/(BLKSIZE*8) + 1;
-int ninodeblocks = NINODES / IPB + 1;
-int nlog = LOGSIZE;
+// The super block itself is written at sector SBSECTOR, which is inside
+// the boot block when a block is larger than a sector.
+
+uint fssize = DEFAULT_FSSIZE;   // size of the image in blocks (-s)
+uint ninodes = DEFAULT_NINODES; // number of inodes (-i)
+uint bsize = DEFAULT_BSIZE;     // size of a block in bytes (-b)
+int nbitmap;
+int ninodeblocks;
+int nlog = 1 + LOGSIZE; // header block + data blocks
 int nmeta;    // Number of meta blocks (boot, sb, nlog, inode, bitmap)
 int nblocks;  // Number of data blocks
 
 int fsfd;
 struct superblock sb;
-char zeroes[BLKSIZE];
 uint freeinode = 1;
 uint freeblock;
 
 
 void balloc(int);
+void wsb(void);
 void wsect(uint, void*);
 void winode(uint, struct dinode*);
 void rinode(uint inum, struct dinode *ip);
 void rsect(uint sec, void *buf);
 uint ialloc(ushort type);
 void iappend(uint inum, void *p, uint n);
+void dappend(uint dino, char *name, uint inum, ushort type);
+void dflush(uint dino);
 
 // convert to intel byte order
 ushort xshort(ushort x) {
@@ -70,66 +80,86 @@ uint xint(uint x) {
 
 int main(int argc, char *argv[]) {
   int i, cc, fd;
-  uint rootino, inum, off;
-  struct dirent de;
-  char buf[BLKSIZE];
-  struct dinode din;
+  uint rootino, inum;
+  char buf[MAXBLKSIZE];
 
   static_assert(sizeof(int) == 4, "Integers must be 4 bytes!");
 
-  if(argc < 2){
-    fprintf(stderr, "Usage: mkfs fs.img files...\n");
+  while((cc = getopt(argc, argv, "s:i:b:")) != -1){
+    switch(cc){
+    case 'b':
+      bsize = strtoul(optarg, 0, 0);
+      if(bsize < MINBLKSIZE || bsize > MAXBLKSIZE || (bsize & (bsize - 1)) != 0){
+        fprintf(stderr, "mkfs: block size must be a power of 2 in [%d, %d]\n",
+                MINBLKSIZE, MAXBLKSIZE);
+        exit(1);
+      }
+      break;
+    case 's':
+      fssize = strtoul(optarg, 0, 0);
+      break;
+    case 'i':
+      ninodes = strtoul(optarg, 0, 0);
+      break;
+    default:
+      goto usage;
+    }
+  }
+
+  if(argc - optind < 1){
+usage:
+    fprintf(stderr, "Usage: mkfs [-s blocks] [-i inodes] [-b block size] fs.img files...\n");
     exit(1);
   }
 
-  assert((BLKSIZE % sizeof(struct dinode)) == 0);
-  assert((BLKSIZE % sizeof(struct dirent)) == 0);
+  assert((MINBLKSIZE % sizeof(struct dinode)) == 0);
+  assert(sizeof(sb) <= SECTSIZE);
 
-  fsfd = open(argv[1], O_RDWR|O_CREAT|O_TRUNC, 0666);
+  fsfd = open(argv[optind], O_RDWR|O_CREAT|O_TRUNC, 0666);
   if(fsfd < 0){
-    perror(argv[1]);
+    perror(argv[optind]);
     exit(1);
   }
 
-  // 1 fs block = 1 disk sector
+  nbitmap = (fssize + BPB(bsize) - 1) / BPB(bsize);
+  ninodeblocks = ninodes / IPB(bsize) + 1;
+
   nmeta = 2 + nlog + ninodeblocks + nbitmap;
-  nblocks = FSSIZE - nmeta;
+  if(ninodes < 2 || fssize <= (uint)nmeta){
+    fprintf(stderr, "mkfs: %u blocks are too few for %u inodes\n", fssize, ninodes);
+    exit(1);
+  }
+  nblocks = fssize - nmeta;
 
-  sb.size = xint(FSSIZE);
+  sb.size = xint(fssize);
   sb.nblocks = xint(nblocks);
-  sb.ninodes = xint(NINODES);
+  sb.ninodes = xint(ninodes);
   sb.nlog = xint(nlog);
   sb.logstart = xint(2);
   sb.inodestart = xint(2+nlog);
   sb.bmapstart = xint(2+nlog+ninodeblocks);
+  sb.bsize = xint(bsize);
 
-  printf("nmeta %d (boot, super, log blocks %u inode blocks %u, bitmap blocks %u) blocks %d total %d\n",
-         nmeta, nlog, ninodeblocks, nbitmap, nblocks, FSSIZE);
+  printf("nmeta %d (boot, super, log blocks %u inode blocks %u, bitmap blocks %u) blocks %d total %d of %u bytes\n",
+         nmeta, nlog, ninodeblocks, nbitmap, nblocks, fssize, bsize);
 
   freeblock = nmeta;     // the first free block that we can allocate
 
-  for(i = 0; i < FSSIZE; i++)
-    wsect(i, zeroes);
+  // The file was truncated on open, so extending it fills every block with zeroes.
+  if(ftruncate(fsfd, (off_t)fssize * bsize) < 0){
+    perror("ftruncate");
+    exit(1);
+  }
 
-  // Write superblock
-  memset(buf, 0, sizeof(buf));
-  memmove(buf, &sb, sizeof(sb));
-  wsect(1, buf);
+  wsb();
 
   rootino = ialloc(T_DIR);
   assert(rootino == ROOTINO);
 
-  memset(&de, 0, sizeof(de));
-  de.inum = xint(rootino);
-  strcpy(de.name, ".");
-  iappend(rootino, &de, sizeof(de));
-
-  memset(&de, 0, sizeof(de));
-  de.inum = xint(rootino);
-  strcpy(de.name, "..");
-  iappend(rootino, &de, sizeof(de));
+  dappend(rootino, ".", rootino, T_DIR);
+  dappend(rootino, "..", rootino, T_DIR);
 
-  for(i = 2; i < argc; i++){
+  for(i = optind + 1; i < argc; i++){
     char *file_name = strrchr(argv[i], '/');
     if (file_name == 0) {
         file_name = argv[i];
@@ -145,10 +175,7 @@ int main(int argc, char *argv[]) {
 
     inum = ialloc(T_FILE);
 
-    memset(&de, 0, sizeof(de));
-    de.inum = xshort(inum);
-    strncpy(de.name, file_name, DIRSIZ);
-    iappend(rootino, &de, sizeof(de));
+    dappend(rootino, file_name, inum, T_FILE);
 
     while((cc = read(fd, buf, sizeof(buf))) > 0)
       iappend(inum, buf, cc);
@@ -156,65 +183,77 @@ int main(int argc, char *argv[]) {
     close(fd);
   }
 
-  // fix size of root inode dir
-  rinode(rootino, &din);
-  off = xint(din.size);
-  off = ((off/BLKSIZE) + 1) * BLKSIZE;
-  din.size = xint(off);
-  winode(rootino, &din);
+  dflush(rootino);
 
   balloc(freeblock);
 
   exit(0);
 }
 
+// Write the super block at SBSECTOR.
+void wsb(void) {
+  char buf[SECTSIZE];
+
+  memset(buf, 0, sizeof(buf));
+  memmove(buf, &sb, sizeof(sb));
+  if(lseek(fsfd, SBSECTOR * SECTSIZE, 0) != SBSECTOR * SECTSIZE){
+    perror("lseek");
+    exit(1);
+  }
+  if(write(fsfd, buf, SECTSIZE) != SECTSIZE){
+    perror("write");
+    exit(1);
+  }
+}
+
 void wsect(uint sec, void *buf) {
-  if(lseek(fsfd, sec * BLKSIZE, 0) != sec * BLKSIZE){
+  if(lseek(fsfd, (off_t)sec * bsize, 0) != (off_t)sec * bsize){
     perror("lseek");
     exit(1);
   }
-  if(write(fsfd, buf, BLKSIZE) != BLKSIZE){
+  if(write(fsfd, buf, bsize) != bsize){
     perror("write");
     exit(1);
   }
 }
 
 void rsect(uint sec, void *buf) {
-  if(lseek(fsfd, sec * BLKSIZE, 0) != sec * BLKSIZE){
+  if(lseek(fsfd, (off_t)sec * bsize, 0) != (off_t)sec * bsize){
     perror("lseek");
     exit(1);
   }
-  if(read(fsfd, buf, BLKSIZE) != BLKSIZE){
+  if(read(fsfd, buf, bsize) != bsize){
     perror("read");
     exit(1);
   }
 }
 
 void winode(uint inum, struct dinode *ip) {
-  char buf[BLKSIZE];
+  char buf[MAXBLKSIZE];
   uint bn;
   struct dinode *dip;
 
   bn = IBLOCK(inum, sb);
   rsect(bn, buf);
-  dip = ((struct dinode*)buf) + (inum % IPB);
+  dip = ((struct dinode*)buf) + (inum % IPB(bsize));
   *dip = *ip;
   wsect(bn, buf);
 }
 
 void rinode(uint inum, struct dinode *ip) {
-  char buf[BLKSIZE];
+  char buf[MAXBLKSIZE];
   uint bn;
   struct dinode *dip;
 
   bn = IBLOCK(inum, sb);
   rsect(bn, buf);
-  dip = ((struct dinode*)buf) + (inum % IPB);
+  dip = ((struct dinode*)buf) + (inum % IPB(bsize));
   *ip = *dip;
 }
 
 uint ialloc(ushort type) {
   uint inum = freeinode++;
+  assert(inum < ninodes);
   struct dinode din;
 
   memset(&din, 0, sizeof(din));
@@ -226,55 +265,95 @@ uint ialloc(ushort type) {
 }
 
 void balloc(int used) {
-  uchar buf[BLKSIZE];
-  int i;
+  uchar buf[MAXBLKSIZE];
+  int i, b, bpb = BPB(bsize);
 
   printf("balloc: first %d blocks have been allocated\n", used);
-  assert(used < BLKSIZE*8);
-  memset(buf, 0, BLKSIZE);
-  for(i = 0; i < used; i++){
-    buf[i/8] = buf[i/8] | (0x1 << (i%8));
+  assert(used < nbitmap*bpb);
+  for(b = 0; b * bpb < used; b++){
+    memset(buf, 0, bsize);
+    for(i = 0; i < bpb && b*bpb + i < used; i++){
+      buf[i/8] = buf[i/8] | (0x1 << (i%8));
+    }
+    printf("balloc: write bitmap block at sector %d\n", sb.bmapstart + b);
+    wsect(sb.bmapstart + b, buf);
   }
-  printf("balloc: write bitmap block at sector %d\n", sb.bmapstart);
-  wsect(sb.bmapstart, buf);
 }
 
 #define min(a, b) ((a) < (b) ? (a) : (b))
 
+// Return the n-th data block under the indirect block *addr whose depth is level.
+// Blocks on the path are allocated if necessary (all blocks are zeroed in advance).
+uint iindirect(uint *addr, uint n, int level) {
+  uint indirect[NINDIRECT(MAXBLKSIZE)];
+  uint span, x;
+  int i;
+
+  if(xint(*addr) == 0){
+    *addr = xint(freeblock++);
+  }
+  x = xint(*addr);
+
+  span = 1;
+  for(i = 1; i < level; i++){
+    span *= NINDIRECT(bsize);
+  }
+  for(; level > 0; level--){
+    rsect(x, (char*)indirect);
+    if(indirect[n / span] == 0){
+      indirect[n / span] = xint(freeblock++);
+      wsect(x, (char*)indirect);
+    }
+    x = xint(indirect[n / span]);
+    n %= span;
+    span /= NINDIRECT(bsize);
+  }
+  return x;
+}
+
+// Return the block of file block number fbn, allocating if necessary.
+uint bmap(struct dinode *din, uint fbn) {
+  uint nblocks;
+  int level;
+
+  if(fbn < NDIRECT){
+    if(xint(din->addrs[fbn]) == 0){
+      din->addrs[fbn] = xint(freeblock++);
+    }
+    return xint(din->addrs[fbn]);
+  }
+  fbn -= NDIRECT;
+
+  nblocks = NINDIRECT(bsize);
+  for(level = 1; level <= NINDIRECT_LEVELS; level++){
+    if(fbn < nblocks){
+      return iindirect(&din->addrs[NDIRECT + level - 1], fbn, level);
+    }
+    fbn -= nblocks;
+    nblocks *= NINDIRECT(bsize);
+  }
+  assert(0);
+  return 0;
+}
+
 // Append nbytes of xp to file represented by inum
 void iappend(uint inum, void *xp, uint n) {
   char *p = (char*)xp;
   uint fbn, off, n1;
   struct dinode din;
-  char buf[BLKSIZE];
-  uint indirect[NINDIRECT];
+  char buf[MAXBLKSIZE];
   uint x;
 
   rinode(inum, &din);
   off = xint(din.size);
   // printf("append inum %d at off %d sz %d\n", inum, off, n);
   while(n > 0){
-    fbn = off / BLKSIZE;
-    assert(fbn < MAXFILE);
-    if(fbn < NDIRECT){
-      if(xint(din.addrs[fbn]) == 0){
-        din.addrs[fbn] = xint(freeblock++);
-      }
-      x = xint(din.addrs[fbn]);
-    } else {
-      if(xint(din.addrs[NDIRECT]) == 0){
-        din.addrs[NDIRECT] = xint(freeblock++);
-      }
-      rsect(xint(din.addrs[NDIRECT]), (char*)indirect);
-      if(indirect[fbn - NDIRECT] == 0){
-        indirect[fbn - NDIRECT] = xint(freeblock++);
-        wsect(xint(din.addrs[NDIRECT]), (char*)indirect);
-      }
-      x = xint(indirect[fbn-NDIRECT]);
-    }
-    n1 = min(n, (fbn + 1) * BLKSIZE - off);
+    fbn = off / bsize;
+    x = bmap(&din, fbn);
+    assert(freeblock < fssize);
+    n1 = min(n, (fbn + 1) * bsize - off);
     rsect(x, buf);
-    memcpy(buf + off - (fbn * BLKSIZE), p, n1);
+    memcpy(buf + off - (fbn * bsize), p, n1);
     wsect(x, buf);
     n -= n1;
     off += n1;
@@ -283,3 +362,42 @@ void iappend(uint inum, void *xp, uint n) {
   din.size = xint(off);
   winode(inum, &din);
 }
+
+// The last block of the directory being built by dappend.
+char dirblock[MAXBLKSIZE];
+uint dirblockoff;  // bytes used in dirblock
+uint dirlastent;   // offset of the last entry in dirblock
+
+// Write dirblock to the directory, extending the last entry to the end of the block.
+void dflush(uint dino) {
+  struct dirent *de;
+
+  if(dirblockoff == 0)
+    return;
+  de = (struct dirent*)(dirblock + dirlastent);
+  de->rec_len = xshort(bsize - dirlastent);
+  iappend(dino, dirblock, bsize);
+  memset(dirblock, 0, sizeof(dirblock));
+  dirblockoff = 0;
+}
+
+// Append an entry (name, inum) to the directory dino. type is the type of the inode.
+// Entries are kept in dirblock until it is full, so call dflush at the end.
+void dappend(uint dino, char *name, uint inum, ushort type) {
+  struct dirent *de;
+  uint namelen = strlen(name);
+  uint size = DIRENTSIZE(namelen);
+
+  assert(namelen <= DIRSIZ);
+  if(dirblockoff + size > bsize)
+    dflush(dino);
+
+  de = (struct dirent*)(dirblock + dirblockoff);
+  de->inum = xint(inum);
+  de->rec_len = xshort(size);
+  de->name_len = namelen;
+  de->file_type = type;
+  memmove(dirblock + dirblockoff + sizeof(*de), name, namelen);
+  dirlastent = dirblockoff;
+  dirblockoff += size;
+}
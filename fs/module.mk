@@ -12,6 +12,18 @@ FS_IMG_FILES := \
 	$(OBJDIR)/user/cat \
 	$(OBJDIR)/user/pipetest \
 	$(OBJDIR)/user/wc \
+	$(OBJDIR)/user/ps \
+	$(OBJDIR)/user/free \
+	$(OBJDIR)/user/rm \
+	$(OBJDIR)/user/rmdir \
+	$(OBJDIR)/user/ln \
+	$(OBJDIR)/user/mv \
+	$(OBJDIR)/user/renametest \
+	$(OBJDIR)/user/symlinktest \
+	$(OBJDIR)/user/seektest \
+	$(OBJDIR)/user/trunctest \
+	$(OBJDIR)/user/getdentstest \
+	$(OBJDIR)/user/swaptest \
 
 FS_CFLAGS := -Wall -Wextra -MD -I$(TOP)
 
@@ -19,6 +31,14 @@ FS_FORMAT := $(OBJDIR)/fs/fsformat
 
 FS_IMAGE := $(OBJDIR)/fs/fs.img
 
+# Size of the file system image in blocks, the number of inodes in it
+# and the size of a block (512, 1024, 2048 or 4096 bytes).
+# The kernel reads the layout from the super block, so these can be changed
+# without rebuilding the kernel.
+FS_BLOCKS ?= 16384
+FS_INODES ?= 200
+FS_BSIZE ?= 512
+
 $(FS_FORMAT): fs/fsformat.c
 	@echo + cc -Os $<
 	@mkdir -p $(@D)
@@ -27,4 +47,4 @@ $(FS_FORMAT): fs/fsformat.c
 $(FS_IMAGE): $(FS_FORMAT) $(FS_IMG_FILES)
 	@echo + mk $(FS_IMAGE)
 	$(V)mkdir -p $(@D)
-	$(V)$(FS_FORMAT) $(FS_IMAGE) $(FS_IMG_FILES)
+	$(V)$(FS_FORMAT) -s $(FS_BLOCKS) -i $(FS_INODES) -b $(FS_BSIZE) $(FS_IMAGE) $(FS_IMG_FILES)
@@ -7,18 +7,24 @@
 #define _XV6RUST_FS_H
 
 #define ROOTINO 1 // root i-number
-#define BLKSIZE 512
-#define FSSIZE 1000 // size of file system in blocks
+// FIXME: the same definition is in src/constants.rs
+#define SECTSIZE 512
+#define MINBLKSIZE 512 // the block size itself is recorded in the super block
+#define MAXBLKSIZE 4096
+#define SBSECTOR 1 // the super block is here whatever the block size is
 
-#define MAXOPBLOCKS 10  // max # of blocks any FS op writes
+#define MAXOPBLOCKS 20  // max # of blocks any FS op writes
 #define LOGSIZE (MAXOPBLOCKS*3) // max data blocks in on-disk log
 #define NBUF (MAXOPBLOCKS*3) // size of disk block cache
 
 // Block containing inode i
-#define IBLOCK(i, sb) ((i) / IPB + sb.inodestart)
+#define IBLOCK(i, sb) ((i) / IPB((sb).bsize) + (sb).inodestart)
 
 // Inodes per block.
-#define IPB (BLKSIZE / sizeof(struct dinode))
+#define IPB(bsize) ((bsize) / sizeof(struct dinode))
+
+// Bitmap bits per block.
+#define BPB(bsize) ((bsize) * 8)
 
 // Disk layout:
 // [ boot block | super block | log | inode blocks |
@@ -34,11 +40,14 @@ struct superblock {
     uint logstart;     // Block number of first log block
     uint inodestart;   // Block number of first inode block
     uint bmapstart;    // Block number of first free map block
+    uint bsize;        // Size of a block (bytes)
 };
 
-#define NDIRECT 12
-#define NINDIRECT (BLKSIZE / sizeof(uint))
-#define MAXFILE (NDIRECT + NINDIRECT)
+// FIXME: the same definition is in src/constants.rs
+#define NDIRECT 10
+#define NINDIRECT(bsize) ((bsize) / sizeof(uint)) // blocks under a single indirect block
+#define NINDIRECT_LEVELS 3
+#define NADDRS (NDIRECT + NINDIRECT_LEVELS) // direct, single, double and triple indirect
 
 // On-disk inode structure
 struct dinode {
@@ -47,22 +56,32 @@ struct dinode {
     short minor; // Minor device number (T_DEV only)
     short nlink; // Number of links to inode in file system
     uint size; // Size of file (bytes)
-    uint addrs[NDIRECT+1]; // Data block addresses
+    uint addrs[NADDRS]; // Data block addresses
 };
 
-// Directory is a file containing a sequence of dirent structures.
-#define DIRSIZ 12
+// Directory is a file containing a sequence of variable-length entries.
+// Each entry is a dirent followed by name_len bytes of the name (without '\0'),
+// and the next entry starts rec_len bytes after it. Entries never cross a block.
+// file_type is the type (T_DIR etc.) of the inode, or 0 if unknown.
+// FIXME: the same definition is in src/fs.rs
+#define DIRSIZ 255
 
 struct dirent {
     uint32_t inum;
-    char name[DIRSIZ];
+    unsigned short rec_len;
+    unsigned char name_len;
+    unsigned char file_type;
 };
 
+// Size of an entry whose name is namelen bytes.
+#define DIRENTSIZE(namelen) ((sizeof(struct dirent) + (namelen) + 3) & ~3)
+
 /* from `stat.h` */
 
 #define T_DIR  1   // Directory
 #define T_FILE 2   // File
 #define T_DEV  3   // Device
+#define T_SYMLINK 4 // Symbolic link
 
 struct stat {
   short type; // Type of file
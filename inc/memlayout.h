@@ -48,19 +48,17 @@
  *                     :              .               :                   |
  *    MMIOLIM ------>  +------------------------------+ 0xefc00000      --+
  *                     |       Memory-mapped I/O      | RW/--  PTSIZE
- * ULIM, MMIOBASE -->  +------------------------------+ 0xef800000
- *                     |  Cur. Page Table (User R-)   | R-/R-  PTSIZE
- *    UVPT      ---->  +------------------------------+ 0xef400000
- *                     |          RO PAGES            | R-/R-  PTSIZE
- *    UPAGES    ---->  +------------------------------+ 0xef000000
- *                     |           RO ENVS            | R-/R-  PTSIZE
- * UTOP,UENVS ------>  +------------------------------+ 0xeec00000
- * UXSTACKTOP -/       |     User Exception Stack     | RW/RW  PGSIZE
- *                     +------------------------------+ 0xeebff000
- *                     |       Empty Memory (*)       | --/--  PGSIZE
- *    USTACKTOP  --->  +------------------------------+ 0xeebfe000
+ *    MMIOBASE ----->  +------------------------------+ 0xef800000
+ *                     |                              | RW/--
+ *                     |         Kernel Heap          | RW/--  KHEAP_SIZE
+ *                     |                              | RW/--
+ *    KHEAP_BASE --->  +------------------------------+ 0xed800000
+ *                     |  Per-cpu Temporary Mappings  | RW/--  PTSIZE
+ * UTOP,KMAP_BASE -->  +------------------------------+ 0xed400000
+ *                     |       Empty Memory (*)       | --/--  2*PGSIZE
+ *    USTACKTOP  --->  +------------------------------+ 0xed3fe000
  *                     |      Normal User Stack       | RW/RW  PGSIZE
- *                     +------------------------------+ 0xeebfd000
+ *                     +------------------------------+ 0xed3fd000
  *                     |                              |
  *                     |                              |
  *                     ~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~
@@ -104,29 +102,23 @@
 #define MMIOLIM		(KSTACKTOP - PTSIZE)
 #define MMIOBASE	(MMIOLIM - PTSIZE)
 
-#define ULIM		(MMIOBASE)
-
 /*
- * User read-only mappings! Anything below here til UTOP are readonly to user.
- * They are global pages mapped in at env allocation time.
+ * Kernel heap. This range is only reserved; pages are mapped on demand.
+ * FIXME: the same definition is in src/constants.rs
  */
+#define KHEAP_SIZE	(8*PTSIZE)
+#define KHEAP_BASE	(MMIOBASE - KHEAP_SIZE)
 
-// User read-only virtual page table (see 'uvpt' below)
-#define UVPT		(ULIM - PTSIZE)
-// Read-only copies of the Page structures
-#define UPAGES		(UVPT - PTSIZE)
-// Read-only copies of the global env structures
-#define UENVS		(UPAGES - PTSIZE)
+// Per-cpu temporary mappings of high memory
+#define KMAP_BASE	(KHEAP_BASE - PTSIZE)
 
 /*
  * Top of user VM. User can manipulate VA from UTOP-1 and down!
  */
 
 // Top of user-accessible VM
-#define UTOP		UENVS
-// Top of one-page user exception stack
-#define UXSTACKTOP	UTOP
-// Next page left invalid to guard against exception stack overflow; then:
+#define UTOP		KMAP_BASE
+// Next two pages left invalid to guard against kernel mappings; then:
 // Top of normal user stack
 #define USTACKTOP	(UTOP - 2*PGSIZE)
 
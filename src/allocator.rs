@@ -1,42 +1,342 @@
 // Some of codes come from https://github.com/redox-os/kernel/blob/master/src/allocator/linked_list.rs
 
+use crate::constants::*;
+use crate::mpconfig;
+use crate::mpconfig::consts::MAX_NUM_CPU;
+use crate::pmap::{self, VirtAddr};
+use crate::slab;
+use crate::spinlock::Mutex;
+use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::NonNull;
+use core::cmp;
+use core::ptr::{null_mut, NonNull};
 use linked_list_allocator::Heap;
 
-static mut HEAP: Option<Heap> = None;
+// The kernel heap reserves [KHEAP_BASE, KHEAP_BASE + KHEAP_SIZE),
+// but only the used part of it is backed by pages of PageAllocator.
+//
+// The lower half is a linked list heap for small objects. It grows on demand.
+// The upper half is for large objects (at least PGSIZE). They are mapped page by page
+// and the pages are given back to PageAllocator when the objects are freed.
+const SMALL_HEAP_BASE: usize = KHEAP_BASE as usize;
+const SMALL_HEAP_LIMIT: usize = KHEAP_SIZE / 2;
+const SMALL_HEAP_INIT_SIZE: usize = 16 * PGSIZE as usize;
+const SMALL_HEAP_GROW_SIZE: usize = 4 * PGSIZE as usize;
+
+const LARGE_AREA_BASE: usize = SMALL_HEAP_BASE + SMALL_HEAP_LIMIT;
+const LARGE_AREA_PAGES: usize = (KHEAP_SIZE - SMALL_HEAP_LIMIT) / PGSIZE as usize;
+const BITMAP_WORDS: usize = LARGE_AREA_PAGES / 32;
+
+/// Statistics of the kernel heap.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HeapStats {
+    pub(crate) small_size: usize,     // bytes mapped for the small object heap
+    pub(crate) small_used: usize,     // bytes used in the small object heap
+    pub(crate) large_pages: usize,    // pages mapped for large objects
+    pub(crate) grows: usize,          // number of times the small object heap grew
+    pub(crate) returned_pages: usize, // pages given back to PageAllocator
+}
+
+impl HeapStats {
+    /// Return the number of pages backing the kernel heap.
+    pub(crate) fn mapped_pages(&self) -> usize {
+        self.small_size / PGSIZE as usize + self.large_pages
+    }
+}
+
+/// Page granular allocator for large objects.
+///
+/// A freed range is unmapped at once, but other cpus may still have its
+/// entries in their TLBs. So its virtual addresses are not reused until
+/// every cpu has flushed its TLB.
+struct LargeArea {
+    used: [u32; BITMAP_WORDS],    // pages allocated or waiting for TLB flushes
+    pending: [u32; BITMAP_WORDS], // pages waiting for TLB flushes
+    pending_stamp: [u32; MAX_NUM_CPU], // TLB flush counts when pages became pending
+    has_pending: bool,
+    npages: usize,
+}
+
+impl LargeArea {
+    const fn new() -> LargeArea {
+        LargeArea {
+            used: [0; BITMAP_WORDS],
+            pending: [0; BITMAP_WORDS],
+            pending_stamp: [0; MAX_NUM_CPU],
+            has_pending: false,
+            npages: 0,
+        }
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let p = ptr as usize;
+        p >= LARGE_AREA_BASE && p < LARGE_AREA_BASE + LARGE_AREA_PAGES * PGSIZE as usize
+    }
+
+    fn is_used(&self, i: usize) -> bool {
+        self.used[i / 32] & (1 << (i % 32)) != 0
+    }
+
+    fn set_used(&mut self, i: usize, used: bool) {
+        if used {
+            self.used[i / 32] |= 1 << (i % 32);
+        } else {
+            self.used[i / 32] &= !(1 << (i % 32));
+        }
+    }
+
+    fn page_va(i: usize) -> VirtAddr {
+        VirtAddr((LARGE_AREA_BASE + i * PGSIZE as usize) as u32)
+    }
+
+    /// Find n contiguous free pages (first fit).
+    fn find_free(&self, n: usize) -> Option<usize> {
+        let mut start = 0;
+        let mut len = 0;
+        for i in 0..LARGE_AREA_PAGES {
+            if self.is_used(i) {
+                start = i + 1;
+                len = 0;
+            } else {
+                len += 1;
+                if len == n {
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+
+    /// Return true if every cpu has flushed its TLB after pages became pending.
+    fn grace_period_passed(&self) -> bool {
+        // A halted cpu has flushed its TLB before halting.
+        mpconfig::cpus().iter().all(|cpu| {
+            !cpu.is_started() || cpu.tlb_flushes() != self.pending_stamp[cpu.cpu_id as usize]
+        })
+    }
+
+    /// Make pending pages available again if possible.
+    fn reclaim_pending(&mut self) {
+        if !self.has_pending || !self.grace_period_passed() {
+            return;
+        }
+        for w in 0..BITMAP_WORDS {
+            self.used[w] &= !self.pending[w];
+            self.pending[w] = 0;
+        }
+        self.has_pending = false;
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let n = (layout.size() + PGSIZE as usize - 1) / PGSIZE as usize;
+        self.reclaim_pending();
+        let start = match self.find_free(n) {
+            None => return null_mut(),
+            Some(start) => start,
+        };
+
+        for i in start..(start + n) {
+            if pmap::kheap_map_page(LargeArea::page_va(i)).is_err() {
+                // Out of memory. Roll back the pages mapped so far.
+                for j in start..i {
+                    pmap::kheap_unmap_page(LargeArea::page_va(j));
+                }
+                return null_mut();
+            }
+        }
+        for i in start..(start + n) {
+            self.set_used(i, true);
+        }
+        self.npages += n;
+        LargeArea::page_va(start).as_mut_ptr()
+    }
+
+    /// Unmap the pages and return the number of them.
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) -> usize {
+        let n = (layout.size() + PGSIZE as usize - 1) / PGSIZE as usize;
+        let start = (ptr as usize - LARGE_AREA_BASE) / PGSIZE as usize;
+        for i in start..(start + n) {
+            pmap::kheap_unmap_page(LargeArea::page_va(i));
+            self.pending[i / 32] |= 1 << (i % 32);
+        }
+        self.npages -= n;
+
+        // Pending pages wait for the TLB flushes after now.
+        // This cpu has already invalidated the entries, so it need not wait.
+        let me = mpconfig::this_cpu().cpu_id;
+        for cpu in mpconfig::cpus() {
+            let flushes = cpu.tlb_flushes();
+            self.pending_stamp[cpu.cpu_id as usize] = if cpu.cpu_id == me {
+                flushes.wrapping_sub(1)
+            } else {
+                flushes
+            };
+        }
+        self.has_pending = true;
+        n
+    }
+}
+
+struct KernelHeap {
+    small: Heap,
+    large: LargeArea,
+    grows: usize,
+    returned_pages: usize,
+}
+
+impl KernelHeap {
+    fn is_large(layout: &Layout) -> bool {
+        layout.size() >= PGSIZE as usize && layout.align() <= PGSIZE as usize
+    }
+
+    /// Map pages at the top of the small object heap and add them to the heap.
+    fn grow(&mut self, layout: &Layout) -> bool {
+        let required = layout.size() + layout.align();
+        let by = cmp::max(SMALL_HEAP_GROW_SIZE, required);
+        let by = (by + PGSIZE as usize - 1) / PGSIZE as usize * PGSIZE as usize;
+        let top = self.small.top();
+        if top + by > SMALL_HEAP_BASE + SMALL_HEAP_LIMIT {
+            return false;
+        }
+
+        let mut va = VirtAddr(top as u32);
+        while va < VirtAddr((top + by) as u32) {
+            if pmap::kheap_map_page(va).is_err() {
+                // Keep the pages mapped so far for the next time.
+                let mapped = va.0 as usize - top;
+                if mapped > 0 {
+                    unsafe { self.small.extend(mapped) };
+                }
+                return false;
+            }
+            va += PGSIZE;
+        }
+        unsafe { self.small.extend(by) };
+        self.grows += 1;
+        true
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if KernelHeap::is_large(&layout) {
+            return self.large.alloc(layout);
+        }
+
+        loop {
+            if let Ok(res) = self.small.allocate_first_fit(layout) {
+                return res.as_ptr();
+            }
+            if !self.grow(&layout) {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if self.large.contains(ptr) {
+            self.returned_pages += self.large.dealloc(ptr, layout);
+        } else {
+            self.small.deallocate(NonNull::new_unchecked(ptr), layout);
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            small_size: self.small.size(),
+            small_used: self.small.used(),
+            large_pages: self.large.npages,
+            grows: self.grows,
+            returned_pages: self.returned_pages,
+        }
+    }
+}
+
+static HEAP: Mutex<Option<KernelHeap>> = Mutex::new(None);
 
 pub struct HeapAllocator;
 
 impl HeapAllocator {
-    pub unsafe fn init(offset: usize, size: usize) {
-        HEAP = Some(Heap::new(offset, size));
+    /// Map the initial pages of the small object heap.
+    /// This must be called after pmap::mem_init.
+    pub unsafe fn init() {
+        let mut va = VirtAddr(SMALL_HEAP_BASE as u32);
+        while va < VirtAddr((SMALL_HEAP_BASE + SMALL_HEAP_INIT_SIZE) as u32) {
+            pmap::kheap_map_page(va).expect("failed to map the kernel heap");
+            va += PGSIZE;
+        }
+
+        let mut heap = HEAP.lock();
+        *heap = Some(KernelHeap {
+            small: Heap::new(SMALL_HEAP_BASE, SMALL_HEAP_INIT_SIZE),
+            large: LargeArea::new(),
+            grows: 0,
+            returned_pages: 0,
+        });
     }
 }
 
 unsafe impl GlobalAlloc for HeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let heap = HEAP.as_mut().expect("HEAP is not initialized yet");
-        match heap.allocate_first_fit(layout) {
-            Err(alloc_err) => {
-                panic!("allocation error: {:?}", alloc_err);
-            }
-            Ok(res) => {
-                #[cfg(feature = "debug")]
-                println!(
-                    "HeapAllocator: allocated for {:?} at 0x{:?}",
-                    layout,
-                    res.as_ptr()
-                );
-                res.as_ptr()
-            }
+        if let Some(res) = slab::alloc(&layout) {
+            return res;
         }
+
+        let mut heap = HEAP.lock();
+        let heap = heap.as_mut().expect("HEAP is not initialized yet");
+        let res = heap.alloc(layout);
+        #[cfg(feature = "debug")]
+        println!("HeapAllocator: allocated for {:?} at {:?}", layout, res);
+        res
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let heap = HEAP.as_mut().expect("HEAP is not initialized yet");
-        heap.deallocate(NonNull::new_unchecked(ptr), layout);
+        if slab::free(ptr, &layout) {
+            return;
+        }
+
+        let mut heap = HEAP.lock();
+        let heap = heap.as_mut().expect("HEAP is not initialized yet");
+        heap.dealloc(ptr, layout);
         #[cfg(feature = "debug")]
         println!("HeapAllocator: released {:?}", ptr);
     }
 }
+
+/// Allocate contiguous pages from the large object area.
+/// They are used as slabs of object caches.
+pub(crate) fn alloc_pages(npages: usize) -> *mut u8 {
+    let pgsize = PGSIZE as usize;
+    let layout = Layout::from_size_align(npages * pgsize, pgsize).unwrap();
+    let mut heap = HEAP.lock();
+    let heap = heap.as_mut().expect("HEAP is not initialized yet");
+    heap.large.alloc(layout)
+}
+
+/// Give back contiguous pages allocated by alloc_pages.
+pub(crate) fn free_pages(p: *mut u8, npages: usize) {
+    let pgsize = PGSIZE as usize;
+    let layout = Layout::from_size_align(npages * pgsize, pgsize).unwrap();
+    let mut heap = HEAP.lock();
+    let heap = heap.as_mut().expect("HEAP is not initialized yet");
+    heap.returned_pages += heap.large.dealloc(p, layout);
+}
+
+/// Same as Box::new, but return NoMem instead of calling the alloc error handler
+/// when the kernel heap is exhausted.
+pub(crate) fn try_box<T>(value: T) -> Result<Box<T>, SysError> {
+    let layout = Layout::new::<T>();
+    assert!(layout.size() > 0);
+    unsafe {
+        let p = alloc::alloc::alloc(layout).cast::<T>();
+        if p.is_null() {
+            return Err(SysError::NoMem);
+        }
+        p.write(value);
+        Ok(Box::from_raw(p))
+    }
+}
+
+/// Return statistics of the kernel heap.
+pub(crate) fn heap_stats() -> HeapStats {
+    let heap = HEAP.lock();
+    heap.as_ref().expect("HEAP is not initialized yet").stats()
+}
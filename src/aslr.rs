@@ -0,0 +1,60 @@
+// Address space layout randomization for user programs.
+//
+// exec places the user stack top and the heap base at random page offsets
+// below USTACKTOP and UHEAPBASE. The heap region is moved down by the offset of
+// the stack as well, so it never overlaps with the stack.
+//
+// It is enabled by default and disabled by the fw_cfg file ASLR_FW_CFG_FILE
+// with content "0" (`make qemu-gdb` does so to keep addresses deterministic).
+
+use crate::constants::*;
+use crate::{fwcfg, random};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// FIXME: the same definition is in Makefile
+const ASLR_FW_CFG_FILE: &str = "opt/xv6-rust/aslr";
+
+static ASLR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub(crate) fn aslr_init() {
+    let mut buf = [0; 1];
+    if let Some(1) = fwcfg::read_file(ASLR_FW_CFG_FILE, &mut buf) {
+        ASLR_ENABLED.store(buf[0] != b'0', Ordering::Relaxed);
+    }
+    if !ASLR_ENABLED.load(Ordering::Relaxed) {
+        println!("aslr: disabled");
+    }
+}
+
+/// Layout of the user address space decided at exec.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct UserLayout {
+    pub(crate) stack_top: u32,
+    pub(crate) heap_base: u32,
+}
+
+impl UserLayout {
+    pub(crate) const fn fixed() -> UserLayout {
+        UserLayout {
+            stack_top: USTACKTOP,
+            heap_base: UHEAPBASE,
+        }
+    }
+
+    /// Return a new layout (randomized if ASLR is enabled).
+    pub(crate) fn new() -> UserLayout {
+        if !ASLR_ENABLED.load(Ordering::Relaxed) {
+            return UserLayout::fixed();
+        }
+        let stack_offset = (random::random_u32() % ASLR_STACK_PAGES) * PGSIZE;
+        let heap_offset = (random::random_u32() % ASLR_HEAP_PAGES) * PGSIZE;
+        UserLayout {
+            stack_top: USTACKTOP - stack_offset,
+            heap_base: UHEAPBASE - stack_offset - heap_offset,
+        }
+    }
+
+    pub(crate) fn stack_base(&self) -> u32 {
+        self.stack_top - USTACKSIZE
+    }
+}
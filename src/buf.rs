@@ -1,5 +1,5 @@
 use crate::constants::*;
-use crate::ide;
+use crate::{env, ide, superblock};
 use crate::spinlock::{Mutex, MutexGuard};
 use consts::*;
 use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
@@ -20,7 +20,8 @@ pub(crate) struct Buf {
     pub(crate) blockno: u32,
     // lock: SleepLock,
     pub(crate) refcnt: u32,
-    pub(crate) data: [u8; BLK_SIZE],
+    pub(crate) size: usize, // block size of the file system; only data[..size] is used
+    pub(crate) data: [u8; MAX_BLK_SIZE],
 }
 
 impl Buf {
@@ -30,7 +31,8 @@ impl Buf {
             dev: 0,
             blockno: 0,
             refcnt: 0,
-            data: [0; BLK_SIZE],
+            size: 0,
+            data: [0; MAX_BLK_SIZE],
         }
     }
 
@@ -50,6 +52,9 @@ impl BufCacheHandler {
         let buf = unsafe { &mut *self.buf };
         if buf.flags & BUF_FLAGS_VALID == 0 {
             ide::ide_rw(buf);
+            if let Some(env) = env::cur_env_mut() {
+                env.count_block_read();
+            }
         }
     }
 
@@ -57,17 +62,20 @@ impl BufCacheHandler {
         self.make_dirty();
         let buf = unsafe { &mut *self.buf };
         ide::ide_rw(buf);
+        if let Some(env) = env::cur_env_mut() {
+            env.count_block_write();
+        }
     }
 
     pub(crate) fn data(&self) -> &[u8] {
         let buf = unsafe { &mut *self.buf };
-        let len = buf.data.len();
+        let len = buf.size;
         unsafe { &*slice_from_raw_parts(buf.data.as_ptr(), len) }
     }
 
     pub(crate) fn data_mut(&self) -> &mut [u8] {
         let buf = unsafe { &mut *self.buf };
-        let len = buf.data.len();
+        let len = buf.size;
         unsafe { &mut *slice_from_raw_parts_mut(buf.data.as_mut_ptr(), len) }
     }
 
@@ -140,6 +148,7 @@ impl BufCache {
                 buf.blockno = blockno;
                 buf.flags = 0;
                 buf.refcnt = 1;
+                buf.size = superblock::get().block_size();
                 *entry_ref = Some(buf);
 
                 BufCacheHandler {
@@ -151,6 +160,11 @@ impl BufCache {
         }
     }
 
+    /// Return the number of buffers in use.
+    pub(crate) fn count(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.is_some()).count()
+    }
+
     pub(crate) fn release(&mut self, handler: BufCacheHandler) {
         let dev = handler.dev;
         let blockno = handler.blockno;
@@ -1,13 +1,13 @@
 // FIXME: how to manage constant values (in rust as well as c and asm)
 
-use crate::fs::DInode;
-use core::mem;
-
 pub(crate) const KERN_BASE: u32 = 0xf0000000;
 pub(crate) const PGSIZE: u32 = 4096;
 pub(crate) const PGSHIFT: u32 = 12;
 
 // Page table/directory entry flags
+pub(crate) const PTE_SWAPPED: u32 = 0x200; // Swapped out (available for software use)
+pub(crate) const PTE_PS: u32 = 0x80; // Page Size (4 MiB page in PDE)
+pub(crate) const PTE_A: u32 = 0x20; // Accessed
 pub(crate) const PTE_PCD: u32 = 0x10; // Cache-disable if set
 pub(crate) const PTE_PWT: u32 = 0x8; // 1: Write-Through, 0: Write-Back
 pub(crate) const PTE_U: u32 = 0x4; // User
@@ -23,18 +23,27 @@ pub(crate) const KSTKSIZE: u32 = 8 * PGSIZE;
 pub(crate) const KSTKGAP: u32 = 8 * PGSIZE;
 pub(crate) const MMIOLIM: u32 = KSTACKTOP - (PTSIZE as u32);
 pub(crate) const MMIOBASE: u32 = MMIOLIM - (PTSIZE as u32);
+// FIXME: the same definition is in inc/memlayout.h
 // Assign kernel heap area instead of Cur. Page Table, RO PAGES, and RO ENVS in JOS
+// This range is only reserved. Pages are mapped on demand by the heap allocator.
 pub(crate) const KHEAP_BASE: u32 = MMIOBASE - KHEAP_SIZE as u32;
-pub(crate) const KHEAP_SIZE: usize = 3 * PTSIZE;
+pub(crate) const KHEAP_SIZE: usize = 8 * PTSIZE;
+
+// Per-cpu temporary mappings of high memory (see pmap::kmap)
+pub(crate) const KMAP_BASE: u32 = KHEAP_BASE - PTSIZE as u32;
 
 // The top address of user can access.
-pub(crate) const UTOP: u32 = KHEAP_BASE;
+pub(crate) const UTOP: u32 = KMAP_BASE;
 pub(crate) const USTACKTOP: u32 = UTOP - (2 * PGSIZE as u32);
 pub(crate) const USTACKSIZE: u32 = PGSIZE;
 
 pub(crate) const UHEAPBASE: u32 = USTACKTOP - USTACKSIZE - (UHEAPSIZE as u32);
 pub(crate) const UHEAPSIZE: usize = 3 * PTSIZE; // maximum heap size for user
 
+// Maximum offsets (in pages) of the user stack and heap randomized by exec (see aslr.rs)
+pub(crate) const ASLR_STACK_PAGES: u32 = 256;
+pub(crate) const ASLR_HEAP_PAGES: u32 = 1024;
+
 // Physical address of startup code for non-boot CPUs (APs)
 // FIXME: the same definition is in src/mpentry.S
 pub(crate) const MPENTRY_PADDR: u32 = 0x7000;
@@ -52,6 +61,18 @@ pub(crate) const CR0_NW: u32 = 0x2000000; // Not Write through
 pub(crate) const CR0_CD: u32 = 0x4000000; // Cache Disable
 pub(crate) const CR0_PG: u32 = 0x8000000; // Paging
 
+// CR4
+pub(crate) const CR4_PSE: u32 = 0x00000010; // Page size extensions
+pub(crate) const CR4_SMEP: u32 = 0x00100000; // Supervisor mode execution protection
+pub(crate) const CR4_SMAP: u32 = 0x00200000; // Supervisor mode access protection
+
+// CPUID.01H:EDX
+pub(crate) const CPUID_EDX_PSE: u32 = 1 << 3; // Page size extensions
+
+// CPUID.(EAX=07H,ECX=0):EBX
+pub(crate) const CPUID_7_EBX_SMEP: u32 = 1 << 7; // Supervisor mode execution protection
+pub(crate) const CPUID_7_EBX_SMAP: u32 = 1 << 20; // Supervisor mode access protection
+
 // EFLAGS register
 pub(crate) const FL_CF: u32 = 1 << 0; // Carry Flag
 pub(crate) const FL_PF: u32 = 1 << 2; // Parity Flag
@@ -76,29 +97,40 @@ pub(crate) const FL_VIP: u32 = 1 << 20; // Virtual Interrupt Pending
 pub(crate) const FL_ID: u32 = 1 << 21; // ID flag
 
 // file system
-pub(crate) const BLK_SIZE: usize = 512;
 pub(crate) const SECTOR_SIZE: usize = 512;
-pub(crate) const FS_SIZE: usize = 1000; // size of file system in blocks
-pub(crate) const MAX_OP_BLOCKS: usize = 10; // max $ of blocks any FS op writes
-pub(crate) const LOG_SIZE: usize = MAX_OP_BLOCKS * 3;
-pub(crate) const NDIRECT: usize = 12;
-pub(crate) const NINDIRECT: usize = BLK_SIZE / 4;
-pub(crate) const MAX_FILE: usize = NDIRECT + NINDIRECT;
+// FIXME: the same definition is in inc/fs.h
+pub(crate) const MIN_BLK_SIZE: usize = 512; // the block size itself is recorded in the super block
+pub(crate) const MAX_BLK_SIZE: usize = 4096; // also the size of each buffer in the buffer cache
+pub(crate) const SUPER_BLOCK_SECTOR: u32 = 1; // the super block is here whatever the block size is
+pub(crate) const MAX_OP_BLOCKS: usize = 20; // max $ of blocks any FS op writes
+pub(crate) const LOG_SIZE: usize = MAX_OP_BLOCKS * 3; // max data blocks in the log; logged blocks stay pinned in the buffer cache
+// FIXME: the same definition is in inc/fs.h
+pub(crate) const NDIRECT: usize = 10;
+pub(crate) const NINDIRECT_LEVELS: usize = 3;
+pub(crate) const NADDRS: usize = NDIRECT + NINDIRECT_LEVELS; // direct, single, double and triple indirect
 pub(crate) const NINODE: usize = 50; // maximum number of active i-nodes
-pub(crate) const IPB: usize = BLK_SIZE / mem::size_of::<DInode>(); // how many inodes a block has
-pub(crate) const BPB: usize = BLK_SIZE * 8; // how many bit a block contains
-pub(crate) const DIR_SIZ: usize = 12; // maximum length of file name.
+pub(crate) const DIR_SIZ: usize = 255; // maximum length of file name.
+pub(crate) const MAX_SYMLINK_HOPS: usize = 8; // maximum number of symbolic links followed in a path lookup
 pub(crate) const ROOT_DEV: u32 = 1; // device number of file system root disk
 pub(crate) const ROOT_INUM: u32 = 1; // inode of root
 pub(crate) const NFILE: usize = 100; // maximum open files per system
 pub(crate) const NFILE_PER_ENV: usize = 16; // maximum open files per process
+pub(crate) const NCHILD_PER_ENV: usize = 16; // default limit of children per process
+
+// swap
+pub(crate) const SWAP_DEV: u32 = 2; // device number of swap disk (master of the secondary channel)
+// FIXME: the same definition is in Makefile
+pub(crate) const SWAP_PAGES: usize = 2048; // size of swap disk in pages
+pub(crate) const SWAP_RESERVE_PAGES: usize = 16; // free pages kept for page tables and kernel heap
 
 // device
 pub(crate) const NDEV: usize = 10; // maximum major device number
 pub(crate) const CONSOLE: usize = 1; // major number for console
-pub(crate) const MAX_CMD_ARG_LEN: usize = 32; // maximum length of arguments
+pub(crate) const MAX_CMD_ARG_LEN: usize = 1024; // maximum length of arguments
+pub(crate) const MAX_CMD_ARGS_SIZE: usize = (USTACKSIZE / 2) as usize; // maximum total size of arguments on the user stack
 pub(crate) const MAX_CMD_ARGS: usize = 10; // maximum number of arguments
-pub(crate) const MAX_PATH_LEN: usize = 32; // maximum length of an absolute path
+pub(crate) const MAX_PATH_LEN: usize = 4096; // maximum length of an absolute path
+pub(crate) const MAX_ENV_NAME_LEN: usize = 16; // maximum length of command name saved by exec
 
 // system call error
 // FIXME: the same definition is in user/errno.h
@@ -115,6 +147,16 @@ pub(crate) enum SysError {
     TryAgain,   // Try again
     BrokenPipe, // Broken pipe
     NotChild,   // Not child process
+    LimitExceeded, // Resource limit exceeded
+    NoMem,      // Out of memory
+    NoFreeEnv,  // No free env
+    Fault,      // Bad address
+    NotEmpty,   // Directory not empty
+    Exists,     // File exists
+    Loop,       // Too many levels of symbolic links
+    TooLarge,   // File too large
+    NameTooLong, // File name too long
+    Busy,       // Device or resource busy
 }
 
 impl SysError {
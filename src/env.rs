@@ -1,17 +1,30 @@
 use alloc::boxed::Box;
-use core::ptr::null;
+use alloc::vec::Vec;
 
+use crate::aslr::UserLayout;
 use crate::constants::*;
 use crate::elf::{Elf, ElfParser, Proghdr, ProghdrType};
 use crate::pmap::{PageDirectory, PhysAddr, VirtAddr};
+use crate::rlimit::consts::*;
+use crate::rlimit::{Rlimit, Rlimits};
 use crate::spinlock::{Mutex, MutexGuard};
+use crate::sysinfo::NSTATUS;
 use crate::trap::Trapframe;
-use crate::{file, fs, log, mpconfig, pmap, sched, util, x86};
+use crate::uaccess::UserAccess;
+use crate::{allocator, file, fs, log, mpconfig, pmap, sched, swap, util, x86};
 use core::fmt::{Error, Formatter};
 use core::{cmp, fmt, mem};
 
 const LOG2ENV: u32 = 10;
-const NENV: u32 = 1 << LOG2ENV;
+pub(crate) const NENV: u32 = 1 << LOG2ENV;
+
+// Size of the kernel buffer to load a program from disk
+const LOAD_BUF_SIZE: usize = 1024;
+
+// for getrusage
+// FIXME: the same definition is in user/user.h
+pub(crate) const RUSAGE_SELF: i32 = 0;
+pub(crate) const RUSAGE_CHILDREN: i32 = -1;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) struct EnvId(pub(crate) u32);
@@ -29,8 +42,10 @@ pub(crate) enum EnvType {
     User,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+// FIXME: the same definition is in user/user.h
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[allow(dead_code)]
+#[repr(u32)]
 enum EnvStatus {
     Free,
     Dying,
@@ -40,6 +55,57 @@ enum EnvStatus {
     NotRunnable,
 }
 
+/// Resource usage of an env.
+/// Times are counted in ticks of the time-stamp counter.
+// FIXME: the same definition is in user/user.h
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct Rusage {
+    pub(crate) utime: u64,   // time spent in user mode
+    pub(crate) stime: u64,   // time spent in kernel mode
+    pub(crate) minflt: u32,  // number of page faults
+    pub(crate) nvcsw: u32,   // number of context switches
+    pub(crate) inblock: u32, // number of blocks read from disk
+    pub(crate) oublock: u32, // number of blocks written to disk
+}
+
+impl Rusage {
+    const fn new() -> Rusage {
+        Rusage {
+            utime: 0,
+            stime: 0,
+            minflt: 0,
+            nvcsw: 0,
+            inblock: 0,
+            oublock: 0,
+        }
+    }
+
+    fn add(&mut self, other: &Rusage) {
+        self.utime += other.utime;
+        self.stime += other.stime;
+        self.minflt += other.minflt;
+        self.nvcsw += other.nvcsw;
+        self.inblock += other.inblock;
+        self.oublock += other.oublock;
+    }
+}
+
+/// Snapshot of an env for process listing.
+// FIXME: the same definition is in user/user.h
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct EnvInfo {
+    env_id: u32,
+    parent_id: u32,
+    status: u32,
+    runs: u32,
+    heap_size: u32,
+    cwd_inum: u32,
+    rss: u32, // resident pages
+    name: [u8; MAX_ENV_NAME_LEN],
+}
+
 #[repr(C)]
 pub(crate) struct Env {
     env_tf: Trapframe,                                  // Saved registers
@@ -49,9 +115,16 @@ pub(crate) struct Env {
     env_status: EnvStatus,                              // Status of the environment
     env_runs: u32,                                      // Number of times environment has run
     env_pgdir: Box<PageDirectory>,                      // Kernel virtual address of page dir
-    env_cwd: Arc<RwLock<Inode>>,                        // Current working directory
+    env_cwd: Option<Arc<RwLock<Inode>>>,                // Current working directory
     env_ofile: [Option<FileTableEntry>; NFILE_PER_ENV], // Open files
     env_heap_size: usize,                               // allocated user heap size
+    env_layout: UserLayout,                             // Stack top and heap base
+    env_rusage: Rusage,                                 // Resource usage of this env
+    env_child_rusage: Rusage,                           // Resource usage of reaped children
+    env_last_tsc: u64,                                  // tsc when this env entered or left kernel
+    env_in_kernel: bool,                                // true while this env is handled in kernel
+    env_name: [u8; MAX_ENV_NAME_LEN],                   // Command name saved by exec
+    env_rlimits: Rlimits,                               // Resource limits
 }
 
 impl PartialEq for Env {
@@ -85,11 +158,57 @@ impl Env {
 
     fn pause(&mut self) {
         self.env_status = EnvStatus::Runnable;
+        self.account_system_time();
     }
 
     fn resume(&mut self) {
         self.env_status = EnvStatus::Running;
         self.env_runs += 1;
+        self.account_system_time();
+    }
+
+    /// Called when this env traps into kernel.
+    /// The time since it left kernel is counted as user time.
+    pub(crate) fn account_user_time(&mut self) {
+        let now = x86::rdtsc();
+        if !self.env_in_kernel {
+            self.env_rusage.utime += now - self.env_last_tsc;
+        }
+        self.env_last_tsc = now;
+        self.env_in_kernel = true;
+    }
+
+    /// Called when this env leaves kernel (or is switched out in kernel).
+    /// The time since it entered kernel is counted as system time.
+    fn account_system_time(&mut self) {
+        let now = x86::rdtsc();
+        if self.env_in_kernel {
+            self.env_rusage.stime += now - self.env_last_tsc;
+        }
+        self.env_last_tsc = now;
+        self.env_in_kernel = false;
+    }
+
+    /// Return true if this env has used up its cpu time limit.
+    pub(crate) fn exceeds_cpu_limit(&self) -> bool {
+        let used = self.env_rusage.utime + self.env_rusage.stime;
+        used > self.env_rlimits.cur(RLIMIT_CPU)
+    }
+
+    fn count_context_switch(&mut self) {
+        self.env_rusage.nvcsw += 1;
+    }
+
+    pub(crate) fn count_page_fault(&mut self) {
+        self.env_rusage.minflt += 1;
+    }
+
+    pub(crate) fn count_block_read(&mut self) {
+        self.env_rusage.inblock += 1;
+    }
+
+    pub(crate) fn count_block_write(&mut self) {
+        self.env_rusage.oublock += 1;
     }
 
     fn die(&mut self) {
@@ -116,21 +235,63 @@ impl Env {
         self.env_pgdir.paddr().unwrap()
     }
 
+    pub(crate) fn get_pgdir_mut(&mut self) -> &mut PageDirectory {
+        &mut self.env_pgdir
+    }
+
     pub(crate) fn get_cwd(&self) -> &Arc<RwLock<Inode>> {
-        &self.env_cwd
+        self.env_cwd.as_ref().expect("env has no cwd")
+    }
+
+    /// Save the last element of path as the command name of this env.
+    fn set_name(&mut self, path: *const u8) {
+        let len = util::strnlen(path, MAX_PATH_LEN);
+        let mut start = 0;
+        for i in 0..len {
+            if unsafe { *path.add(i) } == b'/' {
+                start = i + 1;
+            }
+        }
+
+        self.env_name = [0; MAX_ENV_NAME_LEN];
+        let name_len = cmp::min(len - start, MAX_ENV_NAME_LEN - 1);
+        for i in 0..name_len {
+            self.env_name[i] = unsafe { *path.add(start + i) };
+        }
     }
 
-    pub(crate) fn change_cwd(&mut self, ip: &Arc<RwLock<Inode>>) {
-        let old = Arc::clone(&self.env_cwd);
-        fs::iput(old);
-        self.env_cwd = Arc::clone(ip);
+    fn info(&self) -> EnvInfo {
+        let cwd_inum = self.env_cwd.as_ref().map_or(0, |cwd| cwd.read().get_inum());
+        EnvInfo {
+            env_id: self.env_id.0,
+            parent_id: self.env_parent_id.0,
+            status: self.env_status as u32,
+            runs: self.env_runs,
+            heap_size: self.env_heap_size as u32,
+            cwd_inum,
+            rss: self.env_pgdir.resident_pages() as u32,
+            name: self.env_name,
+        }
+    }
+
+    /// Must be called inside a transaction.
+    pub(crate) fn change_cwd(&mut self, ip: Arc<RwLock<Inode>>) {
+        if let Some(old) = self.env_cwd.replace(ip) {
+            fs::iput(old);
+        }
+    }
+
+    /// Return the number of file descriptors this env can use.
+    fn fd_limit(&self) -> usize {
+        cmp::min(self.env_rlimits.cur(RLIMIT_NOFILE), NFILE_PER_ENV as u64) as usize
     }
 
     pub(crate) fn fd_alloc(
         &mut self,
         ent: FileTableEntry,
     ) -> Result<FileDescriptor, FileTableEntry> {
-        for (fd, ent_opt) in self.env_ofile.iter_mut().enumerate() {
+        let limit = self.fd_limit();
+        for (fd, ent_opt) in self.env_ofile.iter_mut().take(limit).enumerate() {
             if ent_opt.is_none() {
                 *ent_opt = Some(ent);
                 return Ok(FileDescriptor(fd as u32));
@@ -154,9 +315,10 @@ impl Env {
     pub(crate) fn fd_dup(&mut self, fd: FileDescriptor) -> Option<FileDescriptor> {
         let ent_opt = self.fd_get(fd).map(|ent| ent.clone());
 
+        let limit = self.fd_limit();
         ent_opt.and_then(|ent| {
             let mut res = None;
-            for (fd, ent_opt) in self.env_ofile.iter_mut().enumerate() {
+            for (fd, ent_opt) in self.env_ofile.iter_mut().take(limit).enumerate() {
                 if ent_opt.is_none() {
                     *ent_opt = Some(ent.clone());
                     res = Some(FileDescriptor(fd as u32));
@@ -169,7 +331,7 @@ impl Env {
 }
 
 pub(crate) struct EnvTable {
-    envs: [Option<Env>; NENV as usize],
+    envs: [Option<Box<Env>>; NENV as usize],
     next_env_id: u32,
 }
 
@@ -184,7 +346,7 @@ impl EnvTable {
         for env_opt in self.envs.iter() {
             if let Some(env) = env_opt {
                 if env.get_env_id() == env_id {
-                    return Some(env);
+                    return Some(env.as_ref());
                 }
             }
         }
@@ -195,7 +357,7 @@ impl EnvTable {
         for env_opt in &mut self.envs.iter_mut() {
             if let Some(env) = env_opt {
                 if env.get_env_id() == env_id {
-                    return Some(env);
+                    return Some(env.as_mut());
                 }
             }
         }
@@ -237,26 +399,55 @@ impl EnvTable {
         None
     }
 
-    /// Allocates and initializes a new environment.
-    /// On success, the new environment is stored in *newenv_store.
-    ///
-    /// Returns 0 on success, < 0 on failure.  Errors include:
-    ///	-E_NO_FREE_ENV if all NENV environments are allocated
-    ///	-E_NO_MEM on memory exhaustion
-    fn env_alloc(&mut self, parent_id: EnvId, typ: EnvType, cwd: Arc<RwLock<Inode>>) -> EnvId {
-        let mut idx = -1;
-        for (i, env_opt) in self.envs.iter().enumerate() {
-            if env_opt.is_none() {
-                idx = i as i32;
-                break;
+    /// Return the page directory of the env at idx if its pages can be swapped out.
+    /// Envs which may be running on any cpu (including dying ones) are excluded.
+    pub(crate) fn swappable_pgdir(&mut self, idx: usize) -> Option<&mut PageDirectory> {
+        match &mut self.envs[idx] {
+            Some(env) if env.is_runnable() || env.env_status == EnvStatus::NotRunnable => {
+                Some(&mut env.env_pgdir)
             }
+            _ => None,
         }
-        if idx == -1 {
-            panic!("no available env");
+    }
+
+    /// Return the number of envs in each EnvStatus.
+    fn count_by_status(&self) -> [u32; NSTATUS] {
+        let mut count = [0; NSTATUS];
+        for env in self.envs.iter().filter_map(|env_opt| env_opt.as_ref()) {
+            count[env.env_status as usize] += 1;
         }
+        count
+    }
+
+    /// Return a snapshot of at most n envs.
+    fn list(&self, n: usize) -> Vec<EnvInfo> {
+        self.envs
+            .iter()
+            .filter_map(|env_opt| env_opt.as_ref())
+            .take(n)
+            .map(|env| env.info())
+            .collect()
+    }
+
+    /// Allocates and initializes a new environment.
+    /// On success, returns the env_id of the new environment.
+    ///
+    /// Errors include:
+    ///	NoFreeEnv if all NENV environments are allocated
+    fn env_alloc(
+        &mut self,
+        parent_id: EnvId,
+        typ: EnvType,
+        cwd: Arc<RwLock<Inode>>,
+    ) -> Result<EnvId, SysError> {
+        let idx = self
+            .envs
+            .iter()
+            .position(|env_opt| env_opt.is_none())
+            .ok_or(SysError::NoFreeEnv)?;
 
         // Allocate and set up the page directory for this environment.
-        let new_pgdir = env_setup_vm();
+        let new_pgdir = env_setup_vm()?;
 
         // Generate an env_id for this environment.
         let new_id = self.generate_env_id();
@@ -273,15 +464,25 @@ impl EnvTable {
             env_status: EnvStatus::Runnable,
             env_runs: 0,
             env_pgdir: new_pgdir,
-            env_cwd: cwd,
+            env_cwd: Some(cwd),
             env_ofile: [None; NFILE_PER_ENV],
             env_heap_size: 0,
+            env_layout: UserLayout::fixed(),
+            env_rusage: Rusage::new(),
+            env_child_rusage: Rusage::new(),
+            env_last_tsc: x86::rdtsc(),
+            env_in_kernel: false,
+            env_name: [0; MAX_ENV_NAME_LEN],
+            env_rlimits: Rlimits::new(),
         };
 
-        let env_opt = &mut self.envs[idx as usize];
-        *env_opt = Some(new_env);
+        let env_opt = &mut self.envs[idx];
+        *env_opt = Some(allocator::try_box(new_env)?);
+
+        // The new env is runnable, so let an idle cpu run it.
+        sched::wakeup_idle_cpu(self);
 
-        new_id
+        Ok(new_id)
     }
 
     /// Set up the initial program binary, stack, and processor flags
@@ -301,7 +502,7 @@ impl EnvTable {
     /// boot/main.c to get ideas.
     ///
     /// Finally, this function maps one page for the program's initial stack.
-    unsafe fn load_icode(&mut self, env_id: EnvId, binary: *const u8) {
+    unsafe fn load_icode(&mut self, env_id: EnvId, binary: *const u8) -> Result<(), SysError> {
         let env = self.find_mut(env_id).expect("illegal env_id");
 
         let elf = ElfParser::new(binary).expect("binary is not elf");
@@ -314,35 +515,40 @@ impl EnvTable {
                 .expect("failed to get a paddr of pgdir"),
         );
 
-        for ph in elf.program_headers() {
-            if ph.p_type != ProghdrType::PtLoad {
-                continue;
-            }
+        let res = (|| {
+            for ph in elf.program_headers() {
+                if ph.p_type != ProghdrType::PtLoad {
+                    continue;
+                }
 
-            let src_va = VirtAddr(binary as u32 + ph.p_offset);
-            let dest_va = VirtAddr(ph.p_vaddr);
-            let memsz = ph.p_memsz as usize;
-            let filesz = ph.p_filesz as usize;
+                let src_va = VirtAddr(binary as u32 + ph.p_offset);
+                let dest_va = VirtAddr(ph.p_vaddr);
+                let memsz = ph.p_memsz as usize;
+                let filesz = ph.p_filesz as usize;
 
-            env.env_pgdir
-                .as_mut()
-                .region_alloc(dest_va, ph.p_memsz as usize);
+                env.env_pgdir
+                    .as_mut()
+                    .region_alloc(dest_va, ph.p_memsz as usize)?;
 
-            util::memcpy(dest_va, src_va, filesz);
-            util::memset(dest_va + filesz, 0, memsz - filesz);
-        }
+                let _ua = UserAccess::begin();
+                util::memcpy(dest_va, src_va, filesz);
+                util::memset(dest_va + filesz, 0, memsz - filesz);
+            }
 
-        // Now map one page for the program's initial stack
-        // at virtual address USTACKTOP - PGSIZE.
-        let stack_base = VirtAddr(USTACKTOP - PGSIZE);
-        let stack_size = USTACKSIZE as usize;
-        env.env_pgdir.region_alloc(stack_base, stack_size);
+            // Now map one page for the program's initial stack
+            // at virtual address USTACKTOP - PGSIZE.
+            let stack_base = VirtAddr(USTACKTOP - PGSIZE);
+            let stack_size = USTACKSIZE as usize;
+            env.env_pgdir.region_alloc(stack_base, stack_size)
+        })();
 
         // Restore kern page directory
         x86::lcr3(kern_pgdir);
+        res?;
 
         // Set trapframe
         env.set_entry_point(elf.entry_point());
+        Ok(())
     }
 
     /// Frees resources and memory the env uses except for the entry of env_table.
@@ -380,11 +586,18 @@ impl EnvTable {
             match ent_opt {
                 None => (),
                 Some(ent) => {
-                    file::file_table().close(ent);
+                    file::close(ent);
                 }
             }
         }
 
+        // Release the current working directory.
+        if let Some(cwd) = env.env_cwd.take() {
+            log::begin_op();
+            fs::iput(cwd);
+            log::end_op();
+        }
+
         // Change the state to zombie.
         // Call wait_env_id to release the entry later.
         env.env_status = EnvStatus::Zombie;
@@ -397,30 +610,56 @@ impl EnvTable {
         env_id: EnvId,
         parent_env_id: EnvId,
     ) -> Result<EnvId, SysError> {
-        let _child = match self.find(env_id) {
+        let child = match self.find(env_id) {
             None => Err(SysError::NotChild),
             Some(child) if child.env_parent_id != parent_env_id => Err(SysError::NotChild),
             Some(child) if !child.is_zombie() => Err(SysError::TryAgain),
             Some(child) => Ok(child),
         }?;
 
+        // Fold the resource usage of the child (and its children) into the parent.
+        let mut usage = child.env_rusage;
+        usage.add(&child.env_child_rusage);
+        if let Some(parent) = self.find_mut(parent_env_id) {
+            parent.env_child_rusage.add(&usage);
+        }
+
         let idx = self.get_idx(env_id).unwrap();
         self.envs[idx] = None;
         Ok(env_id)
     }
 
+    /// Return the number of children of parent_id (including zombies).
+    fn count_children(&self, parent_id: EnvId) -> usize {
+        self.envs
+            .iter()
+            .filter_map(|env_opt| env_opt.as_ref())
+            .filter(|env| env.env_parent_id == parent_id)
+            .count()
+    }
+
     /// Create a new process copying p as the parent.
     /// Sets up stack to return as if from system call.
     /// Caller must set state of returned proc to RUNNABLE.
     ///
     /// ref. fork() in proc.c (xv6)
-    fn fork(&mut self, parent: &mut Env) -> EnvId {
+    fn fork(&mut self, parent: &mut Env) -> Result<EnvId, SysError> {
+        let nchild = self.count_children(parent.env_id) as u64;
+        if nchild >= parent.env_rlimits.cur(RLIMIT_NCHILD) {
+            return Err(SysError::LimitExceeded);
+        }
+
         // Allocate process.
-        let new_env_id = self.env_alloc(parent.env_id, EnvType::User, parent.get_cwd().clone());
+        let new_env_id = self.env_alloc(parent.env_id, EnvType::User, parent.get_cwd().clone())?;
         let new_env = self.find_mut(new_env_id).unwrap();
 
         // Copy process state from parent.
-        new_env.env_pgdir.copy_uvm(&mut parent.env_pgdir);
+        // The half-copied env is released (and its pages freed) on failure.
+        if let Err(err) = new_env.env_pgdir.copy_uvm(&mut parent.env_pgdir) {
+            let idx = self.get_idx(new_env_id).unwrap();
+            self.envs[idx] = None;
+            return Err(err);
+        }
 
         new_env.env_tf = parent.env_tf;
 
@@ -432,7 +671,12 @@ impl EnvTable {
             new_env.env_ofile[i] = ent_opt.clone();
         }
 
-        new_env_id
+        new_env.env_heap_size = parent.env_heap_size;
+        new_env.env_layout = parent.env_layout;
+        new_env.env_name = parent.env_name;
+        new_env.env_rlimits = parent.env_rlimits;
+
+        Ok(new_env_id)
     }
 }
 
@@ -445,6 +689,11 @@ pub(crate) fn env_table() -> MutexGuard<'static, EnvTable> {
     ENV_TABLE.lock()
 }
 
+/// Lock EnvTable only if it is not locked now.
+pub(crate) fn try_env_table() -> Option<MutexGuard<'static, EnvTable>> {
+    ENV_TABLE.try_lock()
+}
+
 pub(crate) fn cur_env() -> Option<&'static Env> {
     mpconfig::this_cpu().cur_env()
 }
@@ -461,7 +710,7 @@ pub(crate) fn cur_env_mut() -> Option<&'static mut Env> {
 //
 // Returns 0 on success, < 0 on error.  Errors include:
 //	-E_NO_MEM if page directory or table could not be allocated.
-fn env_setup_vm() -> Box<PageDirectory> {
+fn env_setup_vm() -> Result<Box<PageDirectory>, SysError> {
     PageDirectory::new_for_user()
 }
 
@@ -484,16 +733,24 @@ pub(crate) fn env_create_for_init(env_table: &mut EnvTable) -> EnvId {
     }
 
     let root_inode = crate::fs::iget(ROOT_DEV, ROOT_INUM);
-    let env_id = env_table.env_alloc(EnvId(0), EnvType::User, root_inode);
+    let env_id = env_table
+        .env_alloc(EnvId(0), EnvType::User, root_inode)
+        .expect("failed to allocate the init env");
 
     unsafe {
         let user_init_start = &_binary_obj_user_init_start as *const u8;
         let _user_init_end = &_binary_obj_user_init_end as *const u8;
         let _user_init_size = &_binary_obj_user_init_size as *const usize;
 
-        env_table.load_icode(env_id, user_init_start);
+        env_table
+            .load_icode(env_id, user_init_start)
+            .expect("failed to load the init env");
     }
 
+    let init_name = b"init\0";
+    let env = env_table.find_mut(env_id).unwrap();
+    env.set_name(init_name.as_ptr());
+
     env_id
 }
 
@@ -525,6 +782,11 @@ fn env_pop_tf(tf: *const Trapframe) -> ! {
 pub(crate) fn env_run(env_id: EnvId, mut table: MutexGuard<EnvTable>) -> ! {
     if let Some(cur) = cur_env_mut().filter(|e| e.is_running()) {
         cur.pause();
+        if cur.get_env_id() != env_id {
+            cur.count_context_switch();
+            // cur is runnable again but this cpu runs another env, so let an idle cpu run it.
+            sched::wakeup_idle_cpu(&table);
+        }
     }
 
     let env = (*table).find_mut(env_id).unwrap();
@@ -533,6 +795,7 @@ pub(crate) fn env_run(env_id: EnvId, mut table: MutexGuard<EnvTable>) -> ! {
     env.resume();
     mpconfig::this_cpu_mut().set_env(env);
     x86::lcr3(env.env_pgdir.paddr().unwrap());
+    mpconfig::this_cpu_mut().tlb_flushed();
 
     // Unlock EnvTable
     drop(table);
@@ -569,70 +832,63 @@ pub(crate) fn env_destroy(env_id: EnvId, mut env_table: MutexGuard<EnvTable>) {
     }
 }
 
-/// Checks that environment 'env' is allowed to access the range
-/// of memory [va, va+len) with permissions 'perm | PTE_U | PTE_P'.
-/// If it can, then the function simply returns.
-/// If it cannot, 'env' is destroyed and, if env is the current
-/// environment, this function will not return.
-pub(crate) fn user_mem_assert(env: &mut Env, va: VirtAddr, len: usize, perm: u32) {
-    if let Err(addr) = env.env_pgdir.user_mem_check(va, len, perm | PTE_U) {
-        println!(
-            "[{:08x}] user_mem_check assertion failure for va {:08x}",
-            env.env_id, addr.0
-        );
-
-        let env_table = env_table();
-        env_destroy(env.get_env_id(), env_table);
-    }
-}
+pub(crate) fn fork(parent: &mut Env) -> Result<EnvId, SysError> {
+    // Swap out pages of other envs if memory is short.
+    // This is done before locking EnvTable because it takes disk I/O.
+    swap::ensure_free_pages(parent.env_pgdir.count_user_pages());
 
-pub(crate) fn fork(parent: &mut Env) -> EnvId {
     let mut env_table = env_table();
     env_table.fork(parent)
 }
 
-fn load_from_disk(mut dst: VirtAddr, inode: &mut Inode, mut off: u32, mut remain_sz: u32) {
+/// Read remain_sz bytes at off of inode into dst, a user address of the current page directory.
+/// The data goes through a kernel buffer so that user pages are accessible (see UserAccess)
+/// only while it is copied, not while waiting for the disk.
+fn load_from_disk(
+    mut dst: VirtAddr,
+    inode: &mut Inode,
+    mut off: u32,
+    mut remain_sz: u32,
+) -> Result<(), SysError> {
+    let mut kbuf = [0 as u8; LOAD_BUF_SIZE];
     while remain_sz > 0 {
-        let sz = cmp::min(PGSIZE, remain_sz);
-        if fs::readi(inode, dst.as_mut_ptr(), off, sz) != Some(sz) {
-            panic!("load_from_disk: failed to readi");
+        let sz = cmp::min(LOAD_BUF_SIZE as u32, remain_sz);
+        if fs::readi(inode, kbuf.as_mut_ptr(), off, sz) != Some(sz) {
+            return Err(SysError::InvalidArg);
+        }
+        {
+            let _ua = UserAccess::begin();
+            unsafe { util::memcpy(dst, VirtAddr(kbuf.as_ptr() as u32), sz as usize) };
         }
         dst += sz;
         off += sz;
         remain_sz -= sz;
     }
+    Ok(())
 }
 
-pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result<(), SysError> {
-    // check path and return error without changing pgdir if path is illegal.
-    let ip = fs::namei(path)
-        .into_result()
-        .map_err(|_| SysError::InvalidArg)?;
-    let mut inode = fs::ilock(&ip);
-
-    // Allocate and set up the page directory for this environment.
-    let new_pgdir = env_setup_vm();
-    env.env_pgdir = new_pgdir;
-
-    // Change page directory to that of env temporally
-    x86::lcr3(env.get_pgdir_paddr());
-
-    log::begin_op();
-
+/// Load all loadable segments of the elf file and the initial stack into pgdir,
+/// which must be the current page directory.
+/// Return the entry point on success.
+fn load_elf(
+    pgdir: &mut PageDirectory,
+    inode: &mut Inode,
+    layout: &UserLayout,
+) -> Result<VirtAddr, SysError> {
     // Read ELF header
     let mut buf_elf = [0 as u8; mem::size_of::<Elf>()];
     let elf = unsafe { &*(buf_elf.as_ptr() as *const Elf) };
     if fs::readi(
-        &mut inode,
+        inode,
         buf_elf.as_mut_ptr(),
         0,
         mem::size_of::<Elf>() as u32,
     ) != Some(mem::size_of::<Elf>() as u32)
     {
-        panic!("exec: failed to read elf header")
+        return Err(SysError::InvalidArg);
     }
     if !elf.is_valid() {
-        panic!("exec: illgal elf header");
+        return Err(SysError::InvalidArg);
     }
 
     let mut buf_ph = [0 as u8; mem::size_of::<Proghdr>()];
@@ -643,13 +899,13 @@ pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result
         {
             let off = elf.e_phoff + (mem::size_of::<Proghdr>() as u32) * (i as u32);
             if fs::readi(
-                &mut inode,
+                inode,
                 buf_ph.as_mut_ptr(),
                 off,
                 mem::size_of::<Proghdr>() as u32,
             ) != Some(mem::size_of::<Proghdr>() as u32)
             {
-                panic!("exec: failed to read program header");
+                return Err(SysError::InvalidArg);
             }
         }
 
@@ -660,29 +916,86 @@ pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result
         let dest_va = VirtAddr(ph.p_vaddr);
         let memsz = ph.p_memsz as usize;
         let filesz = ph.p_filesz as usize;
+        if filesz > memsz || dest_va.0.checked_add(ph.p_memsz).map_or(true, |end| end > UTOP) {
+            return Err(SysError::InvalidArg);
+        }
 
         // Allocation necessary memory
-        env.env_pgdir.as_mut().region_alloc(dest_va, memsz);
+        let pgsize = PGSIZE as usize;
+        let npages = (dest_va.0 as usize % pgsize + memsz + pgsize - 1) / pgsize;
+        swap::ensure_free_pages(npages);
+        pgdir.region_alloc(dest_va, memsz)?;
 
         // Load data from disk (and occupy zero)
-        unsafe {
-            load_from_disk(dest_va, &mut inode, ph.p_offset, filesz as u32);
-            // util::memcpy(dest_va, src_va, filesz);
-            util::memset(dest_va + filesz, 0, memsz - filesz);
-        }
+        load_from_disk(dest_va, inode, ph.p_offset, filesz as u32)?;
+        let _ua = UserAccess::begin();
+        unsafe { util::memset(dest_va + filesz, 0, memsz - filesz) };
+    }
+
+    // Now map pages for the program's initial stack
+    // at virtual address layout.stack_top - USTACKSIZE.
+    let stack_base = VirtAddr(layout.stack_base());
+    pgdir.region_alloc(stack_base, USTACKSIZE as usize)?;
+
+    Ok(elf.entry_point())
+}
+
+pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result<(), SysError> {
+    // check path and return error without changing pgdir if path is illegal.
+    let ip = fs::namei(path)
+        .into_result()
+        .map_err(|_| SysError::InvalidArg)?;
+    let mut inode = fs::ilock(&ip);
+
+    // check the stack limit before changing pgdir.
+    if USTACKSIZE as u64 > env.env_rlimits.cur(RLIMIT_STACK) {
+        fs::iunlock(inode);
+        log::begin_op();
+        fs::iput(ip);
+        log::end_op();
+        return Err(SysError::LimitExceeded);
     }
 
+    // Allocate and set up a new page directory.
+    // The old one is kept until the new image is loaded successfully.
+    let mut new_pgdir = match env_setup_vm() {
+        Ok(pgdir) => pgdir,
+        Err(err) => {
+            fs::iunlock(inode);
+            log::begin_op();
+            fs::iput(ip);
+            log::end_op();
+            return Err(err);
+        }
+    };
+
+    // Change page directory to the new one temporally
+    x86::lcr3(
+        new_pgdir
+            .paddr()
+            .expect("failed to get a paddr of pgdir"),
+    );
+
+    let layout = UserLayout::new();
+    log::begin_op();
+    let res = load_elf(&mut new_pgdir, &mut inode, &layout);
     fs::iunlock(inode);
     log::end_op();
 
-    // Now map one page for the program's initial stack
-    // at virtual address USTACKTOP - PGSIZE.
-    let stack_base = VirtAddr(USTACKTOP - USTACKSIZE);
-    let stack_size = USTACKSIZE as usize;
-    env.env_pgdir.region_alloc(stack_base, stack_size);
+    let entry_point = match res {
+        Ok(entry_point) => entry_point,
+        Err(err) => {
+            // Back to the old address space. new_pgdir is freed by Drop.
+            x86::lcr3(env.get_pgdir_paddr());
+            return Err(err);
+        }
+    };
 
     // Prepare args
+    let stack_base = VirtAddr(layout.stack_base());
+    let stack_size = USTACKSIZE as usize;
     let mut sp: *mut u8 = stack_base.add(stack_size).as_mut_ptr();
+    let ua = UserAccess::begin();
     unsafe {
         let mut ustack = [0 as u32; 3 + MAX_CMD_ARGS]; // +3 is for return address, argv, and argc
         for (i, s) in argv.iter().enumerate() {
@@ -702,6 +1015,12 @@ pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result
         *sp.cast::<u32>() = argv.len() as u32; // argc
         *sp.add(4).cast::<u32>() = sp.add(12) as u32; // argv
     }
+    drop(ua);
+
+    // The new image is ready, so the old address space can be freed.
+    env.env_pgdir = new_pgdir;
+    env.env_heap_size = 0;
+    env.env_layout = layout;
 
     // Set up appropriate initial values for the segment registers.
     // You will set e->env_tf.tf_eip later.
@@ -710,7 +1029,9 @@ pub(crate) fn exec(path: *const u8, argv: &[*const u8], env: &mut Env) -> Result
     env.env_tf.tf_esp = sp as usize;
 
     // Set trapframe
-    env.set_entry_point(elf.entry_point());
+    env.set_entry_point(entry_point);
+
+    env.set_name(path);
     Ok(())
 }
 
@@ -721,8 +1042,8 @@ pub(crate) fn wait_env_id(env_id: EnvId) -> Result<EnvId, SysError> {
 }
 
 /// Allocate user heap.
-/// Assume that the initial break is UHEAPBASE.
-pub(crate) fn sbrk(nbytes: usize) -> *const u8 {
+/// The initial break is env_layout.heap_base.
+pub(crate) fn sbrk(nbytes: usize) -> Result<*const u8, SysError> {
     let env = cur_env_mut().unwrap();
     let pgdir = &mut env.env_pgdir;
 
@@ -732,13 +1053,47 @@ pub(crate) fn sbrk(nbytes: usize) -> *const u8 {
         (nbytes + pgsize - 1) / pgsize * pgsize
     };
 
-    if env.env_heap_size + required_size > UHEAPSIZE {
-        return null();
+    let limit = cmp::min(env.env_rlimits.cur(RLIMIT_HEAP), UHEAPSIZE as u64);
+    if (env.env_heap_size + required_size) as u64 > limit {
+        return Err(SysError::LimitExceeded);
     }
 
-    let cur_heap_top = VirtAddr(UHEAPBASE + (env.env_heap_size as u32));
-    pgdir.region_alloc(cur_heap_top, required_size);
+    let cur_heap_top = VirtAddr(env.env_layout.heap_base + (env.env_heap_size as u32));
+    swap::ensure_free_pages(required_size / PGSIZE as usize);
+    pgdir.region_alloc(cur_heap_top, required_size)?;
     env.env_heap_size += required_size;
 
-    cur_heap_top.as_ptr::<u8>()
+    Ok(cur_heap_top.as_ptr::<u8>())
+}
+
+/// Return a snapshot of at most n envs.
+pub(crate) fn env_list(n: usize) -> Vec<EnvInfo> {
+    let env_table = env_table();
+    env_table.list(n)
+}
+
+/// Return the number of envs in each EnvStatus.
+pub(crate) fn env_count_by_status() -> [u32; NSTATUS] {
+    let env_table = env_table();
+    env_table.count_by_status()
+}
+
+/// Return resource usage of the current env or its reaped children.
+pub(crate) fn getrusage(who: i32) -> Result<Rusage, SysError> {
+    let env = cur_env_mut().unwrap();
+    match who {
+        RUSAGE_SELF => Ok(env.env_rusage),
+        RUSAGE_CHILDREN => Ok(env.env_child_rusage),
+        _ => Err(SysError::InvalidArg),
+    }
+}
+
+pub(crate) fn getrlimit(resource: usize) -> Result<Rlimit, SysError> {
+    let env = cur_env().unwrap();
+    env.env_rlimits.get(resource)
+}
+
+pub(crate) fn setrlimit(resource: usize, limit: Rlimit) -> Result<(), SysError> {
+    let env = cur_env_mut().unwrap();
+    env.env_rlimits.set(resource, limit)
 }
@@ -3,7 +3,8 @@ use crate::fs::Inode;
 use crate::pipe::Pipe;
 use crate::rwlock::RwLock;
 use crate::spinlock::{Mutex, MutexGuard};
-use crate::{fs, log, pipe};
+use crate::sysfile::consts::{SEEK_CUR, SEEK_END, SEEK_SET};
+use crate::{fs, log, pipe, superblock};
 use alloc::sync::Arc;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -17,17 +18,24 @@ pub(crate) struct File {
     typ: FileType,
     readable: bool,
     writable: bool,
+    append: bool, // each write goes to the end of the file
     pipe: Option<Arc<RwLock<Pipe>>>,
     ip: Option<Arc<RwLock<Inode>>>,
     off: u32,
 }
 
 impl File {
-    fn new_for_inode(readable: bool, writable: bool, ip: &Arc<RwLock<Inode>>) -> File {
+    fn new_for_inode(
+        readable: bool,
+        writable: bool,
+        append: bool,
+        ip: &Arc<RwLock<Inode>>,
+    ) -> File {
         File {
             typ: FileType::Inode,
             readable,
             writable,
+            append,
             pipe: None,
             ip: Some(Arc::clone(ip)),
             off: 0,
@@ -39,6 +47,7 @@ impl File {
             typ: FileType::Pipe,
             readable,
             writable,
+            append: false,
             pipe: Some(Arc::clone(p)),
             ip: None,
             off: 0,
@@ -69,24 +78,36 @@ impl File {
                 p.read(addr, n)
             }
             FileType::Inode => {
-                let ip = self.ip.as_ref().unwrap();
-                let mut inode = fs::ilock(&ip);
-                let cnt_opt = fs::readi(&mut inode, addr, self.off, n as u32);
-                let res = match cnt_opt {
-                    None => Err(SysError::TryAgain),
-                    Some(cnt) => {
-                        if cnt > 0 {
-                            self.off += cnt;
-                        }
-                        Ok(cnt as usize)
-                    }
-                };
-                fs::iunlock(inode);
-                res
+                let cnt = self.read_at(addr, n, self.off)?;
+                self.off += cnt as u32;
+                Ok(cnt)
             }
         }
     }
 
+    /// Read from file at off without moving the offset of the file.
+    pub(crate) fn pread(&self, addr: *mut u8, n: usize, off: u32) -> Result<usize, SysError> {
+        if !self.readable {
+            return Err(SysError::IllegalFileDescriptor);
+        }
+        if self.typ != FileType::Inode {
+            // a pipe has no offset
+            return Err(SysError::InvalidArg);
+        }
+        self.read_at(addr, n, off)
+    }
+
+    fn read_at(&self, addr: *mut u8, n: usize, off: u32) -> Result<usize, SysError> {
+        let ip = self.ip.as_ref().unwrap();
+        let mut inode = fs::ilock(&ip);
+        let cnt_opt = fs::readi(&mut inode, addr, off, n as u32);
+        fs::iunlock(inode);
+        match cnt_opt {
+            None => Err(SysError::TryAgain),
+            Some(cnt) => Ok(cnt as usize),
+        }
+    }
+
     /// Write to file.
     pub(crate) fn write(&mut self, addr: *const u8, n: usize) -> Result<usize, SysError> {
         if !self.writable {
@@ -99,40 +120,181 @@ impl File {
                 p.write(addr, n)
             }
             FileType::Inode => {
-                // write a few blocks at a time to avoid exceeding
-                // the maximum log transaction size, including
-                // i-node, indirect block, allocation blocks,
-                // and 2 blocks of slop for non-aligned writes.
-                // this really belongs lower down, since writei()
-                // might be writing a device like the console.
-                let max = ((MAX_OP_BLOCKS - 1 - 1 - 2) / 2) * 512;
-                let mut i = 0;
-                while i < n {
-                    let mut n1 = n - i;
-                    if n1 > max {
-                        n1 = max;
-                    }
-
-                    log::begin_op();
-                    let ip = self.ip.as_ref().unwrap();
-                    let mut inode = fs::ilock(&ip);
-                    let r = fs::writei(&mut inode, addr, self.off, n as u32);
-                    if r > 0 {
-                        self.off += r;
-                    }
-                    fs::iunlock(inode);
-                    log::end_op();
-
-                    if r != n1 as u32 {
-                        panic!("File::write: short file write");
-                    }
-
-                    i += r as usize;
+                let off = if self.append { None } else { Some(self.off) };
+                let (cnt, end) = self.write_at(addr, n, off)?;
+                if cnt > 0 {
+                    self.off = end;
                 }
+                Ok(cnt)
+            }
+        }
+    }
+
+    /// Start a write(2) to the file, which may be done by several calls of write.
+    /// An append excludes the other appends to the inode until end_write,
+    /// so that they don't interleave with the chunks of it.
+    pub(crate) fn begin_write(&self) {
+        if self.typ == FileType::Inode && self.append {
+            fs::begin_append(self.ip.as_ref().unwrap());
+        }
+    }
+
+    /// Finish the write(2) started by begin_write.
+    pub(crate) fn end_write(&self) {
+        if self.typ == FileType::Inode && self.append {
+            fs::end_append(self.ip.as_ref().unwrap());
+        }
+    }
+
+    /// Write to file at off without moving the offset of the file.
+    pub(crate) fn pwrite(&self, addr: *const u8, n: usize, off: u32) -> Result<usize, SysError> {
+        if !self.writable {
+            return Err(SysError::IllegalFileDescriptor);
+        }
+        if self.typ != FileType::Inode {
+            // a pipe has no offset
+            return Err(SysError::InvalidArg);
+        }
+        self.write_at(addr, n, Some(off)).map(|(cnt, _)| cnt)
+    }
+
+    /// Write at off, or at the end of the file if off is None.
+    /// Return the count of written bytes and the offset just after them.
+    fn write_at(
+        &self,
+        addr: *const u8,
+        n: usize,
+        mut off: Option<u32>,
+    ) -> Result<(usize, u32), SysError> {
+        let ip = self.ip.as_ref().unwrap();
+        let max_file_size = superblock::get().max_file_size() as u64;
+
+        // write a few blocks at a time to avoid exceeding
+        // the maximum log transaction size, including
+        // i-node, two blocks for each level of indirect blocks
+        // (a write may straddle two of them), two bitmap blocks,
+        // and the data blocks. an unaligned write touches
+        // one more data block than its size in blocks.
+        // this really belongs lower down, since writei()
+        // might be writing a device like the console.
+        let max =
+            (MAX_OP_BLOCKS - 1 - 2 * NINDIRECT_LEVELS - 2 - 1) * superblock::get().block_size();
+        let mut i = 0;
+        let mut end = off.unwrap_or(0);
+        while i < n {
+            let mut n1 = n - i;
+            if n1 > max {
+                n1 = max;
+            }
+
+            log::begin_op();
+            let mut inode = fs::ilock(&ip);
+            // The end is found under the lock, so appends never overwrite each other.
+            let off1 = match off {
+                Some(off) => off,
+                None => inode.get_size(),
+            };
+            // devices don't care about offsets.
+            if !inode.is_dev() && off1 as u64 + n1 as u64 > max_file_size {
+                fs::iunlock(inode);
+                log::end_op();
+                if i == 0 {
+                    return Err(SysError::TooLarge);
+                }
+                break;
+            }
+            let r = fs::writei(&mut inode, unsafe { addr.add(i) }, off1, n1 as u32);
+            fs::iunlock(inode);
+            log::end_op();
 
-                Ok(n)
+            if r != n1 as u32 {
+                panic!("File::write: short file write");
             }
+
+            end = off1 + r;
+            off = off.map(|off| off + r);
+            i += r as usize;
+        }
+
+        Ok((i, end))
+    }
+
+    /// Read the entries of the directory as records into buf.
+    /// The offset of the file is the position in the directory,
+    /// so the next call continues from the entry after the last returned one.
+    pub(crate) fn getdents(&mut self, buf: &mut [u8]) -> Result<usize, SysError> {
+        if !self.readable {
+            return Err(SysError::IllegalFileDescriptor);
+        }
+        if self.typ != FileType::Inode {
+            return Err(SysError::NotDir);
+        }
+
+        let ip = self.ip.as_ref().unwrap();
+        let mut inode = fs::ilock(&ip);
+        let res = if inode.is_dir() {
+            fs::dir_read_records(&mut inode, &mut self.off, buf)
+        } else {
+            Err(SysError::NotDir)
+        };
+        fs::iunlock(inode);
+        res
+    }
+
+    /// Change the size of the file to len.
+    /// The blocks past it are freed, and growing the file leaves a hole.
+    pub(crate) fn truncate(&self, len: u32) -> Result<(), SysError> {
+        if !self.writable {
+            return Err(SysError::IllegalFileDescriptor);
+        }
+        if self.typ != FileType::Inode {
+            return Err(SysError::InvalidArg);
+        }
+        if len > superblock::get().max_file_size() {
+            return Err(SysError::TooLarge);
         }
+
+        let ip = self.ip.as_ref().unwrap();
+        let inode = fs::ilock(&ip);
+        let is_file = inode.is_file();
+        fs::iunlock(inode);
+        if !is_file {
+            return Err(SysError::InvalidArg);
+        }
+
+        fs::itrunc(ip, len);
+        Ok(())
+    }
+
+    /// Move the offset of the file and return the new one.
+    /// It can be past the end of the file, and a write there leaves a hole.
+    pub(crate) fn seek(&mut self, offset: i32, whence: u32) -> Result<u32, SysError> {
+        if self.typ != FileType::Inode {
+            // a pipe has no offset
+            return Err(SysError::InvalidArg);
+        }
+
+        let base = if whence == SEEK_SET {
+            0
+        } else if whence == SEEK_CUR {
+            self.off as i64
+        } else if whence == SEEK_END {
+            let ip = self.ip.as_ref().unwrap();
+            let inode = fs::ilock(&ip);
+            let size = inode.get_size();
+            fs::iunlock(inode);
+            size as i64
+        } else {
+            return Err(SysError::InvalidArg);
+        };
+
+        // The offset is returned to user space as a non-negative i32.
+        let off = base + offset as i64;
+        if off < 0 || off > i32::MAX as i64 {
+            return Err(SysError::InvalidArg);
+        }
+        self.off = off as u32;
+        Ok(self.off)
     }
 }
 
@@ -167,12 +329,15 @@ impl FileTable {
         &mut self,
         readable: bool,
         writable: bool,
+        append: bool,
         ip: &Arc<RwLock<Inode>>,
     ) -> Option<FileTableEntry> {
         match self.find_empty_entry() {
             None => None,
             Some(i) => {
-                let f = Arc::new(RwLock::new(File::new_for_inode(readable, writable, ip)));
+                let f = Arc::new(RwLock::new(File::new_for_inode(
+                    readable, writable, append, ip,
+                )));
                 self.files[i] = Some(Arc::clone(&f));
                 Some(FileTableEntry { file: f, index: i })
             }
@@ -212,7 +377,9 @@ impl FileTable {
     }
 
     /// Close file f. (Decrement ref count, close when reaches 0.)
-    pub(crate) fn close(&mut self, entry: FileTableEntry) {
+    /// The inode of the file is returned when it is closed,
+    /// and the caller must iput it after releasing the file table.
+    fn close(&mut self, entry: FileTableEntry) -> Option<Arc<RwLock<Inode>>> {
         let ref_cnt = Arc::strong_count(&entry.file);
 
         if ref_cnt <= 1 {
@@ -221,25 +388,19 @@ impl FileTable {
             // it means only me refers to the file because FileTable itself has one reference.
             let ind = entry.index;
             let mut f = entry.file.write();
-            let typ = f.typ;
-
-            if typ == FileType::Pipe {
-                pipe::close(f.pipe.take().unwrap(), f.writable);
-            } else if typ == FileType::Inode {
-                if let Some(orig_ip) = &f.ip {
-                    // FIXME: how to handle ownership of ip correctly...
-                    let ip = Arc::clone(orig_ip);
-                    // drop(entry);
-
-                    log::begin_op();
-                    fs::iput(ip);
-                    log::end_op();
+            let ip = match f.typ {
+                FileType::Pipe => {
+                    pipe::close(f.pipe.take().unwrap(), f.writable);
+                    None
                 }
-            }
+                FileType::Inode => f.ip.take(),
+            };
 
             self.files[ind] = None;
+            ip
         } else {
             // just drop file
+            None
         }
     }
 }
@@ -250,6 +411,19 @@ pub(crate) fn file_table() -> MutexGuard<'static, FileTable> {
     FILE_TABLE.lock()
 }
 
+/// Close file f.
+/// The last reference of the file to the inode is passed to iput
+/// so that an unlinked inode is freed. The transaction is begun after
+/// the file table is released, since begin_op may wait for other operations.
+pub(crate) fn close(entry: FileTableEntry) {
+    let ip = file_table().close(entry);
+    if let Some(ip) = ip {
+        log::begin_op();
+        fs::iput(ip);
+        log::end_op();
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 pub(crate) struct FileDescriptor(pub(crate) u32);
@@ -9,9 +9,11 @@ use crate::{buf, device, env, log, superblock, util};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cmp::min;
 use core::mem;
-use core::ptr::{null, null_mut};
+use core::ptr::{self, null, null_mut};
+use core::slice;
 
 // TODO: Summarize disk layout
 // - offset 0x4000 is start of inodes.
@@ -23,6 +25,7 @@ pub(crate) enum InodeType {
     Dir,
     File,
     Dev,
+    Symlink,
 }
 
 /// in-memory copy of an inode
@@ -33,11 +36,12 @@ pub(crate) struct Inode {
     valid: bool, // already read data from disk
     // the below is same as DInode
     typ: InodeType,
-    major: u16,                // major device number (T_DEV only)
-    minor: u16,                // minor device number (T_DEV only)
-    nlink: u16,                // number of links to inode in file system
-    size: u32,                 // size of file (bytes)
-    addrs: [u32; NDIRECT + 1], // data block addresses
+    major: u16,           // major device number (T_DEV only)
+    minor: u16,           // minor device number (T_DEV only)
+    nlink: u16,           // number of links to inode in file system
+    size: u32,            // size of file (bytes)
+    addrs: [u32; NADDRS], // data block addresses
+    is_appending: bool,   // an append is in progress (see begin_append)
 }
 
 impl Inode {
@@ -51,7 +55,8 @@ impl Inode {
             minor: 0,
             nlink: 0,
             size: 0,
-            addrs: [0; NDIRECT + 1],
+            addrs: [0; NADDRS],
+            is_appending: false,
         }
     }
 
@@ -69,6 +74,18 @@ impl Inode {
         self.typ == InodeType::File
     }
 
+    pub(crate) fn is_symlink(&self) -> bool {
+        self.typ == InodeType::Symlink
+    }
+
+    pub(crate) fn is_dev(&self) -> bool {
+        self.typ == InodeType::Dev
+    }
+
+    pub(crate) fn get_type(&self) -> InodeType {
+        self.typ
+    }
+
     pub(crate) fn get_dev(&self) -> u32 {
         self.dev
     }
@@ -81,6 +98,10 @@ impl Inode {
         self.nlink
     }
 
+    pub(crate) fn get_size(&self) -> u32 {
+        self.size
+    }
+
     pub(crate) fn incr_nlink(&mut self) {
         self.nlink += 1;
     }
@@ -88,11 +109,13 @@ impl Inode {
         self.nlink -= 1;
     }
 
-    /// Return blockno of data at off bytes
-    fn block_for(&mut self, off: u32, bcache: &mut BufCache) -> u32 {
-        let mut off_as_blk = (off as usize) / BLK_SIZE;
+    /// Return blockno of data at off bytes, allocating it if does_alloc == true.
+    /// Return 0 for a hole if does_alloc == false.
+    fn block_for(&mut self, off: u32, does_alloc: bool, bcache: &mut BufCache) -> u32 {
+        let sb = superblock::get();
+        let mut off_as_blk = (off as usize) / sb.block_size();
         if off_as_blk < NDIRECT {
-            if self.addrs[off_as_blk] == 0 {
+            if self.addrs[off_as_blk] == 0 && does_alloc {
                 self.addrs[off_as_blk] = balloc(self.dev, bcache);
             }
             return self.addrs[off_as_blk];
@@ -100,28 +123,69 @@ impl Inode {
 
         off_as_blk -= NDIRECT;
 
-        if off_as_blk < NINDIRECT {
-            // load indirect block, allocating if necessary
-            if self.addrs[NDIRECT] == 0 {
-                self.addrs[NDIRECT] = balloc(self.dev, bcache);
+        // addrs[NDIRECT + level - 1] is the root of the tree of indirect blocks
+        // whose depth is level.
+        let mut nblocks = sb.nindirect();
+        for level in 1..=NINDIRECT_LEVELS {
+            if off_as_blk < nblocks {
+                // load indirect block, allocating if necessary
+                let idx = NDIRECT + level - 1;
+                if self.addrs[idx] == 0 {
+                    if !does_alloc {
+                        return 0;
+                    }
+                    self.addrs[idx] = balloc(self.dev, bcache);
+                }
+                return indirect_block_for(
+                    self.dev,
+                    self.addrs[idx],
+                    level,
+                    off_as_blk,
+                    does_alloc,
+                    bcache,
+                );
             }
+            off_as_blk -= nblocks;
+            nblocks *= sb.nindirect();
+        }
 
-            let mut bp = bcache.get(self.dev, self.addrs[NDIRECT]);
-            bp.read();
+        panic!("addr_for: out of range");
+    }
+}
 
-            let ap = unsafe { &mut *bp.data_mut().as_mut_ptr().cast::<u32>().add(off_as_blk) };
-            if *ap == 0 {
-                *ap = balloc(self.dev, bcache);
-                log::log_write(&mut bp);
-            }
+/// Return blockno of the n-th data block under the indirect block whose depth is level.
+/// Blocks on the path are allocated if does_alloc == true. Otherwise 0 is returned for a hole.
+fn indirect_block_for(
+    dev: u32,
+    mut block: u32,
+    level: usize,
+    mut n: usize,
+    does_alloc: bool,
+    bcache: &mut BufCache,
+) -> u32 {
+    let nindirect = superblock::get().nindirect();
+    // the number of data blocks under each entry of the current indirect block
+    let mut span = nindirect.pow((level - 1) as u32);
+    for _ in 0..level {
+        let mut bp = bcache.get(dev, block);
+        bp.read();
 
-            let block = *ap;
-            bcache.release(bp);
-            return block;
+        let ap = unsafe { &mut *bp.data_mut().as_mut_ptr().cast::<u32>().add(n / span) };
+        if *ap == 0 {
+            if !does_alloc {
+                bcache.release(bp);
+                return 0;
+            }
+            *ap = balloc(dev, bcache);
+            log::log_write(&mut bp);
         }
 
-        panic!("addr_for: out of range");
+        block = *ap;
+        bcache.release(bp);
+        n %= span;
+        span /= nindirect;
     }
+    block
 }
 
 /// On-disk inode structure
@@ -129,11 +193,11 @@ impl Inode {
 #[repr(C)]
 pub(crate) struct DInode {
     typ: InodeType,
-    major: u16,                // major device number (T_DEV only)
-    minor: u16,                // minor device number (T_DEV only)
-    nlink: u16,                // number of links to inode in file system
-    size: u32,                 // size of file (bytes)
-    addrs: [u32; NDIRECT + 1], // data block addresses
+    major: u16,           // major device number (T_DEV only)
+    minor: u16,           // minor device number (T_DEV only)
+    nlink: u16,           // number of links to inode in file system
+    size: u32,            // size of file (bytes)
+    addrs: [u32; NADDRS], // data block addresses
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -179,7 +243,9 @@ impl InodeCache {
 
     fn remove(&mut self, dev: u32, inum: u32) {
         let key = InodeCacheKey { dev, inum };
-        self.inodes.remove(&key);
+        if self.inodes.remove(&key).is_some() {
+            self.n -= 1;
+        }
     }
 }
 
@@ -192,7 +258,7 @@ fn inode_cache() -> &'static Mutex<InodeCache> {
 
 /// Return inode block corresponding to a passed inum.
 fn block_for_inode(inum: u32, sb: &SuperBlock) -> u32 {
-    inum / (IPB as u32) + sb.inode_start
+    inum / (sb.inodes_per_block() as u32) + sb.inode_start
 }
 
 /// Return inode pointer in the block.
@@ -200,7 +266,8 @@ fn block_for_inode(inum: u32, sb: &SuperBlock) -> u32 {
 fn ref_to_inode(inum: u32, bp: &mut BufCacheHandler) -> &mut DInode {
     let data = bp.data_mut().as_mut_ptr();
     let dip = data.cast::<DInode>();
-    unsafe { &mut *dip.add((inum as usize) % IPB) }
+    let ipb = superblock::get().inodes_per_block();
+    unsafe { &mut *dip.add((inum as usize) % ipb) }
 }
 
 /// Allocate an inode on device dev.
@@ -343,6 +410,28 @@ pub(crate) fn ilock(ip: &Arc<RwLock<Inode>>) -> RwLockWriteGuard<'_, Inode> {
     lk
 }
 
+/// Wait until no append to the inode of ip is in progress, and start one.
+/// An append is written in several transactions, and the appends to an inode
+/// are serialized until end_append so that the data of each one is contiguous.
+/// Must not be called inside a transaction.
+pub(crate) fn begin_append(ip: &Arc<RwLock<Inode>>) {
+    // xv6 would sleep here, but spin as begin_op does.
+    loop {
+        let mut inode = ip.write();
+        if !inode.is_appending {
+            inode.is_appending = true;
+            break;
+        }
+    }
+}
+
+/// Finish the append started by begin_append.
+pub(crate) fn end_append(ip: &Arc<RwLock<Inode>>) {
+    let mut inode = ip.write();
+    assert!(inode.is_appending, "end_append: not appending");
+    inode.is_appending = false;
+}
+
 /// Unlock the given inode.
 pub(crate) fn iunlock(_inode: RwLockWriteGuard<'_, Inode>) {
     // just consume RwLockWriteGuard
@@ -355,59 +444,240 @@ pub(crate) fn iunlock(_inode: RwLockWriteGuard<'_, Inode>) {
 /// to it, free the inode (and its content) on disk.
 /// All calls to iput() must be inside a transaction in
 /// case it has to free the inode.
+/// The inode is freed by free_orphans after the transaction is committed
+/// since the blocks of a large inode don't fit in it.
 pub(crate) fn iput(ip: Arc<RwLock<Inode>>) {
-    let mut lk = ip.write();
-    // Someone might have Arc<RwLock<Inode>>, but no one can see Inode for a while.
+    {
+        let lk = ip.write();
+        // Someone might have Arc<RwLock<Inode>>, but no one can see Inode for a while.
 
-    let inode = &mut *lk;
+        if !lk.valid || lk.nlink > 0 {
+            return;
+        }
 
-    // FIXME: Is it possible that Someone having Arc<RwLock<Inode>> get into trouble?
-    if inode.valid && inode.nlink == 0 {
-        let mut icache = inode_cache().lock();
+        let _icache = inode_cache().lock();
 
-        itrunc(inode);
-        inode.typ = InodeType::Empty;
-        iupdate(inode);
-        inode.valid = false;
+        // References are held by the cache and ip if this is the last one.
+        // iget can't make a new reference while icache is locked.
+        // An open file or a cwd keeps the inode alive until it is released.
+        if Arc::strong_count(&ip) > 2 {
+            return;
+        }
+    }
+
+    // No directory entry refers to the inode, so no one can get it until it is freed.
+    ORPHANS.lock().push(ip);
+}
 
-        icache.remove(inode.dev, inode.inum);
+/// Inodes which have no links and no references, to be freed by free_orphans.
+/// A crash before they are freed leaks them and their blocks.
+static ORPHANS: Mutex<Vec<Arc<RwLock<Inode>>>> = Mutex::new(Vec::new());
+
+/// Held by free_orphans so that its own calls of end_op don't free the orphans recursively.
+static ORPHANS_FREEING: Mutex<()> = Mutex::new(());
+
+/// Free the orphans put by iput, each in transactions of its own.
+/// Called by end_op when no operation is outstanding. If the orphans are
+/// being freed by another cpu, they are left to it or to a later call.
+pub(crate) fn free_orphans() {
+    let _freeing = match ORPHANS_FREEING.try_lock() {
+        Some(freeing) => freeing,
+        None => return,
+    };
+
+    loop {
+        let ip = match ORPHANS.lock().pop() {
+            Some(ip) => ip,
+            None => break,
+        };
+
+        loop {
+            log::begin_op();
+            let mut inode = ilock(&ip);
+            let is_done = itrunc_step(&mut inode, 0);
+            if is_done {
+                let mut icache = inode_cache().lock();
+                inode.typ = InodeType::Empty;
+                iupdate(&inode);
+                inode.valid = false;
+
+                icache.remove(inode.dev, inode.inum);
+            }
+            iunlock(inode);
+            log::end_op();
+
+            if is_done {
+                break;
+            }
+        }
     }
 }
 
-// Truncate inode (discard contents).
-// Only called when the inode has no links
-// to it (no directory entries referring to it)
-// and has no in-memory reference to it (is
-// not an open file or current directory).
-fn itrunc(inode: &mut Inode) {
-    for i in 0..NDIRECT {
+/// Truncate the inode of ip to size bytes, freeing the blocks past it.
+/// Growing the inode leaves a hole. The whole contents is discarded by iput
+/// when the inode has no links to it (no directory entries referring to it)
+/// and has no in-memory reference to it (is not an open file or current directory).
+/// The blocks are freed in several transactions so that a large file doesn't
+/// overflow the log, as File::write does for writes.
+/// Must not be called inside a transaction, and the caller must not hold the lock.
+pub(crate) fn itrunc(ip: &Arc<RwLock<Inode>>, size: u32) {
+    loop {
+        log::begin_op();
+        let mut inode = ilock(ip);
+        let is_done = itrunc_step(&mut inode, size);
+        iunlock(inode);
+        log::end_op();
+
+        if is_done {
+            break;
+        }
+    }
+}
+
+/// The max number of blocks itrunc_step frees in one transaction.
+/// Besides a bitmap block for each of them, a step writes the i-node,
+/// the indirect blocks on the paths to both ends of the freed range
+/// and the last data block.
+const TRUNC_STEP_BLOCKS: usize = MAX_OP_BLOCKS - 2 - 2 * NINDIRECT_LEVELS;
+
+/// Free at most TRUNC_STEP_BLOCKS blocks past size bytes of inode, from the last one.
+/// Return true if inode is truncated to size. Otherwise the size is left as it is
+/// (the freed blocks read as a hole) and the caller must call again in a new transaction.
+/// Caller must hold the lock.
+fn itrunc_step(inode: &mut Inode, size: u32) -> bool {
+    let sb = superblock::get();
+    let bsize = sb.block_size();
+    // the first block to free
+    let first = (size as usize + bsize - 1) / bsize;
+    let mut budget = TRUNC_STEP_BLOCKS;
+
+    // The trees of indirect blocks are freed from the deepest one.
+    let mut bases = [0; NINDIRECT_LEVELS];
+    let mut base = NDIRECT;
+    for (level, b) in (1..=NINDIRECT_LEVELS).zip(bases.iter_mut()) {
+        *b = base;
+        base += sb.nindirect().pow(level as u32);
+    }
+    for level in (1..=NINDIRECT_LEVELS).rev() {
+        let idx = NDIRECT + level - 1;
+        let base = bases[level - 1];
+        let nblocks = sb.nindirect().pow(level as u32);
+        if inode.addrs[idx] > 0 && first < base + nblocks && budget > 0 {
+            let start = first.saturating_sub(base);
+            if free_indirect(inode.dev, inode.addrs[idx], level, start, &mut budget) {
+                inode.addrs[idx] = 0;
+            }
+        }
+    }
+
+    for i in (min(first, NDIRECT)..NDIRECT).rev() {
+        if budget == 0 {
+            break;
+        }
         if inode.addrs[i] > 0 {
             bfree(inode.dev, inode.addrs[i]);
             inode.addrs[i] = 0;
+            budget -= 1;
         }
     }
 
-    if inode.addrs[NDIRECT] > 0 {
-        // there are indirect inodes too.
+    if budget == 0 {
+        iupdate(inode);
+        return false;
+    }
+
+    // Clear the rest of the last block.
+    // Bytes past the end must read as zeros when the inode grows again.
+    if size < inode.size && size as usize % bsize != 0 {
         let mut bcache = buf::buf_cache();
-        let mut bp = bcache.get(inode.dev, inode.addrs[NDIRECT]);
+        let block = inode.block_for(size, false, &mut bcache);
+        if block != 0 {
+            let mut bp = bcache.get(inode.dev, block);
+            bp.read();
+            let start = size as usize % bsize;
+            unsafe {
+                util::memset(
+                    VirtAddr(bp.data().as_ptr().add(start) as u32),
+                    0,
+                    bsize - start,
+                )
+            };
+            log::log_write(&mut bp);
+            bcache.release(bp);
+        }
+    }
+
+    inode.size = size;
+    iupdate(inode);
+    true
+}
+
+/// Free the data blocks from the first-th one under the indirect block whose depth is level,
+/// from the last one and at most *budget blocks including the indirect blocks left empty.
+/// Return true if the indirect block itself is freed.
+fn free_indirect(dev: u32, block: u32, level: usize, first: usize, budget: &mut usize) -> bool {
+    let nindirect = superblock::get().nindirect();
+    // the number of data blocks under each entry
+    let span = nindirect.pow((level - 1) as u32);
+
+    // Copy entries because bfree locks the buffer cache.
+    let mut entries = {
+        let mut bcache = buf::buf_cache();
+        let mut bp = bcache.get(dev, block);
         bp.read();
 
         let a = bp.data().as_ptr().cast::<u32>();
-        for i in 0..NINDIRECT {
-            let inum = unsafe { *a.add(i) };
-            if inum > 0 {
-                bfree(inode.dev, inum);
-            }
+        let entries: Vec<u32> = (0..nindirect).map(|i| unsafe { *a.add(i) }).collect();
+        bcache.release(bp);
+        entries
+    };
+
+    let mut is_changed = false;
+    for (i, entry) in entries.iter_mut().enumerate().rev() {
+        // the entries before are all kept
+        if *budget == 0 || (i + 1) * span <= first {
+            break;
         }
+        if *entry == 0 {
+            continue;
+        }
+        let is_freed = if level > 1 {
+            free_indirect(
+                dev,
+                *entry,
+                level - 1,
+                first.saturating_sub(i * span),
+                budget,
+            )
+        } else {
+            bfree(dev, *entry);
+            *budget -= 1;
+            true
+        };
+        if is_freed {
+            *entry = 0;
+            is_changed = true;
+        }
+    }
 
-        bcache.release(bp);
-        bfree(inode.dev, inode.addrs[NDIRECT]);
-        inode.addrs[NDIRECT] = 0;
+    if first == 0 && *budget > 0 && entries.iter().all(|&entry| entry == 0) {
+        bfree(dev, block);
+        *budget -= 1;
+        return true;
     }
 
-    inode.size = 0;
-    iupdate(inode);
+    if is_changed {
+        let mut bcache = buf::buf_cache();
+        let mut bp = bcache.get(dev, block);
+        bp.read();
+        let a = bp.data_mut().as_mut_ptr().cast::<u32>();
+        for (i, &entry) in entries.iter().enumerate() {
+            unsafe { *a.add(i) = entry };
+        }
+        log::log_write(&mut bp);
+        bcache.release(bp);
+    }
+    false
 }
 
 /// Common idiom: unlock, then put.
@@ -429,33 +699,37 @@ pub(crate) fn readi(inode: &mut Inode, mut dst: *mut u8, mut off: u32, mut n: u3
         return res.map(|cnt| cnt as u32);
     }
 
-    if off > inode.size || off + n < off {
-        panic!("readi: illegal offset");
-    }
-    if off + n > inode.size {
-        n = inode.size - off;
+    // offsets past the end are allowed since lseek may move there.
+    if off >= inode.size {
+        return Some(0);
     }
+    n = min(n, inode.size - off);
 
     #[cfg(feature = "debug")]
     println!("[readi] inum: {}, off: {}, n: {}", inode.inum, off, n);
 
+    let bsize = superblock::get().block_size() as u32;
     let mut bcache = buf::buf_cache();
     let mut tot = 0;
     while tot < n {
-        let block = inode.block_for(off, &mut bcache);
-        let mut bp = bcache.get(inode.dev, block);
-        bp.read();
-
-        let m = min(n - tot, (BLK_SIZE as u32) - off % (BLK_SIZE as u32));
-        unsafe {
-            util::memmove(
-                VirtAddr(dst as u32),
-                VirtAddr(bp.data().as_ptr().add((off as usize) % BLK_SIZE) as u32),
-                m as usize,
-            )
-        };
+        let block = inode.block_for(off, false, &mut bcache);
+        let m = min(n - tot, bsize - off % bsize);
+        if block == 0 {
+            // a hole reads as zeros
+            unsafe { util::memset(VirtAddr(dst as u32), 0, m as usize) };
+        } else {
+            let mut bp = bcache.get(inode.dev, block);
+            bp.read();
+            unsafe {
+                util::memmove(
+                    VirtAddr(dst as u32),
+                    VirtAddr(bp.data().as_ptr().add((off % bsize) as usize) as u32),
+                    m as usize,
+                )
+            };
+            bcache.release(bp);
+        }
 
-        bcache.release(bp);
         tot += m;
         off += m;
         dst = unsafe { dst.add(m as usize) };
@@ -472,12 +746,15 @@ pub(crate) fn writei(inode: &mut Inode, mut src: *const u8, mut off: u32, n: u32
         return sw.write.call((inode, src, n as usize)) as u32;
     }
 
-    if off > inode.size || off + n < off {
+    // Writing past the end leaves a hole, whose blocks are not allocated.
+    if off + n < off {
         panic!("writei: illegal offset");
     }
-    if off + n > (MAX_FILE * BLK_SIZE) as u32 {
+    let sb = superblock::get();
+    if off + n > sb.max_file_size() {
         panic!("writei: too large offset");
     }
+    let bsize = sb.block_size() as u32;
 
     #[cfg(feature = "debug")]
     println!("[writei] inum: {}, off: {}, n: {}", inode.inum, off, n);
@@ -486,14 +763,14 @@ pub(crate) fn writei(inode: &mut Inode, mut src: *const u8, mut off: u32, n: u32
         let mut bcache = buf::buf_cache();
         let mut tot = 0;
         while tot < n {
-            let block = inode.block_for(off, &mut bcache);
+            let block = inode.block_for(off, true, &mut bcache);
             let mut bp = bcache.get(inode.dev, block);
             bp.read();
 
-            let m = min(n - tot, (BLK_SIZE as u32) - off % (BLK_SIZE as u32));
+            let m = min(n - tot, bsize - off % bsize);
             unsafe {
                 util::memmove(
-                    VirtAddr(bp.data().as_ptr().add(off as usize % BLK_SIZE) as u32),
+                    VirtAddr(bp.data().as_ptr().add((off % bsize) as usize) as u32),
                     VirtAddr(src as u32),
                     m as usize,
                 );
@@ -542,19 +819,20 @@ pub(crate) fn stati(inode: &mut Inode) -> Stat {
 
 /// Calculate a bitmap brock appropriate for blockno
 fn block_for_bitmap(blockno: u32, sb: &SuperBlock) -> u32 {
-    blockno / (BPB as u32) + sb.bmap_start
+    blockno / (sb.bits_per_block() as u32) + sb.bmap_start
 }
 
 /// Allocate a zeroed disk block.
 fn balloc(dev: u32, bcache: &mut BufCache) -> u32 {
     let sb = superblock::get();
+    let bpb = sb.bits_per_block();
 
-    for blockno in 0..sb.size {
+    for blockno in (0..sb.size).step_by(bpb) {
         let mut bp = bcache.get(dev, block_for_bitmap(blockno, sb));
         bp.read();
 
         let mut bi = 0;
-        while bi < BPB && blockno + (bi as u32) < sb.size {
+        while bi < bpb && blockno + (bi as u32) < sb.size {
             let m = 1 << (bi % 8);
             // is block free?
             if bp.data()[bi / 8] & m == 0 {
@@ -577,8 +855,10 @@ fn balloc(dev: u32, bcache: &mut BufCache) -> u32 {
 
 /// Zero a block
 fn bzero(dev: u32, blockno: u32, bcache: &mut BufCache) {
-    let bp = bcache.get(dev, blockno);
-    unsafe { util::memset(VirtAddr(bp.data().as_ptr() as u32), 0, BLK_SIZE) };
+    let mut bp = bcache.get(dev, blockno);
+    let len = bp.data().len();
+    unsafe { util::memset(VirtAddr(bp.data().as_ptr() as u32), 0, len) };
+    log::log_write(&mut bp);
     bcache.release(bp);
 }
 
@@ -590,7 +870,7 @@ fn bfree(dev: u32, blockno: u32) {
     let mut bp = bcache.get(dev, block_for_bitmap(blockno, sb));
     bp.read();
 
-    let bi = (blockno % (BPB as u32)) as usize;
+    let bi = (blockno % (sb.bits_per_block() as u32)) as usize;
     let m = 1 << (bi % 8);
     if bp.data()[bi / 8] & m == 0 {
         panic!("bfree: freeing free block");
@@ -605,58 +885,142 @@ fn bfree(dev: u32, blockno: u32) {
 // Dir
 // ---------------------------------------------------------------------------------
 
-// FIXME: the same definition is in user/stat.h
+// FIXME: the same definition is in inc/fs.h
+/// On-disk header of a directory entry.
+/// The name follows it without '\0', and the next entry starts rec_len bytes after it.
+/// Entries never cross a block, so rec_len of the last entry in a block reaches the end of it.
+/// A removed entry is merged into the previous entry in its block,
+/// or its inum is set to 0 if it is the first one.
+/// file_type is the type of the inode as in ext2, so that listing a directory
+/// doesn't read the inodes of the entries.
 #[repr(C)]
+struct DirEntHeader {
+    inum: u32,
+    rec_len: u16,
+    name_len: u8,
+    file_type: u8,
+}
+
+const DIR_ENT_HEADER_SIZE: u32 = mem::size_of::<DirEntHeader>() as u32;
+
+/// Size of a directory entry whose name is name_len bytes (aligned to 4 bytes).
+fn dir_ent_size(name_len: usize) -> u32 {
+    ((DIR_ENT_HEADER_SIZE as usize + name_len + 3) & !3) as u32
+}
+
+/// Return the type of the inode recorded in a directory entry.
+/// Empty means that it is unknown.
+fn from_file_type(file_type: u8) -> InodeType {
+    match file_type {
+        t if t == InodeType::Dir as u8 => InodeType::Dir,
+        t if t == InodeType::File as u8 => InodeType::File,
+        t if t == InodeType::Dev as u8 => InodeType::Dev,
+        t if t == InodeType::Symlink as u8 => InodeType::Symlink,
+        _ => InodeType::Empty,
+    }
+}
+
+/// in-memory copy of a directory entry
 pub(crate) struct DirEnt {
     inum: u32,
-    name: [u8; DIR_SIZ],
+    rec_len: u16,
+    name_len: u8,
+    file_type: u8,
+    name: [u8; DIR_SIZ + 1], // terminated with '\0'
 }
 
 impl DirEnt {
-    pub(crate) fn empty() -> DirEnt {
-        DirEnt {
+    /// Read the directory entry at off.
+    pub(crate) fn read(dir: &mut Inode, off: u32) -> DirEnt {
+        let mut header = DirEntHeader {
             inum: 0,
-            name: [0; DIR_SIZ],
+            rec_len: 0,
+            name_len: 0,
+            file_type: 0,
+        };
+        let header_p = &mut header as *mut _ as *mut u8;
+        if readi(dir, header_p, off, DIR_ENT_HEADER_SIZE) != Some(DIR_ENT_HEADER_SIZE) {
+            panic!("DirEnt::read: failed to readi");
         }
-    }
 
-    fn set_name(&mut self, new_name: *const u8) {
-        let new_len = util::strnlen(new_name, DIR_SIZ);
-        if new_len > DIR_SIZ {
-            panic!("DirEnt::set_name: too long name");
+        let name_len = header.name_len as usize;
+        if name_len > DIR_SIZ
+            || (header.rec_len as u32) < dir_ent_size(name_len)
+            || header.rec_len % 4 != 0
+        {
+            panic!("DirEnt::read: broken entry at {}", off);
         }
 
-        let mut dst = self.name.as_mut_ptr();
-        let mut src = new_name;
-        for _ in 0..DIR_SIZ {
-            unsafe {
-                *dst = *src;
-                dst = dst.add(1);
-                src = src.add(1);
-            }
+        let mut ent = DirEnt {
+            inum: header.inum,
+            rec_len: header.rec_len,
+            name_len: header.name_len,
+            file_type: header.file_type,
+            name: [0; DIR_SIZ + 1],
+        };
+        let name_p = ent.name.as_mut_ptr();
+        if readi(dir, name_p, off + DIR_ENT_HEADER_SIZE, name_len as u32) != Some(name_len as u32) {
+            panic!("DirEnt::read: failed to readi");
         }
+        ent
+    }
+
+    pub(crate) fn get_inum(&self) -> u32 {
+        self.inum
     }
 
-    pub(crate) fn get_name(&self) -> *const u8 {
-        self.name.as_ptr()
+    pub(crate) fn name(&self) -> &[u8] {
+        &self.name[..(self.name_len as usize)]
     }
 
-    fn as_u8_ptr(&self) -> *const u8 {
-        (self as *const DirEnt).cast::<u8>()
+    /// Bytes of this entry which are in use. The rest of rec_len is free.
+    fn used_size(&self) -> u32 {
+        if self.inum == 0 {
+            0
+        } else {
+            dir_ent_size(self.name_len as usize)
+        }
     }
+}
 
-    fn as_u8_mut_ptr(&mut self) -> *mut u8 {
-        (self as *mut DirEnt).cast::<u8>()
+/// Write the header of a directory entry at off.
+fn write_dir_ent_header(dir: &mut Inode, off: u32, header: &DirEntHeader) {
+    let header_p = header as *const _ as *const u8;
+    if writei(dir, header_p, off, DIR_ENT_HEADER_SIZE) != DIR_ENT_HEADER_SIZE {
+        panic!("write_dir_ent_header: failed to writei");
     }
 }
 
+/// Write a directory entry (inum, typ, name) whose record is rec_len bytes at off.
+fn write_dir_ent(dir: &mut Inode, off: u32, inum: u32, typ: InodeType, rec_len: u32, name: &[u8]) {
+    let mut buf = [0 as u8; DIR_ENT_HEADER_SIZE as usize + DIR_SIZ + 1];
+    let header = DirEntHeader {
+        inum,
+        rec_len: rec_len as u16,
+        name_len: name.len() as u8,
+        file_type: typ as u8,
+    };
+    unsafe { ptr::write_unaligned(buf.as_mut_ptr().cast::<DirEntHeader>(), header) };
+    buf[(DIR_ENT_HEADER_SIZE as usize)..(DIR_ENT_HEADER_SIZE as usize + name.len())]
+        .copy_from_slice(name);
+
+    let size = dir_ent_size(name.len());
+    if writei(dir, buf.as_ptr(), off, size) != size {
+        panic!("write_dir_ent: failed to writei");
+    }
+}
+
+/// Return name as a slice.
+/// name must be at most DIR_SIZ bytes, which skip_elem guarantees.
+fn name_slice<'a>(name: *const u8) -> &'a [u8] {
+    let len = util::strnlen(name, DIR_SIZ + 1);
+    assert!(len <= DIR_SIZ, "name_slice: too long name");
+    unsafe { core::slice::from_raw_parts(name, len) }
+}
+
 /// This is just for debug.
 fn print_file_name(label: &str, p: *const u8) {
-    let mut buf = [0 as u8; DIR_SIZ];
-    for i in 0..(util::strnlen(p, DIR_SIZ)) {
-        buf[i] = unsafe { *p.add(i) };
-    }
-    let sli = core::str::from_utf8(&buf).unwrap();
+    let sli = core::str::from_utf8(name_slice(p)).unwrap();
     println!("{}: {}", label, sli);
 }
 
@@ -669,8 +1033,6 @@ fn dir_lookup(
         panic!("dir_lookup: inode is not dir");
     }
 
-    let dir_ent_size = mem::size_of::<DirEnt>() as u32;
-    let mut ent = DirEnt::empty();
     let mut off = 0;
 
     #[cfg(feature = "debug")]
@@ -680,10 +1042,7 @@ fn dir_lookup(
     );
 
     while off < dir.size {
-        let ptr = ent.as_u8_mut_ptr();
-        if readi(dir, ptr, off, dir_ent_size) != Some(dir_ent_size) {
-            panic!("dir_lookup: failed to readi");
-        }
+        let ent = DirEnt::read(dir, off);
 
         #[cfg(feature = "debug")]
         print!("[dir_lookup] ent.inum: {}, ", ent.inum);
@@ -700,7 +1059,7 @@ fn dir_lookup(
             }
         }
 
-        off += dir_ent_size;
+        off += ent.rec_len as u32;
     }
 
     None
@@ -714,8 +1073,8 @@ pub(crate) fn dir_lookup_with_name(
     #[cfg(feature = "debug")]
     print_file_name("dir_lookup for name", name);
 
-    let cond: Box<dyn Fn(&DirEnt) -> bool> =
-        Box::new(move |ent| util::strncmp(name, ent.name.as_ptr(), DIR_SIZ) == 0);
+    let name = name_slice(name).to_vec();
+    let cond: Box<dyn Fn(&DirEnt) -> bool> = Box::new(move |ent| ent.name() == &name[..]);
     dir_lookup(dir, p_off, Box::new(cond))
 }
 
@@ -732,62 +1091,187 @@ pub(crate) fn dir_lookup_with_inum(
 }
 
 /// Write a new directory entry (name, inum) into the directory dp.
+/// typ is the type of the inode.
 /// Return true if successful. Return false if it already exists.
-pub(crate) fn dir_link(dir: &mut Inode, name: *const u8, inum: u32) -> bool {
+pub(crate) fn dir_link(dir: &mut Inode, name: *const u8, inum: u32, typ: InodeType) -> bool {
     // check that name is not present
     if dir_lookup_with_name(dir, name, null_mut()).is_some() {
         return false;
     }
 
-    // look for an empty dirent
-    let dir_ent_size = mem::size_of::<DirEnt>() as u32;
-    let mut ent = DirEnt::empty();
-    let mut off = 0;
+    let name = name_slice(name);
+    let need = dir_ent_size(name.len());
 
+    // look for an entry with enough free space
+    let mut off = 0;
     while off < dir.size {
-        let ptr = ent.as_u8_mut_ptr();
-        if readi(dir, ptr, off, dir_ent_size) != Some(dir_ent_size) {
-            panic!("dir_link: failed to readi");
-        }
-        if ent.inum == 0 {
-            break;
+        let ent = DirEnt::read(dir, off);
+        let used = ent.used_size();
+        let rec_len = ent.rec_len as u32;
+        if rec_len - used >= need {
+            if used == 0 {
+                write_dir_ent(dir, off, inum, typ, rec_len, name);
+            } else {
+                // split the entry
+                let header = DirEntHeader {
+                    inum: ent.inum,
+                    rec_len: used as u16,
+                    name_len: ent.name_len,
+                    file_type: ent.file_type,
+                };
+                write_dir_ent_header(dir, off, &header);
+                write_dir_ent(dir, off + used, inum, typ, rec_len - used, name);
+            }
+            return true;
         }
-        off += dir_ent_size;
+        off += rec_len;
     }
 
-    ent.set_name(name);
-    ent.inum = inum;
-    let ptr = ent.as_u8_ptr();
-    if writei(dir, ptr, off, dir_ent_size) != dir_ent_size {
-        panic!("dir_link: failed to writei");
-    }
+    // add a new block which has only this entry
+    let bsize = superblock::get().block_size() as u32;
+    write_dir_ent(dir, off, inum, typ, bsize, name);
+    dir.size = off + bsize;
+    iupdate(dir);
 
     true
 }
 
-pub(crate) fn is_dir_empty(dp: &mut Inode) -> bool {
-    assert!(dp.typ == InodeType::Dir);
+/// Change the inode of the directory entry at off to inum whose type is typ.
+/// The name is not changed.
+pub(crate) fn dir_set_inum(dir: &mut Inode, off: u32, inum: u32, typ: InodeType) {
+    let ent = DirEnt::read(dir, off);
+    let header = DirEntHeader {
+        inum,
+        rec_len: ent.rec_len,
+        name_len: ent.name_len,
+        file_type: typ as u8,
+    };
+    write_dir_ent_header(dir, off, &header);
+}
 
-    let dir_ent_size = mem::size_of::<DirEnt>() as u32;
-    let mut off = 2 * dir_ent_size;
+/// Remove the directory entry at off.
+pub(crate) fn dir_unlink(dir: &mut Inode, off: u32) {
+    let bsize = superblock::get().block_size() as u32;
+    let block_start = off - off % bsize;
+    let ent = DirEnt::read(dir, off);
 
-    while off < dp.size {
-        let mut ent = DirEnt::empty();
-        let ent_p = &mut ent as *mut _ as *mut u8;
-        let n = readi(dp, ent_p, off, dir_ent_size);
+    if off == block_start {
+        let header = DirEntHeader {
+            inum: 0,
+            rec_len: ent.rec_len,
+            name_len: 0,
+            file_type: 0,
+        };
+        write_dir_ent_header(dir, off, &header);
+        return;
+    }
 
-        if n != Some(dir_ent_size) {
-            panic!("is_dir_empty: failed to readi");
+    // merge the entry into the previous one
+    let mut prev_off = block_start;
+    loop {
+        let prev = DirEnt::read(dir, prev_off);
+        let next_off = prev_off + prev.rec_len as u32;
+        if next_off == off {
+            let header = DirEntHeader {
+                inum: prev.inum,
+                rec_len: prev.rec_len + ent.rec_len,
+                name_len: prev.name_len,
+                file_type: prev.file_type,
+            };
+            write_dir_ent_header(dir, prev_off, &header);
+            return;
         }
-        if ent.inum != 0 {
-            return false;
+        if next_off > off {
+            panic!("dir_unlink: no entry at {}", off);
         }
+        prev_off = next_off;
+    }
+}
+
+/// Return true if dp has no entries other than "." and "..".
+pub(crate) fn is_dir_empty(dp: &mut Inode) -> bool {
+    assert!(dp.typ == InodeType::Dir);
 
-        off += dir_ent_size;
+    let mut off = 0;
+    while off < dp.size {
+        let ent = DirEnt::read(dp, off);
+        if ent.inum != 0 && ent.name() != b"." && ent.name() != b".." {
+            return false;
+        }
+        off += ent.rec_len as u32;
     }
     true
 }
 
+// FIXME: the same definition is in user/stat.h
+/// Header of a record returned by getdents, which doesn't depend on the on-disk format.
+/// The name follows it with '\0', and the next record starts rec_len bytes after it.
+#[repr(C)]
+struct DirRecordHeader {
+    inum: u32,
+    rec_len: u16,
+    typ: InodeType,
+}
+
+/// Size of a record whose name is name_len bytes (aligned to 4 bytes).
+fn dir_record_size(name_len: usize) -> usize {
+    (mem::size_of::<DirRecordHeader>() + name_len + 1 + 3) & !3
+}
+
+/// Fill buf with the records of the entries of dir from *off, and move *off past them.
+/// Return the total size of the records, which is 0 at the end of dir.
+/// The types are taken from the entries without reading the inodes.
+pub(crate) fn dir_read_records(
+    dir: &mut Inode,
+    off: &mut u32,
+    buf: &mut [u8],
+) -> Result<usize, SysError> {
+    assert!(dir.typ == InodeType::Dir);
+
+    // *off may have been moved by lseek. Walk from the start of its block
+    // to check that it is at an entry.
+    let bsize = superblock::get().block_size() as u32;
+    let mut p = *off - *off % bsize;
+    while p < *off && p < dir.size {
+        p += DirEnt::read(dir, p).rec_len as u32;
+    }
+    if p != *off && *off < dir.size {
+        return Err(SysError::InvalidArg);
+    }
+
+    let mut len = 0;
+    while *off < dir.size {
+        let ent = DirEnt::read(dir, *off);
+        if ent.inum != 0 {
+            let name = ent.name();
+            let rec_len = dir_record_size(name.len());
+            if len + rec_len > buf.len() {
+                if len == 0 {
+                    // buf is too small for even one record.
+                    return Err(SysError::InvalidArg);
+                }
+                break;
+            }
+
+            let header = DirRecordHeader {
+                inum: ent.inum,
+                rec_len: rec_len as u16,
+                typ: from_file_type(ent.file_type),
+            };
+            let rec = &mut buf[len..(len + rec_len)];
+            unsafe { ptr::write_unaligned(rec.as_mut_ptr().cast::<DirRecordHeader>(), header) };
+            let name_start = mem::size_of::<DirRecordHeader>();
+            rec[name_start..(name_start + name.len())].copy_from_slice(name);
+            for b in rec[(name_start + name.len())..].iter_mut() {
+                *b = 0;
+            }
+            len += rec_len;
+        }
+        *off += ent.rec_len as u32;
+    }
+    Ok(len)
+}
+
 // ---------------------------------------------------------------------------------
 // Path names
 // ---------------------------------------------------------------------------------
@@ -797,18 +1281,20 @@ pub(crate) fn is_dir_empty(dp: &mut Inode) -> bool {
 /// The returned path has no leading slashes,
 /// so the caller can check *path=='\0' to see if the name is the last one.
 /// If no name to remove, return 0.
+/// name must have room for DIR_SIZ + 1 bytes.
+/// Return SysError::NameTooLong if the element is longer than DIR_SIZ bytes.
 ///
 /// Examples:
 ///   skipelem("a/bb/c", name) = "bb/c", setting name = "a"
 ///   skipelem("///a//bb", name) = "bb", setting name = "a"
 ///   skipelem("a", name) = "", setting name = "a"
 ///   skipelem("", name) = skipelem("////", name) = 0
-unsafe fn skip_elem(mut path: *const u8, name: *mut u8) -> *const u8 {
+unsafe fn skip_elem(mut path: *const u8, name: *mut u8) -> Result<*const u8, SysError> {
     while *path == '/' as u8 {
         path = path.add(1);
     }
     if *path == 0 {
-        return null();
+        return Ok(null());
     }
 
     let s = path;
@@ -817,25 +1303,43 @@ unsafe fn skip_elem(mut path: *const u8, name: *mut u8) -> *const u8 {
     }
 
     let len = path.offset_from(s);
-    if len >= DIR_SIZ as isize {
-        core::intrinsics::copy(s, name, DIR_SIZ);
-    } else {
-        core::intrinsics::copy(s, name, len as usize);
-        *name.offset(len) = 0;
+    if len > DIR_SIZ as isize {
+        return Err(SysError::NameTooLong);
     }
+    core::intrinsics::copy(s, name, len as usize);
+    *name.offset(len) = 0;
 
     while *path == '/' as u8 {
         path = path.add(1);
     }
-    path
+    Ok(path)
+}
+
+/// Return the target path of a symbolic link without '\0'.
+pub(crate) fn read_link(inode: &mut Inode) -> Vec<u8> {
+    let size = inode.size;
+    let mut target = vec![0; size as usize];
+    let n = readi(inode, target.as_mut_ptr(), 0, size).unwrap_or(0);
+    target.truncate(n as usize);
+    target
 }
 
 /// Look up and return the inode for a path name.
 /// If does_want_parent == true, return the inode for the parent and copy the final
-/// path element into name, which must have room for DIRSIZ bytes.
+/// path element into name, which must have room for DIR_SIZ + 1 bytes.
+/// Symbolic links are followed, except for the final path element
+/// if does_follow_last == false (always the case when does_want_parent == true).
 /// Must be called inside a transaction since it calls iput().
-fn namex(mut path: *const u8, does_want_parent: bool, name: *mut u8) -> Option<Arc<RwLock<Inode>>> {
+fn namex(
+    mut path: *const u8,
+    does_want_parent: bool,
+    does_follow_last: bool,
+    name: *mut u8,
+) -> Result<Arc<RwLock<Inode>>, SysError> {
     let mut ip: Arc<RwLock<Inode>>;
+    // holds the rest of the path after a symbolic link is expanded
+    let mut expanded: Vec<u8>;
+    let mut hops = 0;
 
     unsafe {
         if *path == '/' as u8 {
@@ -846,7 +1350,13 @@ fn namex(mut path: *const u8, does_want_parent: bool, name: *mut u8) -> Option<A
         }
 
         loop {
-            path = skip_elem(path, name);
+            path = match skip_elem(path, name) {
+                Err(err) => {
+                    iput(ip);
+                    return Err(err);
+                }
+                Ok(path) => path,
+            };
             if path.is_null() {
                 break;
             }
@@ -856,43 +1366,90 @@ fn namex(mut path: *const u8, does_want_parent: bool, name: *mut u8) -> Option<A
             if !inode.is_dir() {
                 iunlock(inode);
                 iput(ip);
-                return None;
+                return Err(SysError::NotDir);
             }
 
-            if does_want_parent && *path == '\0' as u8 {
+            let is_last = *path == '\0' as u8;
+            if does_want_parent && is_last {
                 // stop one level early
                 iunlock(inode);
-                return Some(ip);
+                return Ok(ip);
             }
 
-            match dir_lookup_with_name(&mut inode, name, null_mut()) {
+            let next = match dir_lookup_with_name(&mut inode, name, null_mut()) {
                 None => {
                     iunlock(inode);
                     iput(ip);
-                    return None;
+                    return Err(SysError::NoEnt);
                 }
-                Some(next) => {
-                    iunlock(inode);
-                    iput(ip);
-                    ip = next;
+                Some(next) => next,
+            };
+            iunlock(inode);
+
+            if !is_last || does_follow_last {
+                let mut next_inode = ilock(&next);
+                if next_inode.is_symlink() {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        iunlock(next_inode);
+                        iput(next);
+                        iput(ip);
+                        return Err(SysError::Loop);
+                    }
+
+                    // Replace the link with its target and continue from ip,
+                    // the directory containing the link, or from the root.
+                    let mut target = read_link(&mut next_inode);
+                    iunlock(next_inode);
+                    iput(next);
+                    if target.is_empty() {
+                        iput(ip);
+                        return Err(SysError::NoEnt);
+                    }
+                    let rest_len = util::strnlen(path, MAX_PATH_LEN + 1);
+                    if target.len() + 1 + rest_len > MAX_PATH_LEN {
+                        iput(ip);
+                        return Err(SysError::NameTooLong);
+                    }
+                    target.push('/' as u8);
+                    target.extend_from_slice(slice::from_raw_parts(path, rest_len));
+                    target.push(0);
+                    expanded = target;
+                    path = expanded.as_ptr();
+                    if *path == '/' as u8 {
+                        iput(ip);
+                        ip = iget(ROOT_DEV, ROOT_INUM);
+                    }
+                    continue;
                 }
+                iunlock(next_inode);
             }
+
+            iput(ip);
+            ip = next;
         }
 
         if does_want_parent {
             iput(ip);
-            return None;
+            return Err(SysError::NoEnt);
         }
     }
 
-    Some(ip)
+    Ok(ip)
+}
+
+/// Look up the inode for a path name, following a symbolic link at the end.
+pub(crate) fn namei(path: *const u8) -> Result<Arc<RwLock<Inode>>, SysError> {
+    let mut name = [0; DIR_SIZ + 1];
+    namex(path, false, true, name.as_mut_ptr())
 }
 
-pub(crate) fn namei(path: *const u8) -> Option<Arc<RwLock<Inode>>> {
-    let mut name = [0; DIR_SIZ];
-    namex(path, false, name.as_mut_ptr())
+/// Look up the inode for a path name. A symbolic link at the end is returned as it is.
+pub(crate) fn namei_nofollow(path: *const u8) -> Result<Arc<RwLock<Inode>>, SysError> {
+    let mut name = [0; DIR_SIZ + 1];
+    namex(path, false, false, name.as_mut_ptr())
 }
 
-pub(crate) fn nameiparent(path: *const u8, name: *mut u8) -> Option<Arc<RwLock<Inode>>> {
-    namex(path, true, name)
+pub(crate) fn nameiparent(path: *const u8, name: *mut u8) -> Result<Arc<RwLock<Inode>>, SysError> {
+    namex(path, true, false, name)
 }
@@ -0,0 +1,72 @@
+// QEMU firmware configuration (fw_cfg) interface.
+// It is used to pass boot options to the kernel (e.g. `-fw_cfg name=opt/xv6-rust/aslr,string=0`).
+//
+// ref. https://www.qemu.org/docs/master/specs/fw_cfg.html
+
+use crate::x86;
+
+const FW_CFG_PORT_SEL: u16 = 0x510;
+const FW_CFG_PORT_DATA: u16 = 0x511;
+
+// selector keys
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+
+const FW_CFG_MAX_FILE_PATH: usize = 56;
+
+fn select(key: u16) {
+    x86::outw(FW_CFG_PORT_SEL, key);
+}
+
+fn read(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        *b = x86::inb(FW_CFG_PORT_DATA);
+    }
+}
+
+fn read_be32() -> u32 {
+    let mut buf = [0; 4];
+    read(&mut buf);
+    u32::from_be_bytes(buf)
+}
+
+fn read_be16() -> u16 {
+    let mut buf = [0; 2];
+    read(&mut buf);
+    u16::from_be_bytes(buf)
+}
+
+fn exists() -> bool {
+    let mut sig = [0; 4];
+    select(FW_CFG_SIGNATURE);
+    read(&mut sig);
+    &sig == b"QEMU"
+}
+
+/// Read the fw_cfg file `name` into buf.
+/// Return the number of bytes read, or None if the file doesn't exist.
+pub(crate) fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    if !exists() {
+        return None;
+    }
+
+    select(FW_CFG_FILE_DIR);
+    let count = read_be32();
+    for _ in 0..count {
+        // struct FWCfgFile { u32 size; u16 select; u16 reserved; char name[56]; }
+        let size = read_be32() as usize;
+        let key = read_be16();
+        let _reserved = read_be16();
+        let mut file_name = [0; FW_CFG_MAX_FILE_PATH];
+        read(&mut file_name);
+
+        let len = file_name.iter().position(|&c| c == 0).unwrap_or(FW_CFG_MAX_FILE_PATH);
+        if &file_name[..len] == name.as_bytes() {
+            let n = core::cmp::min(size, buf.len());
+            select(key);
+            read(&mut buf[..n]);
+            return Some(n);
+        }
+    }
+    None
+}
@@ -6,7 +6,8 @@
 use crate::buf::consts::{BUF_FLAGS_DIRTY, BUF_FLAGS_VALID};
 use crate::buf::Buf;
 use crate::constants::*;
-use crate::x86;
+use crate::spinlock::Mutex;
+use crate::{superblock, x86};
 use consts::*;
 
 mod consts {
@@ -19,6 +20,8 @@ mod consts {
 
     pub(crate) const PRIMARY_COMMAND_BASE_REG: u16 = 0x1f0; // for sending command to drive or posting status from the drive
     pub(crate) const PRIMARY_CONTROL_BASE_REG: u16 = 0x3f6; // for drive control and post alternate status
+    pub(crate) const SECONDARY_COMMAND_BASE_REG: u16 = 0x170;
+    pub(crate) const SECONDARY_CONTROL_BASE_REG: u16 = 0x376;
 
     // register
     // `PRIMARY_BASE_REG + reg` is a target port
@@ -41,13 +44,27 @@ mod consts {
     pub(crate) const IDE_CMD_WRMUL: u8 = 0xc5;
 }
 
+/// Serializes accesses to the disks.
+static IDE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Return (command base register, control base register) of the channel
+/// which dev is attached to.
+/// Device 0 and 1 are on the primary channel, and 2 and 3 are on the secondary channel.
+fn channel(dev: u32) -> (u16, u16) {
+    if dev < 2 {
+        (PRIMARY_COMMAND_BASE_REG, PRIMARY_CONTROL_BASE_REG)
+    } else {
+        (SECONDARY_COMMAND_BASE_REG, SECONDARY_CONTROL_BASE_REG)
+    }
+}
+
 /// Wait until disk to be ready.
-fn ide_wait_ready(check_error: bool) -> bool {
+fn ide_wait_ready(base: u16, check_error: bool) -> bool {
     let mut r: u8;
 
     loop {
         // ref. 7.2.13 Status register in Spec
-        r = x86::inb(PRIMARY_COMMAND_BASE_REG + REG_STATUS);
+        r = x86::inb(base + REG_STATUS);
         if (r & (SR_BSY | SR_DRDY)) == SR_DRDY {
             break;
         }
@@ -60,7 +77,7 @@ fn ide_wait_ready(check_error: bool) -> bool {
 /// (With qemu, it means that we have an option like `-drive file=fs.img,index=1,media=disk,format=raw`)
 fn ide_probe_disk1() -> bool {
     // wait for Device 0 to be ready
-    if !ide_wait_ready(true) {
+    if !ide_wait_ready(PRIMARY_COMMAND_BASE_REG, true) {
         panic!("something wrong with ide");
     }
 
@@ -69,16 +86,7 @@ fn ide_probe_disk1() -> bool {
     x86::outb(PRIMARY_COMMAND_BASE_REG + REG_HDDEVSEL, 0xe0 | (1 << 4));
 
     // check whether Device 1 exists and get ready
-    let mut found: bool = false;
-    for _ in 0..1000 {
-        let r = x86::inb(PRIMARY_COMMAND_BASE_REG + REG_STATUS);
-        if r != 0 {
-            if r & (SR_BSY | SR_DWF | SR_ERR) == 0 {
-                found = true;
-                break;
-            }
-        }
-    }
+    let found = ide_wait_present(PRIMARY_COMMAND_BASE_REG);
 
     // switch back to Device 0
     x86::outb(PRIMARY_COMMAND_BASE_REG + REG_HDDEVSEL, 0xe0 | (0 << 4));
@@ -92,102 +100,90 @@ fn ide_probe_disk1() -> bool {
     found
 }
 
-fn ide_start(b: &Buf) {
-    if b.blockno >= (FS_SIZE as u32) {
-        panic!("ide_start: incorrect blockno");
+/// Check whether the selected device of the channel exists and gets ready.
+fn ide_wait_present(base: u16) -> bool {
+    for _ in 0..1000 {
+        let r = x86::inb(base + REG_STATUS);
+        // 0xff means that nothing is attached to the channel.
+        if r != 0 && r != 0xff {
+            if r & (SR_BSY | SR_DWF | SR_ERR) == 0 {
+                return true;
+            }
+        }
     }
+    false
+}
 
-    let sector_per_block = (BLK_SIZE / SECTOR_SIZE) as u32;
-    let sector = b.blockno * sector_per_block;
-    let read_cmd = if sector_per_block == 1 {
-        IDE_CMD_READ
-    } else {
-        IDE_CMD_RDMUL
-    };
-    let write_cmd = if sector_per_block == 1 {
-        IDE_CMD_WRITE
-    } else {
-        IDE_CMD_WRMUL
-    };
+/// Check whether the swap disk (the master of the secondary channel) exists.
+/// (With qemu, it means that we have an option like `-drive file=swap.img,index=2,media=disk,format=raw`)
+pub(crate) fn ide_probe_swap() -> bool {
+    let (base, _) = channel(SWAP_DEV);
+    x86::outb(base + REG_HDDEVSEL, 0xe0 | (((SWAP_DEV & 1) as u8) << 4));
+    let found = ide_wait_present(base);
 
-    if sector_per_block > 7 {
-        panic!("ide_start: illegal sector per block");
+    print!("Swap device presence: ");
+    if found {
+        println!("yes");
+    } else {
+        println!("no");
     }
+    found
+}
 
-    if !ide_wait_ready(true) {
-        panic!("ide_start: something bad occurred.")
+/// Select the device and set the sector address and count.
+fn ide_select(dev: u32, sector: u32, nsect: u8) {
+    // Only 28-bit LBA is encoded below.
+    if sector + (nsect as u32) > (1 << 28) {
+        panic!("ide_select: incorrect sector");
     }
 
+    let (base, ctl) = channel(dev);
+
     // This is Device Control Register? (7.2.6 in Spec).
     // Disables interrupt and perform polling when read and write
-    x86::outb(PRIMARY_CONTROL_BASE_REG, (1 << 1) | (1 << 3));
+    x86::outb(ctl, (1 << 1) | (1 << 3));
 
     // This register contains the number of sectors of data requested to be transferred
     // on a read or write operation between the host and the drive.
     // See 7.2 in Spec.
-    x86::outb(
-        PRIMARY_COMMAND_BASE_REG + REG_SECCOUNT0,
-        sector_per_block as u8,
-    ); // number of sectors
+    x86::outb(base + REG_SECCOUNT0, nsect); // number of sectors
 
     // This register contains the starting sector number for any disk data access
     // for the subsequent command.
     // As we set up in `ide_probe_disk1`, addressing is based on LBA not CHS.
     // See 7.2 in Spec.
-    x86::outb(PRIMARY_COMMAND_BASE_REG + REG_LBA0, (sector & 0xff) as u8);
-    x86::outb(
-        PRIMARY_COMMAND_BASE_REG + REG_LBA1,
-        ((sector >> 8) & 0xff) as u8,
-    );
+    x86::outb(base + REG_LBA0, (sector & 0xff) as u8);
+    x86::outb(base + REG_LBA1, ((sector >> 8) & 0xff) as u8);
+    x86::outb(base + REG_LBA2, ((sector >> 16) & 0xff) as u8);
     x86::outb(
-        PRIMARY_COMMAND_BASE_REG + REG_LBA2,
-        ((sector >> 16) & 0xff) as u8,
+        base + REG_HDDEVSEL,
+        0xe0 | (((dev & 1) as u8) << 4) | (((sector >> 24) & 0x0f) as u8),
     );
-    x86::outb(
-        PRIMARY_COMMAND_BASE_REG + REG_HDDEVSEL,
-        0xe0 | (((b.dev & 1) as u8) << 4) | (((sector >> 24) & 0x0f) as u8),
-    );
-
-    if b.flags & BUF_FLAGS_DIRTY != 0 {
-        // This register contains the command code being sent to the drive.
-        // Command execution begins immediately after this register is written.
-        //
-        // The detail of write protocol is in 10.2 of Spec
-        x86::outb(PRIMARY_COMMAND_BASE_REG + REG_COMMAND, write_cmd);
-        x86::outsl(
-            PRIMARY_COMMAND_BASE_REG + REG_DATA,
-            b.data.as_ptr().cast::<u32>(),
-            BLK_SIZE / 4,
-        );
-    } else {
-        // The detail of read protocol is in 10.1 of Spec
-        x86::outb(PRIMARY_COMMAND_BASE_REG + REG_COMMAND, read_cmd);
-    }
 }
 
 /// Sync buf with disk.
 /// If B_DIRTY is set, write buf to disk, clear B_DIRTY, set B_VALID.
 /// Else if B_VALID is not set, read buf from disk, set B_VALID.
+/// A block larger than a sector is transferred by one multi-sector command.
 pub(crate) fn ide_rw(b: &mut Buf) {
     if (b.flags & (BUF_FLAGS_VALID | BUF_FLAGS_DIRTY)) == BUF_FLAGS_VALID {
         panic!("ide_rw: nothing to do");
     }
+    // A block of the file system must be in its image.
+    if b.dev == ROOT_DEV && b.blockno >= superblock::get().size {
+        panic!("ide_rw: incorrect blockno");
+    }
 
-    // read or write
-    ide_start(b);
-
-    // wait by polling
-    if !ide_wait_ready(true) {
-        panic!("ide_intr: something bad occurred.");
+    let sector_per_block = b.size / SECTOR_SIZE;
+    if sector_per_block == 0 || sector_per_block > MAX_BLK_SIZE / SECTOR_SIZE {
+        panic!("ide_rw: illegal sector per block");
     }
+    let sector = b.blockno * (sector_per_block as u32);
 
-    // Read data if needed.
-    if b.flags & BUF_FLAGS_DIRTY == 0 {
-        x86::insl(
-            PRIMARY_COMMAND_BASE_REG + REG_DATA,
-            b.data.as_mut_ptr().cast::<u32>(),
-            BLK_SIZE / 4,
-        );
+    if b.flags & BUF_FLAGS_DIRTY != 0 {
+        ide_write_sectors(b.dev, sector, b.data.as_ptr(), sector_per_block);
+    } else {
+        ide_read_sectors(b.dev, sector, b.data.as_mut_ptr(), sector_per_block);
     }
 
     // Change flags as completed
@@ -195,6 +191,60 @@ pub(crate) fn ide_rw(b: &mut Buf) {
     b.flags &= !BUF_FLAGS_DIRTY;
 }
 
+/// Read nsect sectors from the sector of dev into dst.
+/// Swap and the super block use this directly, bypassing the buffer cache.
+pub(crate) fn ide_read_sectors(dev: u32, sector: u32, dst: *mut u8, nsect: usize) {
+    let _lock = IDE_LOCK.lock();
+    let (base, _) = channel(dev);
+    if !ide_wait_ready(base, true) {
+        panic!("ide_read_sectors: something bad occurred.")
+    }
+
+    ide_select(dev, sector, nsect as u8);
+    x86::outb(base + REG_COMMAND, IDE_CMD_READ);
+
+    // The drive is ready for each sector in turn (10.1 in Spec).
+    for i in 0..nsect {
+        if !ide_wait_ready(base, true) {
+            panic!("ide_read_sectors: something bad occurred.");
+        }
+        x86::insl(
+            base + REG_DATA,
+            unsafe { dst.add(i * SECTOR_SIZE) }.cast::<u32>(),
+            SECTOR_SIZE / 4,
+        );
+    }
+}
+
+/// Write nsect sectors from src to the sector of dev.
+/// Swap uses this directly, bypassing the buffer cache.
+pub(crate) fn ide_write_sectors(dev: u32, sector: u32, src: *const u8, nsect: usize) {
+    let _lock = IDE_LOCK.lock();
+    let (base, _) = channel(dev);
+    if !ide_wait_ready(base, true) {
+        panic!("ide_write_sectors: something bad occurred.")
+    }
+
+    ide_select(dev, sector, nsect as u8);
+    x86::outb(base + REG_COMMAND, IDE_CMD_WRITE);
+
+    // The drive requests each sector in turn (10.2 in Spec).
+    for i in 0..nsect {
+        if !ide_wait_ready(base, true) {
+            panic!("ide_write_sectors: something bad occurred.");
+        }
+        x86::outsl(
+            base + REG_DATA,
+            unsafe { src.add(i * SECTOR_SIZE) }.cast::<u32>(),
+            SECTOR_SIZE / 4,
+        );
+    }
+
+    if !ide_wait_ready(base, true) {
+        panic!("ide_write_sectors: something bad occurred.");
+    }
+}
+
 pub(crate) fn ide_init() {
     if !ide_probe_disk1() {
         panic!("Device 1 must be available");
@@ -2,7 +2,7 @@
 
 use crate::constants::*;
 use crate::pmap::{PhysAddr, VirtAddr};
-use crate::trap::consts::{IRQ_ERROR, IRQ_OFFSET, IRQ_SPURIOUS, IRQ_TIMER};
+use crate::trap::consts::{IRQ_ERROR, IRQ_OFFSET, IRQ_SPURIOUS, IRQ_TIMER, IRQ_WAKEUP};
 use crate::{kclock, mpconfig, pmap};
 use consts::*;
 
@@ -34,6 +34,7 @@ mod consts {
     pub(crate) const LVT_MASKED: i32 = 0x00010000; // Interrupt masked. Inhibits reception of the interrupt if set.
 
     // ref. Intel SDM Vol.3 10.6.1 Interrupt Command Register ICR
+    pub(crate) const ICR_FIXED: i32 = 0x00000000; // Delivery Mode: Fixed
     pub(crate) const ICR_INIT: i32 = 0x00000500; // Delivery Mode: INIT
     pub(crate) const ICR_STARTUP: i32 = 0x00000600; // Deliverymode: Start Up
     pub(crate) const ICR_DELIVS: i32 = 0x00001000; // Delivery Status: Send Pending if set, otherwise Idle.
@@ -42,6 +43,8 @@ mod consts {
     pub(crate) const ICR_BCAST: i32 = 0x00080000; // Destination: All Including Self
 
     pub(crate) const TDCR_X1: i32 = 0x0000000b; // divide counts by 1
+
+    pub(crate) const TIMER_INITIAL_COUNT: i32 = 10000000;
 }
 
 struct LocalAPIC(VirtAddr);
@@ -91,6 +94,39 @@ impl LocalAPIC {
         self.write(EOI, 0);
     }
 
+    /// Start the periodic timer.
+    ///
+    /// The timer repeatedly counts down at bus frequency
+    /// from lapic[TICR] and then issues an interrupt.
+    /// If we cared more about precise timekeeping,
+    /// TICR would be calibrated using an external time source.
+    ///
+    /// See Intel SDM Vol3 10.5.4 APIC Timer
+    fn timer_start(&self) {
+        self.write(TDCR, TDCR_X1);
+        self.write(
+            LVT_TIMER,
+            LVT_TIMER_PERIODIC | (IRQ_OFFSET + IRQ_TIMER) as i32,
+        );
+        self.write(TICR, TIMER_INITIAL_COUNT);
+    }
+
+    /// Stop the timer.
+    /// Writing 0 to the initial count register stops the count down,
+    /// and masking the LVT entry discards an interrupt which might be in flight.
+    fn timer_stop(&self) {
+        self.write(LVT_TIMER, LVT_MASKED | (IRQ_OFFSET + IRQ_TIMER) as i32);
+        self.write(TICR, 0);
+    }
+
+    /// Send a fixed interrupt with vector to the processor specified by apic_id.
+    /// See Intel SDM Vol.3 10.6 Issuing Interprocessor Interrupts
+    fn send_ipi(&self, apic_id: u8, vector: u8) {
+        self.write(ICRHI, (apic_id as i32) << 24);
+        self.write(ICRLO, ICR_FIXED | (vector as i32));
+        while self.read(ICRLO) & ICR_DELIVS != 0 {}
+    }
+
     /// Spin for a given number of microseconds.
     /// On real hardware would want to tune this dynamically.
     fn micro_delay(&self, _us: u32) {}
@@ -127,18 +163,9 @@ pub(crate) fn lapic_init() {
     // I'm not sure what spurious interrupt is, but it is something like unexpected interrupt?
     lapic.write(SVR, SVR_ENABLE | ((IRQ_OFFSET + IRQ_SPURIOUS) as i32));
 
-    // The timer repeatedly counts down at bus frequency
-    // from lapic[TICR] and then issues an interrupt.
-    // If we cared more about precise timekeeping,
-    // TICR would be calibrated using an external time source.
-    //
-    // See Intel SDM Vol3 10.5.4 APIC Timer
-    lapic.write(TDCR, TDCR_X1);
-    lapic.write(
-        LVT_TIMER,
-        LVT_TIMER_PERIODIC | (IRQ_OFFSET + IRQ_TIMER) as i32,
-    );
-    lapic.write(TICR, 10000000);
+    // Start the periodic timer for preemption.
+    // It is stopped while the cpu is idle (see sched_halt).
+    lapic.timer_start();
 
     // Leave LINT0 of the BSP enabled so that it can get
     // interrupts from the 8259A chip.
@@ -247,3 +274,24 @@ pub(crate) fn cpu_num() -> i32 {
 pub(crate) fn eoi() {
     unsafe { LAPIC.as_ref().unwrap().eoi() };
 }
+
+/// Restart the periodic timer of this cpu.
+pub(crate) fn timer_start() {
+    unsafe { LAPIC.as_ref().unwrap().timer_start() };
+}
+
+/// Stop the periodic timer of this cpu.
+/// It is used to avoid useless wakeups while the cpu is idle.
+pub(crate) fn timer_stop() {
+    unsafe { LAPIC.as_ref().unwrap().timer_stop() };
+}
+
+/// Send an IPI to wake up the (probably halted) cpu specified by apic_id.
+pub(crate) fn send_wakeup(apic_id: u8) {
+    unsafe {
+        LAPIC
+            .as_ref()
+            .unwrap()
+            .send_ipi(apic_id, IRQ_OFFSET + IRQ_WAKEUP)
+    };
+}
@@ -19,6 +19,7 @@
 pub mod console;
 
 mod allocator;
+mod aslr;
 mod buf;
 pub mod constants;
 mod device;
@@ -26,6 +27,7 @@ mod elf;
 mod env;
 mod file;
 mod fs;
+mod fwcfg;
 mod gdt;
 mod ide;
 mod kbd;
@@ -39,14 +41,20 @@ mod once;
 mod picirq;
 mod pipe;
 mod pmap;
+mod random;
+mod rlimit;
 mod rwlock;
 mod sched;
 pub mod serial;
+mod slab;
 mod spinlock;
 mod superblock;
+mod swap;
+mod sysinfo;
 mod syscall;
 mod sysfile;
 mod trap;
+mod uaccess;
 mod util;
 pub mod vga_buffer;
 pub mod volatile;
@@ -80,7 +88,7 @@ pub fn lib_main() {
         let vga_buffer = &mut *((0xb8000 + KERN_BASE) as *mut Buffer);
         vga_buffer::init_writer(vga_buffer);
         pmap::mem_init();
-        HeapAllocator::init(KHEAP_BASE as usize, KHEAP_SIZE);
+        HeapAllocator::init();
         gdt::init_percpu();
         trap::trap_init();
         mpconfig::mp_init();
@@ -88,6 +96,8 @@ pub fn lib_main() {
         // do mp::boot_aps() after preparing processes
         picirq::pic_init();
         ide::ide_init();
+        swap::swap_init();
+        aslr::aslr_init();
         buf::buf_init();
         kbd::kbd_init();
         {
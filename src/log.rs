@@ -1,10 +1,10 @@
 use crate::buf::BufCacheHandler;
-use crate::constants::{BLK_SIZE, LOG_SIZE, MAX_OP_BLOCKS, ROOT_DEV};
+use crate::constants::{LOG_SIZE, MAX_OP_BLOCKS, MIN_BLK_SIZE, ROOT_DEV};
 use crate::once::Once;
 use crate::pmap::VirtAddr;
 use crate::spinlock::{Mutex, MutexGuard};
-use crate::{buf, superblock, util};
-use core::mem;
+use crate::{buf, fs, superblock, util};
+use core::{cmp, mem};
 
 // Contents of the header block, used for both the on-disk header block
 // and to keep track in memory of logged block# before commit.
@@ -36,6 +36,7 @@ impl LogHeader {
 struct Log {
     start: usize,
     size: usize,
+    capacity: usize, // how many data blocks fit in the log
     outstanding: usize, // how many FS sys calls are executing
     // committing: bool,   // true if someone is in commit(). Please wait
     dev: u32,
@@ -45,9 +46,12 @@ struct Log {
 impl Log {
     /// Create a new Log.
     fn new(start: usize, size: usize, dev: u32) -> Log {
+        // The first block of the log holds the header.
+        let capacity = cmp::min(size.saturating_sub(1), LOG_SIZE);
         Log {
             start,
             size,
+            capacity,
             outstanding: 0,
             dev,
             lh: LogHeader::empty(),
@@ -67,7 +71,7 @@ pub(crate) fn begin_op() {
     loop {
         let mut log = get_log();
 
-        if log.lh.n + (log.outstanding + 1) * MAX_OP_BLOCKS > LOG_SIZE {
+        if log.lh.n + (log.outstanding + 1) * MAX_OP_BLOCKS > log.capacity {
             // this op might exhaust log space; wait for commit
             continue;
         }
@@ -87,6 +91,9 @@ pub(crate) fn end_op() {
     if log.outstanding == 0 {
         // do commit
         commit(&mut log);
+        drop(log);
+        // Free the inodes put in the committed transactions.
+        fs::free_orphans();
     }
 }
 
@@ -113,7 +120,7 @@ fn write_log(log: &Log) {
         unsafe {
             let dst = VirtAddr(buf_to.data().as_ptr() as u32);
             let src = VirtAddr(buf_from.data().as_ptr() as u32);
-            let len = BLK_SIZE;
+            let len = buf_to.data().len();
             util::memmove(dst, src, len);
         }
 
@@ -175,7 +182,7 @@ fn install_trans(log: &Log) {
         unsafe {
             let dst = VirtAddr(buf_to.data().as_ptr() as u32);
             let src = VirtAddr(buf_from.data().as_ptr() as u32);
-            let len = BLK_SIZE;
+            let len = buf_to.data().len();
             util::memmove(dst, src, len);
         }
 
@@ -204,7 +211,7 @@ fn recover_from_log(log: &mut Log) {
 pub(crate) fn log_write(buf: &mut BufCacheHandler) {
     let mut log = get_log();
 
-    if log.lh.n >= LOG_SIZE || log.lh.n >= log.size - 1 {
+    if log.lh.n >= log.capacity {
         panic!("too big a transaction");
     }
     if log.outstanding < 1 {
@@ -235,12 +242,15 @@ pub(crate) fn log_write(buf: &mut BufCacheHandler) {
 }
 
 fn log_init(dev: u32) -> Log {
-    if mem::size_of::<LogHeader>() >= BLK_SIZE {
+    if mem::size_of::<LogHeader>() >= MIN_BLK_SIZE {
         panic!("log_init: too big logheader");
     }
 
     let sb = superblock::get();
     let mut log = Log::new(sb.log_start as usize, sb.nlog as usize, dev);
+    if log.capacity < MAX_OP_BLOCKS {
+        panic!("log_init: too small log");
+    }
 
     recover_from_log(&mut log);
 
@@ -52,6 +52,7 @@ pub(crate) fn boot_aps() {
 #[no_mangle]
 pub extern "C" fn mp_main() {
     // We are in high EIP now, safe to switch to kern_pgdir
+    pmap::mem_init_percpu();
     pmap::load_kern_pgdir();
     let cpu = mpconfig::this_cpu_mut();
     println!("SMP: CPU {} starting", cpu.cpu_id);
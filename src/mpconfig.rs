@@ -207,6 +207,7 @@ pub(crate) struct CpuInfo {
     cpu_status: CpuStatus,
     cpu_env: *mut Env,
     cpu_ts: TaskState,
+    cpu_tlb_flushes: u32, // Number of times this cpu has reloaded cr3
 }
 
 impl CpuInfo {
@@ -216,6 +217,7 @@ impl CpuInfo {
             cpu_status: CpuStatus::CpuUnused,
             cpu_env: null_mut(),
             cpu_ts: TaskState::empty(),
+            cpu_tlb_flushes: 0,
         }
     }
 
@@ -228,12 +230,22 @@ impl CpuInfo {
         &self.cpu_ts
     }
 
+    pub(crate) fn is_halted(&self) -> bool {
+        self.cpu_status == CpuStatus::CpuHalted
+    }
+
     pub(crate) fn started(&mut self) {
         let p = ((&mut self.cpu_status) as *mut CpuStatus).cast::<u32>();
         let v = CpuStatus::CpuStarted as u32;
         x86::xchg(p, v);
     }
 
+    pub(crate) fn halted(&mut self) {
+        let p = ((&mut self.cpu_status) as *mut CpuStatus).cast::<u32>();
+        let v = CpuStatus::CpuHalted as u32;
+        x86::xchg(p, v);
+    }
+
     pub(crate) fn cur_env(&self) -> Option<&Env> {
         unsafe { self.cpu_env.as_ref() }
     }
@@ -253,6 +265,19 @@ impl CpuInfo {
     pub(crate) fn get_ts_esp0(&self) -> VirtAddr {
         self.cpu_ts.ts_esp0
     }
+
+    /// Record that this cpu has flushed its TLB.
+    pub(crate) fn tlb_flushed(&mut self) {
+        let p = (&mut self.cpu_tlb_flushes) as *mut u32;
+        unsafe { p.write_volatile(self.cpu_tlb_flushes.wrapping_add(1)) };
+    }
+
+    /// Return the number of TLB flushes of this cpu.
+    /// Other cpus read this to know whether stale TLB entries may remain.
+    pub(crate) fn tlb_flushes(&self) -> u32 {
+        let p = (&self.cpu_tlb_flushes) as *const u32;
+        unsafe { p.read_volatile() }
+    }
 }
 
 // Why it requires 4 bytes?
@@ -1,20 +1,48 @@
-use core::mem;
+use core::{cmp, mem};
 use core::ops::{Add, AddAssign, Deref, DerefMut, Index, IndexMut, Sub};
 use core::ptr::{null_mut, slice_from_raw_parts};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use crate::allocator;
 use crate::constants::*;
 use crate::kclock;
+use crate::lapic;
 use crate::mpconfig::consts::MAX_NUM_CPU;
 use crate::spinlock::Mutex;
+use crate::swap;
 use crate::util;
 use crate::x86;
 use alloc::boxed::Box;
 
 extern "C" {
     static end: u32;
+    static etext: u32;
     static bootstack: u32;
 }
 
+// Physical address where the kernel text is loaded
+// FIXME: the same definition is in kernel.ld
+const KERN_TEXT_PADDR: u32 = 0x100000;
+
+// True if kern_pgdir uses 4 MiB pages, which requires CR4_PSE on every cpu.
+static PSE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// True if the kernel can't execute (SMEP) or access (SMAP) user pages.
+// User memory is accessed only in windows opened by uaccess.
+static SMEP_ENABLED: AtomicBool = AtomicBool::new(false);
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Physical memory below this is mapped at KERN_BASE (low memory).
+// Memory above this (high memory) is only accessible through kmap.
+const DIRECT_MAP_SIZE: u32 = 0xffffffff - KERN_BASE + 1;
+
+// The page table for kmap, which is shared by all page directories.
+static mut KMAP_PGTABLE: *mut PageTable = null_mut();
+// Number of kmap slots per cpu
+const KMAP_SLOTS: usize = 4;
+// Number of kmap slots in use on each cpu
+static mut KMAP_DEPTH: [usize; MAX_NUM_CPU] = [0; MAX_NUM_CPU];
+
 // This MUST be initialized first with `init()`
 struct KernelPageDirectory(*mut PageDirectory);
 // Get the lock of KERN_PGDIR first if you use both of KERN_PGDIR and PAGE_ALLOCATOR.
@@ -50,8 +78,12 @@ impl DerefMut for KernelPageDirectory {
 // MUST be initialized first with `init()`
 // Get the lock of KERN_PGDIR first if you use both of KERN_PGDIR and PAGE_ALLOCATOR.
 static PAGE_ALLOCATOR: Mutex<PageAllocator> = Mutex::new(PageAllocator {
-    page_free_list: null_mut(),
+    free_lists: [[null_mut(); MAX_ORDER + 1]; NZONES],
+    free_blocks: [[0; MAX_ORDER + 1]; NZONES],
     pages: null_mut(),
+    high_pages: null_mut(),
+    nlow: 0,
+    npages: 0,
 });
 
 #[repr(align(4096))]
@@ -164,8 +196,8 @@ pub(crate) struct PhysAddr(pub(crate) u32);
 
 impl PhysAddr {
     pub(crate) fn to_va(&self) -> VirtAddr {
-        // this is not actually correct, va should be less than npages * 4096.
-        assert!(self.0 < 0x10000000, "PhysAddr(0x{:x}) is too high", self.0);
+        // High memory must be accessed through kmap.
+        assert!(self.0 < DIRECT_MAP_SIZE, "PhysAddr(0x{:x}) is too high", self.0);
         VirtAddr(self.0 | KERN_BASE)
     }
 
@@ -284,8 +316,10 @@ impl PageDirectory {
         }
     }
 
-    pub(crate) fn new_for_user() -> Box<PageDirectory> {
-        let mut pgdir = PageDirectory::new();
+    /// Return NoMem if the kernel heap is exhausted.
+    pub(crate) fn new_for_user() -> Result<Box<PageDirectory>, SysError> {
+        // Allocate before locking because the kernel heap may map new pages.
+        let mut pgdir = allocator::try_box(PageDirectory::new())?;
         let kern_pgdir = KERN_PGDIR.lock();
         // Copy kernel mapping
         for (i, kern_pde) in kern_pgdir.entries.iter().enumerate() {
@@ -294,7 +328,7 @@ impl PageDirectory {
                 pgdir.entries[i] = pde;
             }
         }
-        Box::new(pgdir)
+        Ok(pgdir)
     }
 
     fn get(&mut self, pdx: PDX) -> Option<&mut PDE> {
@@ -324,11 +358,12 @@ impl PageDirectory {
     ) -> Option<&mut PTE> {
         let pdx = PDX::new(va);
         let pde = &mut self[pdx];
+        assert!(!pde.is_large(), "walk: va is mapped by a 4 MiB page");
         if !pde.exists() {
             if !should_create {
                 return None;
             }
-            let pa = allocator.alloc(AllocFlag::AllocZero).expect("alloc failed");
+            let pa = allocator.alloc(AllocFlag::AllocZero)?;
             pde.set(pa, PTE_U | PTE_P | PTE_W);
             allocator.incref_pde(pde);
         }
@@ -368,6 +403,29 @@ impl PageDirectory {
         }
     }
 
+    /// Map [va, va+size) to [pa, pa+size) with 4 MiB pages.
+    /// Size is a multiple of PTSIZE, and va and pa are both aligned to PTSIZE.
+    /// CR4_PSE must be enabled before this mapping is used.
+    fn boot_map_region_large(
+        &mut self,
+        start_va: VirtAddr,
+        size: usize,
+        start_pa: PhysAddr,
+        perm: u32,
+    ) {
+        assert_eq!(start_va.0 as usize % PTSIZE, 0, "start_va is not aligned.");
+        assert_eq!(start_pa.0 as usize % PTSIZE, 0, "start_pa is not aligned.");
+        assert_eq!(size % PTSIZE, 0, "size should be multiple of PTSIZE");
+
+        for i in 0..(size / PTSIZE) {
+            let va = start_va + i * PTSIZE;
+            let pa = start_pa + i * PTSIZE;
+            let pdx = PDX::new(va);
+            assert!(!self[pdx].exists(), "va is already mapped");
+            self[pdx] = PDE::new(pa, perm | PTE_P | PTE_PS);
+        }
+    }
+
     // Return the page mapped at virtual address 'va'.
     // PTE is used by page_remove and
     // can be used to verify page permissions for syscall arguments,
@@ -426,33 +484,50 @@ impl PageDirectory {
     /// RETURNS:
     ///   0 on success
     ///   -E_NO_MEM, if page table couldn't be allocated
-    fn insert(&mut self, pa: PhysAddr, va: VirtAddr, perm: u32, allocator: &mut PageAllocator) {
-        let old_pte = self.walk(va, true, allocator).unwrap();
+    fn insert(
+        &mut self,
+        pa: PhysAddr,
+        va: VirtAddr,
+        perm: u32,
+        allocator: &mut PageAllocator,
+    ) -> Result<(), SysError> {
+        let old_pte = self.walk(va, true, allocator).ok_or(SysError::NoMem)?;
         // increment first to handle the corner case: the same PageInfo is re-inserted at the same virtual address
         let new_pte = PTE::new(pa, perm | PTE_P);
         allocator.incref_pte(&new_pte);
         if old_pte.exists() {
             PageDirectory::remove_pte(va, old_pte, allocator);
+        } else if old_pte.is_swapped() {
+            swap::free_slot(old_pte.swap_slot());
         }
         old_pte.set(new_pte.addr(), new_pte.attr());
+        Ok(())
     }
 
     /// Allocate len bytes of physical memory for environment env,
     /// and map it at virtual address va in the environment's address space.
     /// Does not zero or otherwise initialize the mapped pages in any way.
     /// Pages should be writable by user and kernel.
-    /// Panic if any allocation attempt fails.
-    pub(crate) fn region_alloc(&mut self, va: VirtAddr, len: usize) {
-        let mut allocator = PAGE_ALLOCATOR.lock();
+    /// Return Err(NoMem) if any allocation attempt fails.
+    /// Pages mapped before the failure are left in place and
+    /// freed when the page directory is dropped.
+    pub(crate) fn region_alloc(&mut self, va: VirtAddr, len: usize) -> Result<(), SysError> {
         let start_va = va.round_down(PGSIZE as usize);
         let end_va = va.add(len).round_up(PGSIZE as usize);
 
         let mut va = start_va;
         while va < end_va {
-            let pa = allocator.alloc(AllocFlag::None).unwrap();
-            self.insert(pa, va, PTE_U | PTE_W, &mut *allocator);
+            // PageAllocator is not locked here because getting a page may swap out others.
+            let pa = alloc_user_page().ok_or(SysError::NoMem)?;
+            let mut allocator = PAGE_ALLOCATOR.lock();
+            if let Err(err) = self.insert(pa, va, PTE_U | PTE_W, &mut *allocator) {
+                let pp = allocator.page((pa.0 >> PGSHIFT) as usize);
+                allocator.free(pp);
+                return Err(err);
+            }
             va += PGSIZE;
         }
+        Ok(())
     }
 
     pub(crate) fn vaddr(&self) -> VirtAddr {
@@ -466,6 +541,11 @@ impl PageDirectory {
     /// Convert a virtual address to a physical address.
     /// Return None if there is not page mapping.
     pub(crate) fn convert_to_pa(&mut self, va: VirtAddr) -> Option<PhysAddr> {
+        let pde = &self[PDX::new(va)];
+        if pde.exists() && pde.is_large() {
+            return Some(PhysAddr(pde.0 & !(PTSIZE as u32 - 1)) + (va.0 & (PTSIZE as u32 - 1)));
+        }
+
         let mut allocator = PAGE_ALLOCATOR.lock();
         self.lookup(va, &mut *allocator)
             .map(|pte| pte.addr() + (va.0 & 0xfff))
@@ -482,6 +562,9 @@ impl PageDirectory {
             if pte.exists() {
                 let va = VirtAddr((pdx.0).0 | ((i as u32) * PGSIZE));
                 PageDirectory::remove_pte(va, pte, &mut *allocator);
+            } else if pte.is_swapped() {
+                swap::free_slot(pte.swap_slot());
+                pte.clear();
             }
         }
 
@@ -521,6 +604,8 @@ impl PageDirectory {
             let pte_opt = self.walk(va, false, &mut *allocator);
             match pte_opt {
                 None => return Err(va),
+                // A swapped-out page is brought back by the page fault handler on access.
+                Some(pte) if pte.is_swapped() && (pte.attr() | PTE_P) & perm == perm => (),
                 Some(pte) if pte.attr() & perm != perm => return Err(va),
                 _ => (),
             }
@@ -531,7 +616,8 @@ impl PageDirectory {
     }
 
     /// Copy src's pages in user region to self.
-    pub(crate) fn copy_uvm(&mut self, src: &mut PageDirectory) {
+    /// Return Err(NoMem) if memory is exhausted.
+    pub(crate) fn copy_uvm(&mut self, src: &mut PageDirectory) -> Result<(), SysError> {
         let mut va = VirtAddr(0);
         let end_va = VirtAddr(UTOP);
         let mut allocator = PAGE_ALLOCATOR.lock();
@@ -542,17 +628,141 @@ impl PageDirectory {
                     // println!("copy va(0x{:x})", va.0);
                     // Map a new page to self.
                     let attr = pte.attr();
-                    let pa = allocator.alloc(AllocFlag::None).unwrap();
-                    self.insert(pa, va, attr, &mut allocator);
+                    self.walk(va, true, &mut allocator)
+                        .ok_or(SysError::NoMem)?;
+                    let pa = allocator.alloc_high(AllocFlag::None).ok_or(SysError::NoMem)?;
+                    self.insert(pa, va, attr, &mut allocator)?;
 
                     // Copy memory.
-                    // Note that virtual addresses are handled in the parent page mapping (... is it right?)
-                    // unsafe { util::memmove(pa.to_va(), va, PGSIZE as usize) };
-                    unsafe { util::memmove(pa.to_va(), pte.addr().to_va(), PGSIZE as usize) };
+                    // Both pages may be in high memory, so map them temporarily.
+                    let dst = kmap(pa);
+                    let src = kmap(pte.addr());
+                    unsafe { util::memmove(dst.va(), src.va(), PGSIZE as usize) };
+                } else if pte.is_swapped() {
+                    // Read the page of src from the swap disk directly.
+                    let attr = pte.attr() & (PTE_U | PTE_W);
+                    let slot = pte.swap_slot();
+                    self.walk(va, true, &mut allocator)
+                        .ok_or(SysError::NoMem)?;
+                    let pa = allocator.alloc_high(AllocFlag::None).ok_or(SysError::NoMem)?;
+                    self.insert(pa, va, attr, &mut allocator)?;
+
+                    let dst = kmap(pa);
+                    swap::read_page(slot, dst.va());
                 }
             }
             va += PGSIZE;
         }
+        Ok(())
+    }
+
+    /// Return the number of user pages resident in physical memory (RSS).
+    /// Swapped-out pages and page tables are not counted.
+    pub(crate) fn resident_pages(&self) -> usize {
+        let mut count = 0;
+        let mut pdx = PDX::new(VirtAddr(0));
+        while pdx < PDX::new(VirtAddr(UTOP)) {
+            let pde = &self[pdx];
+            if pde.exists() {
+                let pt = pde.table();
+                count += (0..NPTENTRIES).filter(|&i| pt[i].exists()).count();
+            }
+            pdx += 1;
+        }
+        count
+    }
+
+    /// Return the number of pages (including page tables) required to copy
+    /// the user region of this page directory.
+    pub(crate) fn count_user_pages(&mut self) -> usize {
+        let mut count = 0;
+        let mut pdx = PDX::new(VirtAddr(0));
+        while pdx < PDX::new(VirtAddr(UTOP)) {
+            let pde = &self[pdx];
+            if pde.exists() {
+                count += 1;
+                let pt = pde.table();
+                for i in 0..NPTENTRIES {
+                    if pt[i].exists() || pt[i].is_swapped() {
+                        count += 1;
+                    }
+                }
+            }
+            pdx += 1;
+        }
+        count
+    }
+
+    /// Advance the clock hand over the user pages of this page directory
+    /// and return the first page which is not referenced since the last scan.
+    /// Referenced pages get a second chance: their accessed bits are cleared.
+    /// Shared pages are never chosen.
+    /// Return None if the hand reaches UTOP.
+    ///
+    /// This page directory must not be loaded on any cpu
+    /// because accessed bits are cleared without TLB invalidation.
+    pub(crate) fn clock_scan(&mut self, hand: &mut VirtAddr) -> Option<VirtAddr> {
+        let allocator = PAGE_ALLOCATOR.lock();
+        while *hand < VirtAddr(UTOP) {
+            let va = *hand;
+            let pde = &self[PDX::new(va)];
+            if !pde.exists() {
+                *hand = (va + PTSIZE).round_down(PTSIZE);
+                continue;
+            }
+            *hand += PGSIZE;
+
+            let pte = &mut pde.table()[PTX(va)];
+            if !pte.exists() || pte.attr() & PTE_U == 0 {
+                continue;
+            }
+            if pte.attr() & PTE_A != 0 {
+                pte.0 &= !PTE_A;
+                continue;
+            }
+            if allocator.ref_count(pte.addr()) != 1 {
+                continue;
+            }
+            return Some(va);
+        }
+        None
+    }
+
+    /// Replace the page at va with the swap slot and return the page.
+    /// The reference of the PTE is passed to the caller, who writes the page
+    /// to the slot and then releases it by put_user_page.
+    pub(crate) fn start_swap_out(&mut self, va: VirtAddr, slot: usize) -> PhysAddr {
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        let pte = self
+            .lookup(va, &mut allocator)
+            .expect("start_swap_out: page is not mapped");
+        let pa = pte.addr();
+        let attr = pte.attr();
+        pte.set_swapped(slot, attr);
+        x86::invlpg(va);
+        pa
+    }
+
+    /// Return the swap slot of va if the page is swapped out.
+    pub(crate) fn swapped_slot(&mut self, va: VirtAddr) -> Option<usize> {
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        self.walk(va, false, &mut allocator)
+            .filter(|pte| pte.is_swapped())
+            .map(|pte| pte.swap_slot())
+    }
+
+    /// Map pa, which has been read from the swap slot, at va.
+    /// The caller must free the slot.
+    pub(crate) fn swap_in(&mut self, va: VirtAddr, pa: PhysAddr) {
+        let mut allocator = PAGE_ALLOCATOR.lock();
+        let pte = self
+            .walk(va, false, &mut allocator)
+            .filter(|pte| pte.is_swapped())
+            .expect("swap_in: page is not swapped out");
+        let attr = pte.attr() & (PTE_U | PTE_W);
+        pte.set(pa, attr | PTE_P);
+        allocator.incref_pte(pte);
+        x86::invlpg(va);
     }
 }
 
@@ -629,6 +839,11 @@ impl PDE {
         self.0 & PTE_P == 0x1
     }
 
+    /// Return true if this maps a 4 MiB page instead of a page table.
+    fn is_large(&self) -> bool {
+        self.0 & PTE_PS != 0
+    }
+
     fn set(&mut self, pa: PhysAddr, attr: u32) {
         self.0 = pa.0 | attr;
     }
@@ -713,6 +928,22 @@ impl PTE {
     fn clear(&mut self) {
         self.0 = 0;
     }
+
+    /// Return true if the page is swapped out.
+    /// Then the entry holds the swap slot instead of the physical address.
+    fn is_swapped(&self) -> bool {
+        self.0 & (PTE_P | PTE_SWAPPED) == PTE_SWAPPED
+    }
+
+    fn swap_slot(&self) -> usize {
+        (self.0 >> PGSHIFT) as usize
+    }
+
+    /// Make this entry refer to the swap slot.
+    /// The permissions are kept to restore them on swap-in.
+    fn set_swapped(&mut self, slot: usize, attr: u32) {
+        self.0 = ((slot as u32) << PGSHIFT) | (attr & (PTE_U | PTE_W)) | PTE_SWAPPED;
+    }
 }
 
 fn round_up_u32(x: u32, base: u32) -> u32 {
@@ -828,33 +1059,40 @@ pub fn mem_init() {
     // each physical page, there is a corresponding struct PageInfo in this
     // array.  'npages' is the number of physical pages in memory.  Use memset
     // to initialize all fields of each struct PageInfo to 0.
+    // Only PageInfos of low memory are allocated here. Those of high memory are
+    // allocated after the switch to kern_pgdir because entry_pgdir maps only 4 MiB.
+    let nlow = cmp::min(npages, DIRECT_MAP_SIZE / PGSIZE);
     let page_info_size = mem::size_of::<PageInfo>();
-    let pages = boot_allocator.alloc(npages * page_info_size as u32).0 as *mut PageInfo;
-
-    // Allocate kernel heap
-    let kheap = boot_allocator.alloc(KHEAP_SIZE as u32).0 as *mut PageInfo;
-    println!("kheap: {:?}", kheap);
+    let pages = boot_allocator.alloc(nlow * page_info_size as u32).0 as *mut PageInfo;
 
     // Now that we've allocated the initial kernel data structures, we set
     // up the list of free physical pages. Once we've done so, all further
     // memory management will go through the page_* functions. In
     // particular, we can now map memory using boot_map_region or page_insert
     let mut allocator = PAGE_ALLOCATOR.lock();
-    allocator.init(pages, &mut boot_allocator, npages, npages_basemem);
+    allocator.init(pages, &mut boot_allocator, nlow, npages_basemem);
     println!("pages: 0x{:?}", pages);
-    println!("page_free_list: 0x{:?}", allocator.page_free_list);
+    println!("free pages: {}", allocator.count());
 
     // Now we set up virtual memory
 
-    // Map kernel heap
+    // Allocate page tables for kernel heap
     // This mapping is not in neither xv6 nor jos.
-    kern_pgdir.boot_map_region(
-        VirtAddr(KHEAP_BASE),
-        KHEAP_SIZE,
-        VirtAddr(kheap as u32).to_pa(),
-        PTE_P | PTE_W,
-        &mut allocator,
-    );
+    // Pages of the heap are mapped on demand, but its page tables must exist
+    // from the start because user page directories copy the PDEs of kern_pgdir.
+    let mut va = VirtAddr(KHEAP_BASE);
+    while va < VirtAddr(KHEAP_BASE) + KHEAP_SIZE {
+        kern_pgdir
+            .walk(va, true, &mut allocator)
+            .expect("failed to allocate page tables for kernel heap");
+        va += PTSIZE;
+    }
+
+    // Allocate the page table for temporary mappings of high memory.
+    kern_pgdir
+        .walk(VirtAddr(KMAP_BASE), true, &mut allocator)
+        .expect("failed to allocate the page table for kmap");
+    unsafe { KMAP_PGTABLE = kern_pgdir[PDX::new(VirtAddr(KMAP_BASE))].table() };
 
     // Initialize the SMP-related parts of the memory map.
     mem_init_mp(&mut *kern_pgdir, &mut allocator);
@@ -865,13 +1103,64 @@ pub fn mem_init() {
     // We might not have 2^32 - KERNBASE bytes of physical memory, but
     // we just set up the mapping anyway.
     // Permissions: kernel RW, user NONE
-    kern_pgdir.boot_map_region(
-        VirtAddr(KERN_BASE),
-        ((0xffffffff) - KERN_BASE + 1) as usize,
-        PhysAddr(0),
-        PTE_P | PTE_W,
-        &mut allocator,
-    );
+    let direct_map_size = DIRECT_MAP_SIZE as usize;
+    if pse_supported() {
+        // The first 4 MiB is mapped with 4 KiB pages to make the kernel text read-only,
+        // and the rest is mapped with 4 MiB pages.
+        let text_end = unsafe { VirtAddr(&etext as *const _ as u32).to_pa() };
+        assert!(
+            text_end.0 as usize <= PTSIZE,
+            "kernel text should be in the first 4 MiB"
+        );
+        kern_pgdir.boot_map_region(
+            VirtAddr(KERN_BASE),
+            KERN_TEXT_PADDR as usize,
+            PhysAddr(0),
+            PTE_P | PTE_W,
+            &mut allocator,
+        );
+        kern_pgdir.boot_map_region(
+            PhysAddr(KERN_TEXT_PADDR).to_va(),
+            (text_end.0 - KERN_TEXT_PADDR) as usize,
+            PhysAddr(KERN_TEXT_PADDR),
+            PTE_P,
+            &mut allocator,
+        );
+        kern_pgdir.boot_map_region(
+            text_end.to_va(),
+            PTSIZE - text_end.0 as usize,
+            text_end,
+            PTE_P | PTE_W,
+            &mut allocator,
+        );
+        kern_pgdir.boot_map_region_large(
+            VirtAddr(KERN_BASE) + PTSIZE,
+            direct_map_size - PTSIZE,
+            PhysAddr(PTSIZE as u32),
+            PTE_W,
+        );
+
+        PSE_ENABLED.store(true, Ordering::SeqCst);
+        println!("PSE is enabled");
+    } else {
+        kern_pgdir.boot_map_region(
+            VirtAddr(KERN_BASE),
+            direct_map_size,
+            PhysAddr(0),
+            PTE_P | PTE_W,
+            &mut allocator,
+        );
+    }
+    let (smep, smap) = smep_smap_supported();
+    SMEP_ENABLED.store(smep, Ordering::SeqCst);
+    SMAP_ENABLED.store(smap, Ordering::SeqCst);
+    if smep {
+        println!("SMEP is enabled");
+    }
+    if smap {
+        println!("SMAP is enabled");
+    }
+    mem_init_percpu();
 
     // Switch from the minimal entry page directory to the full kern_pgdir
     // page table we just created.	Our instruction pointer should be
@@ -885,6 +1174,53 @@ pub fn mem_init() {
     cr0 |= CR0_PE | CR0_PG | CR0_AM | CR0_WP | CR0_NE | CR0_MP;
     cr0 &= !(CR0_TS | CR0_EM);
     x86::lcr0(cr0);
+
+    // Add high memory to the allocator.
+    if npages > nlow {
+        let nhigh = allocator.init_highmem(npages);
+        println!("high memory: {} pages", nhigh);
+        if nhigh < (npages - nlow) as usize {
+            println!(
+                "{} pages of high memory are ignored",
+                (npages - nlow) as usize - nhigh
+            );
+        }
+    }
+}
+
+fn pse_supported() -> bool {
+    let (_, _, _, edx) = x86::cpuid(1);
+    edx & CPUID_EDX_PSE != 0
+}
+
+/// Return whether (SMEP, SMAP) are supported.
+fn smep_smap_supported() -> (bool, bool) {
+    let (max_leaf, _, _, _) = x86::cpuid(0);
+    if max_leaf < 7 {
+        return (false, false);
+    }
+    let (_, ebx, _, _) = x86::cpuid(7);
+    (ebx & CPUID_7_EBX_SMEP != 0, ebx & CPUID_7_EBX_SMAP != 0)
+}
+
+pub(crate) fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Set up control registers required by kern_pgdir on this cpu.
+/// This must be called before loading kern_pgdir.
+pub(crate) fn mem_init_percpu() {
+    let mut cr4 = x86::rcr4();
+    if PSE_ENABLED.load(Ordering::SeqCst) {
+        cr4 |= CR4_PSE;
+    }
+    if SMEP_ENABLED.load(Ordering::SeqCst) {
+        cr4 |= CR4_SMEP;
+    }
+    if SMAP_ENABLED.load(Ordering::SeqCst) {
+        cr4 |= CR4_SMAP;
+    }
+    x86::lcr4(cr4);
 }
 
 /// Modify mappings in kern_pgdir to support SMP
@@ -921,24 +1257,45 @@ fn mem_init_mp(kern_pgdir: &mut PageDirectory, allocator: &mut PageAllocator) {
 // --------------------------------------------------------------
 // Tracking of physical pages.
 // The 'pages' array has one 'struct PageInfo' entry per physical page.
-// Pages are reference counted, and free pages are kept on a linked list.
+// Pages are reference counted, and free pages are managed by the buddy system:
+// a free block of order k consists of 2^k pages aligned to 2^k pages and
+// is kept on the free list of order k.
+//
+// Physical memory is divided into two zones. Low memory is mapped at KERN_BASE,
+// so the kernel can access it directly. High memory is used for pages which
+// the kernel accesses through other mappings (user pages and kernel heap pages).
 // --------------------------------------------------------------
 
+/// The maximum order of blocks (4 MiB)
+pub(crate) const MAX_ORDER: usize = 10;
+
+const ZONE_LOW: usize = 0;
+const ZONE_HIGH: usize = 1;
+const NZONES: usize = 2;
+
 #[derive(Debug)]
 #[repr(C)]
 struct PageInfo {
-    pp_link: *mut PageInfo,
+    pp_link: *mut PageInfo, // next block in the free list
+    pp_prev: *mut PageInfo, // previous block in the free list
     pp_ref: u16,
+    pp_order: u8, // order of the block if this is the head of a free or allocated block
+    pp_free: bool, // true if this is the head of a free block
 }
 
 // FIXME: how to represent it in rust way
 // This MUST be protected by Mutex
 struct PageAllocator {
-    page_free_list: *mut PageInfo,
-    pages: *mut PageInfo,
+    free_lists: [[*mut PageInfo; MAX_ORDER + 1]; NZONES],
+    free_blocks: [[usize; MAX_ORDER + 1]; NZONES], // number of free blocks of each order
+    pages: *mut PageInfo,      // PageInfo of low memory
+    high_pages: *mut PageInfo, // PageInfo of high memory
+    nlow: usize,
+    npages: usize,
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 #[repr(u8)]
 enum AllocFlag {
     None,
@@ -948,23 +1305,63 @@ enum AllocFlag {
 unsafe impl Send for PageAllocator {}
 unsafe impl Sync for PageAllocator {}
 
+/// Statistics of free physical memory.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PhysMemStats {
+    pub(crate) free_blocks: [usize; MAX_ORDER + 1], // number of free blocks of each order
+    pub(crate) free_pages: usize,
+    pub(crate) free_high_pages: usize, // free pages in high memory
+    pub(crate) total_pages: usize,
+    pub(crate) high_pages: usize,
+}
+
+impl PhysMemStats {
+    /// Return the largest order which can be allocated now.
+    pub(crate) fn largest_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER).rev().find(|&order| self.free_blocks[order] > 0)
+    }
+
+    /// Return the percentage of free pages which cannot be used
+    /// for an allocation of the order (unusable free space index).
+    /// 0 means no fragmentation and 100 means that the order cannot be allocated.
+    pub(crate) fn fragmentation(&self, order: usize) -> usize {
+        if self.free_pages == 0 {
+            return 0;
+        }
+        let usable: usize = (order..=MAX_ORDER)
+            .map(|k| self.free_blocks[k] << k)
+            .sum();
+        (self.free_pages - usable) * 100 / self.free_pages
+    }
+}
+
 impl PageAllocator {
-    /// Initialize page structure and memory free list.
+    /// Initialize page structure and memory free list of low memory.
     /// After this is done, NEVER use boot_alloc again.  ONLY use the page
     /// allocator functions below to allocate and deallocate physical
-    /// memory via the page_free_list.
+    /// memory via the free lists.
     fn init(
         &mut self,
         pages: *mut PageInfo,
         ba: &mut BootAllocator,
-        npages: u32,
+        nlow: u32,
         npages_basemem: u32,
     ) {
-        self.page_free_list = null_mut();
+        self.free_lists = [[null_mut(); MAX_ORDER + 1]; NZONES];
+        self.free_blocks = [[0; MAX_ORDER + 1]; NZONES];
         self.pages = pages;
+        self.nlow = nlow as usize;
+        self.npages = nlow as usize;
 
+        for i in 0..self.nlow {
+            PageAllocator::init_page_info(self.page(i));
+        }
+
+        // Free pages from the higher addresses because free lists are LIFO.
+        // Lower pages should be allocated first because entry_pgdir
+        // doesn't map the higher addresses.
         let first_free_page = ba.alloc(0).to_pa().0 / PGSIZE;
-        for i in 0..npages {
+        for i in (0..nlow).rev() {
             // skip the first 4 KB in case that we need real-mode IDT and BIOS structures.
             if i == 0 {
                 continue;
@@ -986,93 +1383,211 @@ impl PageAllocator {
                 continue;
             }
 
-            let page = unsafe { &mut *(self.pages.add(i as usize)) };
-            // println!("page[{}]: {:?}", i, page);
-            page.pp_ref = 0;
-            page.pp_link = self.page_free_list;
-            self.page_free_list = page as *mut PageInfo;
+            let page = self.page(i as usize);
+            unsafe { (*page).pp_ref = 0 };
+            self.free_order(page, 0);
+        }
+    }
+
+    /// Add high memory to the allocator.
+    /// This must be called after kern_pgdir is loaded because
+    /// the PageInfo array is allocated from low memory.
+    /// Return the number of pages added.
+    fn init_highmem(&mut self, npages: u32) -> usize {
+        let page_info_size = mem::size_of::<PageInfo>();
+        let pages_per_block = (PGSIZE as usize << MAX_ORDER) / page_info_size;
+        let nhigh = cmp::min(npages as usize - self.nlow, pages_per_block);
+        if nhigh == 0 {
+            return 0;
         }
 
-        // It is necessary to reverse the order because
-        // entry_pgdir doesn't map the higher addresses.
+        let bytes = nhigh * page_info_size;
+        let order = (0..=MAX_ORDER)
+            .find(|&k| (PGSIZE as usize) << k >= bytes)
+            .unwrap();
+        let pa = match self.alloc_order(order, AllocFlag::None) {
+            None => return 0,
+            Some(pa) => pa,
+        };
+        self.high_pages = pa.to_va().as_mut_ptr();
+        self.npages = self.nlow + nhigh;
+
+        for i in self.nlow..self.npages {
+            PageAllocator::init_page_info(self.page(i));
+        }
+        for i in (self.nlow..self.npages).rev() {
+            let page = self.page(i);
+            unsafe { (*page).pp_ref = 0 };
+            self.free_order(page, 0);
+        }
+        nhigh
+    }
+
+    fn init_page_info(page: *mut PageInfo) {
         unsafe {
-            let mut prev = self.page_free_list;
-            let mut cur = (*prev).pp_link;
-            (*prev).pp_link = null_mut();
-            while cur != null_mut() {
-                let tmp = (*cur).pp_link;
-                (*cur).pp_link = prev;
-                prev = cur;
-                cur = tmp;
+            (*page).pp_link = null_mut();
+            (*page).pp_prev = null_mut();
+            (*page).pp_ref = 1;
+            (*page).pp_order = 0;
+            (*page).pp_free = false;
+        }
+    }
+
+    fn page(&self, idx: usize) -> *mut PageInfo {
+        unsafe {
+            if idx < self.nlow {
+                self.pages.add(idx)
+            } else {
+                self.high_pages.add(idx - self.nlow)
             }
-            self.page_free_list = prev;
         }
     }
 
-    /// Allocates a physical page.  If (alloc_flags & ALLOC_ZERO), fills the entire
+    fn index(&self, pp: *const PageInfo) -> usize {
+        unsafe {
+            let low_end = self.pages.add(self.nlow) as *const PageInfo;
+            if pp >= self.pages && pp < low_end {
+                pp.offset_from(self.pages) as usize
+            } else {
+                self.nlow + pp.offset_from(self.high_pages) as usize
+            }
+        }
+    }
+
+    fn zone(&self, idx: usize) -> usize {
+        if idx < self.nlow {
+            ZONE_LOW
+        } else {
+            ZONE_HIGH
+        }
+    }
+
+    fn push_block(&mut self, pp: *mut PageInfo, order: usize) {
+        let zone = self.zone(self.index(pp));
+        unsafe {
+            (*pp).pp_order = order as u8;
+            (*pp).pp_free = true;
+            (*pp).pp_prev = null_mut();
+            (*pp).pp_link = self.free_lists[zone][order];
+            if !self.free_lists[zone][order].is_null() {
+                (*self.free_lists[zone][order]).pp_prev = pp;
+            }
+        }
+        self.free_lists[zone][order] = pp;
+        self.free_blocks[zone][order] += 1;
+    }
+
+    fn remove_block(&mut self, pp: *mut PageInfo, order: usize) {
+        let zone = self.zone(self.index(pp));
+        unsafe {
+            if (*pp).pp_prev.is_null() {
+                self.free_lists[zone][order] = (*pp).pp_link;
+            } else {
+                (*(*pp).pp_prev).pp_link = (*pp).pp_link;
+            }
+            if !(*pp).pp_link.is_null() {
+                (*(*pp).pp_link).pp_prev = (*pp).pp_prev;
+            }
+            (*pp).pp_link = null_mut();
+            (*pp).pp_prev = null_mut();
+            (*pp).pp_free = false;
+        }
+        self.free_blocks[zone][order] -= 1;
+    }
+
+    /// Allocates a physical page of low memory.  If (alloc_flags & ALLOC_ZERO), fills the entire
     /// returned physical page with '\0' bytes.  Does NOT increment the reference
     /// count of the page - the caller must do these if necessary (either explicitly
     /// or via page_insert).
     ///
-    /// Be sure to set the pp_link field of the allocated page to NULL so
-    /// page_free can check for double-free bugs.
-    ///
     /// Returns NULL if out of free memory.
     fn alloc(&mut self, flag: AllocFlag) -> Option<PhysAddr> {
-        unsafe {
-            let pp = self.page_free_list;
-            if pp == null_mut() {
-                return None;
-            }
+        self.alloc_order(0, flag)
+    }
 
-            self.page_free_list = (*pp).pp_link;
+    /// Allocates a physical page preferring high memory.
+    /// The page must not be accessed through the direct map (use kmap).
+    fn alloc_high(&mut self, flag: AllocFlag) -> Option<PhysAddr> {
+        self.alloc_zone(ZONE_HIGH, 0, flag)
+            .or_else(|| self.alloc_zone(ZONE_LOW, 0, flag))
+    }
 
-            match flag {
-                AllocFlag::AllocZero => {
-                    let va: *mut u8 = self.to_pa(pp).to_va().as_mut_ptr();
-                    core::intrinsics::write_bytes(va, 0, PGSIZE as usize);
-                }
-                _ => {}
-            }
+    /// Allocates 2^order physically contiguous pages of low memory aligned to 2^order pages.
+    /// The reference count of each page is 0.
+    fn alloc_order(&mut self, order: usize, flag: AllocFlag) -> Option<PhysAddr> {
+        self.alloc_zone(ZONE_LOW, order, flag)
+    }
 
-            #[cfg(feature = "debug")]
-            println!(
-                "[PageAllocator] alloc. pp: {:p}, phys: {:x}, page_free_list: {:p}",
-                pp,
-                self.to_pa(pp).0,
-                self.page_free_list
-            );
+    fn alloc_zone(&mut self, zone: usize, order: usize, flag: AllocFlag) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
 
-            (*pp).pp_ref = 0;
-            (*pp).pp_link = null_mut();
+        // Find the smallest free block which is large enough.
+        let mut k = (order..=MAX_ORDER).find(|&k| !self.free_lists[zone][k].is_null())?;
+        let pp = self.free_lists[zone][k];
+        self.remove_block(pp, k);
+
+        // Split the block and return the upper halves to the free lists.
+        let idx = self.index(pp);
+        while k > order {
+            k -= 1;
+            let buddy = self.page(idx + (1 << k));
+            self.push_block(buddy, k);
+        }
 
-            Some(self.to_pa(pp))
+        let pa = self.to_pa(pp);
+        for i in 0..(1 << order) {
+            unsafe { (*self.page(idx + i)).pp_ref = 0 };
+        }
+        unsafe { (*pp).pp_order = order as u8 };
+
+        match flag {
+            AllocFlag::AllocZero => {
+                for i in 0..(1 << order) {
+                    let page = kmap(pa + i * PGSIZE as usize);
+                    unsafe {
+                        core::intrinsics::write_bytes(
+                            page.va().as_mut_ptr::<u8>(),
+                            0,
+                            PGSIZE as usize,
+                        );
+                    }
+                }
+            }
+            _ => {}
         }
+
+        #[cfg(feature = "debug")]
+        println!(
+            "[PageAllocator] alloc. pp: {:p}, phys: {:x}, order: {}",
+            pp, pa.0, order
+        );
+
+        Some(pa)
     }
 
     fn to_pa(&self, pp: *const PageInfo) -> PhysAddr {
-        unsafe {
-            let offset = pp.offset_from(self.pages) as u32;
-            PhysAddr(offset << PGSHIFT)
-        }
+        PhysAddr((self.index(pp) as u32) << PGSHIFT)
+    }
+
+    fn ref_count(&self, pa: PhysAddr) -> u16 {
+        unsafe { (*self.page((pa.0 >> PGSHIFT) as usize)).pp_ref }
     }
 
     // TODO: summarize PDE and PTE
     fn incref_pte(&self, pte: &PTE) {
-        let offset = (pte.0 >> PGSHIFT) as isize;
-        let pp = unsafe { &mut *(self.pages.offset(offset)) };
+        let pp = unsafe { &mut *self.page((pte.0 >> PGSHIFT) as usize) };
         pp.pp_ref += 1;
     }
 
     fn incref_pde(&self, pde: &PDE) {
-        let offset = (pde.0 >> PGSHIFT) as isize;
-        let pp = unsafe { &mut *(self.pages.offset(offset)) };
+        let pp = unsafe { &mut *self.page((pde.0 >> PGSHIFT) as usize) };
         pp.pp_ref += 1;
     }
 
     fn decref_pte(&mut self, pte: &PTE) {
-        let offset = (pte.0 >> PGSHIFT) as isize;
-        let pp = unsafe { &mut *(self.pages.offset(offset)) };
+        let pp = unsafe { &mut *self.page((pte.0 >> PGSHIFT) as usize) };
         pp.pp_ref -= 1;
         if pp.pp_ref == 0 {
             self.free(pp);
@@ -1080,8 +1595,7 @@ impl PageAllocator {
     }
 
     fn decref_pde(&mut self, pde: &PDE) {
-        let offset = (pde.0 >> PGSHIFT) as isize;
-        let pp = unsafe { &mut *(self.pages.offset(offset)) };
+        let pp = unsafe { &mut *self.page((pde.0 >> PGSHIFT) as usize) };
         pp.pp_ref -= 1;
         if pp.pp_ref == 0 {
             self.free(pp);
@@ -1091,27 +1605,124 @@ impl PageAllocator {
     /// Return a page to the free list.
     /// (This function should only be called when pp->pp_ref reaches 0.)
     fn free(&mut self, pp: *mut PageInfo) {
+        self.free_order(pp, 0);
+    }
+
+    /// Return a block of 2^order pages and merge it with its buddies.
+    /// Blocks never cross the boundary of zones because it is aligned to 4 MiB.
+    fn free_order(&mut self, pp: *mut PageInfo, order: usize) {
         unsafe {
             assert_ne!(pp, null_mut(), "pp should not be null");
             assert_eq!((*pp).pp_ref, 0, "pp_ref should be zero");
-            assert_eq!((*pp).pp_link, null_mut(), "pp_link should be null");
+            assert!(!(*pp).pp_free, "pp should not be freed twice");
+        }
 
-            #[cfg(feature = "debug")]
-            println!("[PageAllocator] free page_info {:p} ", pp);
-            (*pp).pp_link = self.page_free_list;
-            self.page_free_list = pp;
+        #[cfg(feature = "debug")]
+        println!("[PageAllocator] free page_info {:p}, order: {}", pp, order);
+
+        let mut idx = self.index(pp);
+        let mut k = order;
+        while k < MAX_ORDER {
+            let buddy_idx = idx ^ (1 << k);
+            if buddy_idx + (1 << k) > self.npages {
+                break;
+            }
+            let buddy = self.page(buddy_idx);
+            let is_free_buddy = unsafe { (*buddy).pp_free && (*buddy).pp_order as usize == k };
+            if !is_free_buddy {
+                break;
+            }
+            self.remove_block(buddy, k);
+            idx &= !(1 << k);
+            k += 1;
         }
+        self.push_block(self.page(idx), k);
+    }
+
+    fn count_zone(&self, zone: usize) -> usize {
+        (0..=MAX_ORDER)
+            .map(|k| self.free_blocks[zone][k] << k)
+            .sum()
     }
 
     /// Return count of free pages.
     pub(crate) fn count(&self) -> usize {
-        let mut n = 0;
-        let mut ptr = self.page_free_list;
-        while ptr != null_mut() {
-            n += 1;
-            ptr = unsafe { (*ptr).pp_link };
+        self.count_zone(ZONE_LOW) + self.count_zone(ZONE_HIGH)
+    }
+
+    fn stats(&self) -> PhysMemStats {
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        for k in 0..=MAX_ORDER {
+            free_blocks[k] = self.free_blocks[ZONE_LOW][k] + self.free_blocks[ZONE_HIGH][k];
+        }
+        PhysMemStats {
+            free_blocks,
+            free_pages: self.count(),
+            free_high_pages: self.count_zone(ZONE_HIGH),
+            total_pages: self.npages,
+            high_pages: self.npages - self.nlow,
+        }
+    }
+}
+
+/// A temporary kernel mapping of a physical page.
+/// The mapping is removed when this is dropped.
+pub(crate) struct Kmap {
+    va: VirtAddr,
+    slot: Option<usize>, // None if the page is in low memory
+}
+
+impl Kmap {
+    pub(crate) fn va(&self) -> VirtAddr {
+        self.va
+    }
+}
+
+impl Drop for Kmap {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            let cpu = lapic::cpu_num() as usize;
+            unsafe {
+                KMAP_DEPTH[cpu] -= 1;
+                assert_eq!(
+                    slot,
+                    cpu * KMAP_SLOTS + KMAP_DEPTH[cpu],
+                    "kmap should be released in reverse order"
+                );
+                (*KMAP_PGTABLE)[slot].clear();
+            }
+            x86::invlpg(self.va.round_down(PGSIZE as usize));
+        }
+    }
+}
+
+/// Map the physical page containing pa temporarily and
+/// return the mapping which can be used only on this cpu.
+/// Low memory is returned as it is in the direct map.
+pub(crate) fn kmap(pa: PhysAddr) -> Kmap {
+    if pa.0 < DIRECT_MAP_SIZE {
+        return Kmap {
+            va: pa.to_va(),
+            slot: None,
+        };
+    }
+
+    let cpu = lapic::cpu_num() as usize;
+    unsafe {
+        assert!(
+            KMAP_DEPTH[cpu] < KMAP_SLOTS,
+            "kmap: too many temporary mappings"
+        );
+        let slot = cpu * KMAP_SLOTS + KMAP_DEPTH[cpu];
+        KMAP_DEPTH[cpu] += 1;
+
+        let va = VirtAddr(KMAP_BASE) + slot * PGSIZE as usize;
+        (*KMAP_PGTABLE)[slot].set(PhysAddr(pa.0 & !(PGSIZE - 1)), PTE_P | PTE_W);
+        x86::invlpg(va);
+        Kmap {
+            va: va + (pa.0 & (PGSIZE - 1)),
+            slot: Some(slot),
         }
-        n
     }
 }
 
@@ -1125,7 +1736,76 @@ pub(crate) fn load_kern_pgdir() {
     x86::lcr3(kern_pgdir.paddr());
 }
 
+/// Map a new page at va in the kernel heap.
+pub(crate) fn kheap_map_page(va: VirtAddr) -> Result<(), SysError> {
+    assert!(va >= VirtAddr(KHEAP_BASE) && va < VirtAddr(KHEAP_BASE) + KHEAP_SIZE);
+    let mut kern_pgdir = KERN_PGDIR.lock();
+    let mut allocator = PAGE_ALLOCATOR.lock();
+    let pa = allocator.alloc_high(AllocFlag::None).ok_or(SysError::NoMem)?;
+    kern_pgdir.insert(pa, va, PTE_W, &mut allocator)
+}
+
+/// Unmap the page at va in the kernel heap and give it back to PageAllocator.
+/// Note that only the TLB of this cpu is invalidated.
+pub(crate) fn kheap_unmap_page(va: VirtAddr) {
+    assert!(va >= VirtAddr(KHEAP_BASE) && va < VirtAddr(KHEAP_BASE) + KHEAP_SIZE);
+    let mut kern_pgdir = KERN_PGDIR.lock();
+    let mut allocator = PAGE_ALLOCATOR.lock();
+    kern_pgdir.remove(va, &mut allocator);
+}
+
+/// Allocate a page for user space. The page is not zeroed
+/// and its reference count is 0 until it is mapped.
+///
+/// If no page is free, pages of other envs are swapped out. This is done
+/// only if EnvTable is available because this cpu may have locked it.
+pub(crate) fn alloc_user_page() -> Option<PhysAddr> {
+    let pa = PAGE_ALLOCATOR.lock().alloc_high(AllocFlag::None);
+    if pa.is_some() {
+        return pa;
+    }
+    swap::reclaim(1, false);
+    PAGE_ALLOCATOR.lock().alloc_high(AllocFlag::None)
+}
+
+/// Drop a reference to a user page, and free it if it was the last one.
+pub(crate) fn put_user_page(pa: PhysAddr) {
+    let mut allocator = PAGE_ALLOCATOR.lock();
+    allocator.decref_pte(&PTE::new(pa, 0));
+}
+
 pub(crate) fn free_page_count() -> usize {
     let allocator = PAGE_ALLOCATOR.lock();
     allocator.count()
 }
+
+/// Allocate 2^order physically contiguous pages aligned to 2^order pages.
+/// This is for device drivers (e.g. DMA buffers), so the pages are not reference counted.
+/// Return None if there is no such block.
+pub(crate) fn alloc_contiguous(order: usize) -> Option<PhysAddr> {
+    let mut allocator = PAGE_ALLOCATOR.lock();
+    allocator.alloc_order(order, AllocFlag::AllocZero)
+}
+
+/// Free pages allocated by alloc_contiguous.
+pub(crate) fn free_contiguous(pa: PhysAddr, order: usize) {
+    assert!(order <= MAX_ORDER, "order is too large");
+    assert_eq!(
+        pa.0 as usize % ((PGSIZE as usize) << order),
+        0,
+        "pa is not aligned to the order"
+    );
+    let mut allocator = PAGE_ALLOCATOR.lock();
+    let pp = allocator.page((pa.0 >> PGSHIFT) as usize);
+    assert_eq!(
+        unsafe { (*pp).pp_order } as usize,
+        order,
+        "pa was not allocated with the order"
+    );
+    allocator.free_order(pp, order);
+}
+
+pub(crate) fn phys_mem_stats() -> PhysMemStats {
+    let allocator = PAGE_ALLOCATOR.lock();
+    allocator.stats()
+}
@@ -0,0 +1,31 @@
+// Entropy source of the kernel.
+//
+// The pool is stirred with the time-stamp counter at each timer interrupt
+// and again when a value is taken, so the output depends on the jitter of
+// interrupts as well as the time of the request.
+// It is not cryptographically secure.
+
+use crate::spinlock::Mutex;
+use crate::x86;
+
+static POOL: Mutex<u64> = Mutex::new(0);
+
+/// Finalizer of SplitMix64.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Called from the timer interrupt handler.
+pub(crate) fn add_timer_entropy() {
+    let mut pool = POOL.lock();
+    *pool = mix(*pool ^ x86::rdtsc());
+}
+
+pub(crate) fn random_u32() -> u32 {
+    let mut pool = POOL.lock();
+    *pool = mix(*pool ^ x86::rdtsc());
+    (*pool >> 32) as u32
+}
@@ -0,0 +1,70 @@
+use crate::constants::*;
+use consts::*;
+
+// FIXME: the same definition is in user/user.h
+pub(crate) mod consts {
+    pub(crate) const RLIMIT_NOFILE: usize = 0; // maximum number of open files
+    pub(crate) const RLIMIT_HEAP: usize = 1; // maximum size of user heap (bytes)
+    pub(crate) const RLIMIT_STACK: usize = 2; // maximum size of user stack (bytes)
+    pub(crate) const RLIMIT_NCHILD: usize = 3; // maximum number of children
+    pub(crate) const RLIMIT_CPU: usize = 4; // maximum cpu time (tsc ticks)
+    pub(crate) const RLIMIT_NLIMITS: usize = 5;
+
+    pub(crate) const RLIM_INFINITY: u64 = u64::MAX;
+}
+
+/// Soft (cur) and hard (max) limit of a resource.
+// FIXME: the same definition is in user/user.h
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct Rlimit {
+    pub(crate) cur: u64,
+    pub(crate) max: u64,
+}
+
+impl Rlimit {
+    const fn new(v: u64) -> Rlimit {
+        Rlimit { cur: v, max: v }
+    }
+}
+
+/// Resource limits of an env.
+/// They are inherited by fork.
+#[derive(Clone, Copy)]
+pub(crate) struct Rlimits([Rlimit; RLIMIT_NLIMITS]);
+
+impl Rlimits {
+    pub(crate) fn new() -> Rlimits {
+        let mut limits = [Rlimit::new(0); RLIMIT_NLIMITS];
+        limits[RLIMIT_NOFILE] = Rlimit::new(NFILE_PER_ENV as u64);
+        limits[RLIMIT_HEAP] = Rlimit::new(UHEAPSIZE as u64);
+        limits[RLIMIT_STACK] = Rlimit::new(USTACKSIZE as u64);
+        limits[RLIMIT_NCHILD] = Rlimit::new(NCHILD_PER_ENV as u64);
+        limits[RLIMIT_CPU] = Rlimit::new(RLIM_INFINITY);
+        Rlimits(limits)
+    }
+
+    /// Return the soft limit of resource.
+    pub(crate) fn cur(&self, resource: usize) -> u64 {
+        self.0[resource].cur
+    }
+
+    pub(crate) fn get(&self, resource: usize) -> Result<Rlimit, SysError> {
+        self.0
+            .get(resource)
+            .map(|limit| *limit)
+            .ok_or(SysError::InvalidArg)
+    }
+
+    /// Change the limit of resource.
+    /// The soft limit cannot exceed the hard limit and
+    /// the hard limit can only be lowered.
+    pub(crate) fn set(&mut self, resource: usize, new_limit: Rlimit) -> Result<(), SysError> {
+        let limit = self.0.get_mut(resource).ok_or(SysError::InvalidArg)?;
+        if new_limit.cur > new_limit.max || new_limit.max > limit.max {
+            return Err(SysError::InvalidArg);
+        }
+        *limit = new_limit;
+        Ok(())
+    }
+}
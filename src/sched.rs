@@ -1,7 +1,7 @@
 use crate::env;
 use crate::env::EnvTable;
-use crate::mpconfig;
 use crate::spinlock::MutexGuard;
+use crate::{lapic, mpconfig, pmap};
 
 /// Choose a user environment to run and run it.
 pub(crate) fn sched_yield() -> ! {
@@ -32,11 +32,23 @@ pub(crate) fn sched_yield() -> ! {
     }
 }
 
+/// Halt this cpu until another cpu makes an env runnable.
+///
+/// The cpu is marked as halted before EnvTable is unlocked,
+/// so a cpu which makes an env runnable afterwards always sees it
+/// and sends an IPI (see wakeup_idle_cpu).
+/// The lapic timer is stopped to avoid useless wakeups while idle.
 pub(crate) fn sched_halt(table: MutexGuard<EnvTable>) -> ! {
+    #[cfg(feature = "debug")]
     println!("sched_halt: there is no runnable envs.");
-    drop(table);
 
-    let cpu = mpconfig::this_cpu();
+    let cpu = mpconfig::this_cpu_mut();
+    // Flush the TLB so that this cpu holds no stale entries while halted.
+    pmap::load_kern_pgdir();
+    cpu.tlb_flushed();
+    cpu.halted();
+    lapic::timer_stop();
+    drop(table);
     unsafe {
         llvm_asm!(
         "movl $0, %ebp; \
@@ -53,3 +65,15 @@ pub(crate) fn sched_halt(table: MutexGuard<EnvTable>) -> ! {
 
     panic!("sched_halt: should not be come here");
 }
+
+/// Wake up one of idle cpus (if any) to run a newly runnable env.
+/// Caller must hold the lock of EnvTable.
+pub(crate) fn wakeup_idle_cpu(_table: &EnvTable) {
+    let me = mpconfig::this_cpu().cpu_id;
+    for cpu in mpconfig::cpus() {
+        if cpu.cpu_id != me && cpu.is_halted() {
+            lapic::send_wakeup(cpu.cpu_id);
+            return;
+        }
+    }
+}
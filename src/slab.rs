@@ -0,0 +1,345 @@
+// Object caches for hot kernel objects.
+//
+// Each cache hands out objects of one fixed size. Free objects are kept in a shared depot
+// (a free list protected by a lock) and in per-cpu magazines, which can be used without
+// taking any lock because the kernel runs with interrupts disabled.
+// The memory of the depot comes from the large object area of the kernel heap.
+// A slab whose objects are all free is given back when the depot has enough free objects.
+
+use crate::allocator;
+use crate::constants::*;
+use crate::env::Env;
+use crate::lapic;
+use crate::mpconfig::consts::MAX_NUM_CPU;
+use crate::pmap::PageDirectory;
+use crate::spinlock::Mutex;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ptr::null_mut;
+
+const MAGAZINE_SIZE: usize = 16;
+// FIXME: the same definition is in user/user.h
+pub(crate) const NCACHES: usize = 9;
+pub(crate) const CACHE_NAME_LEN: usize = 12;
+
+// Number of free objects a depot keeps before giving back empty slabs
+const DEPOT_RESERVE: usize = MAGAZINE_SIZE;
+
+const KHEAP_PAGES: usize = KHEAP_SIZE / PGSIZE as usize;
+
+/// Box<Env> and Box<PageDirectory> have the layouts of their types, so they get their own caches.
+/// Objects shared by Arc (File, Inode, Pipe) are served by the size classes, because
+/// the layout of the allocation of Arc<T> is private to liballoc.
+/// The caches are searched in order and the first matching one is used.
+static CACHES: [ObjectCache; NCACHES] = [
+    ObjectCache::new::<Env>("env"),
+    ObjectCache::new::<PageDirectory>("pgdir"),
+    ObjectCache::with_size("size-16", 16),
+    ObjectCache::with_size("size-32", 32),
+    ObjectCache::with_size("size-64", 64),
+    ObjectCache::with_size("size-128", 128),
+    ObjectCache::with_size("size-256", 256),
+    ObjectCache::with_size("size-512", 512),
+    ObjectCache::with_size("size-1024", 1024),
+];
+
+/// Number of free objects of each slab indexed by the page number of the slab in the kernel heap.
+/// An entry is only touched with the depot lock of the cache owning the slab.
+struct SlabCounts(UnsafeCell<[u16; KHEAP_PAGES]>);
+
+unsafe impl Sync for SlabCounts {}
+
+static SLAB_COUNTS: SlabCounts = SlabCounts(UnsafeCell::new([0; KHEAP_PAGES]));
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Depot {
+    free_list: *mut FreeObject,
+    nfree: usize,
+    npages: usize,
+}
+
+impl Depot {
+    fn pop(&mut self) -> *mut u8 {
+        let obj = self.free_list;
+        if !obj.is_null() {
+            self.free_list = unsafe { (*obj).next };
+            self.nfree -= 1;
+        }
+        obj.cast()
+    }
+
+    fn push(&mut self, p: *mut u8) {
+        let obj = p.cast::<FreeObject>();
+        unsafe { (*obj).next = self.free_list };
+        self.free_list = obj;
+        self.nfree += 1;
+    }
+
+    /// Remove the free objects in [start, start + size) from the free list.
+    fn remove_range(&mut self, start: *mut u8, size: usize) {
+        let (start, end) = (start as usize, start as usize + size);
+        let mut link: *mut *mut FreeObject = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() {
+                let obj = *link;
+                if (obj as usize) >= start && (obj as usize) < end {
+                    *link = (*obj).next;
+                    self.nfree -= 1;
+                } else {
+                    link = &mut (*obj).next;
+                }
+            }
+        }
+    }
+}
+
+struct Magazine {
+    objs: [*mut u8; MAGAZINE_SIZE],
+    n: usize,
+    allocs: usize, // number of allocations on this cpu
+    frees: usize,  // number of frees on this cpu
+}
+
+impl Magazine {
+    const fn new() -> Magazine {
+        Magazine {
+            objs: [null_mut(); MAGAZINE_SIZE],
+            n: 0,
+            allocs: 0,
+            frees: 0,
+        }
+    }
+}
+
+pub(crate) struct ObjectCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    is_exact: bool,    // only serves the layout of (size, align)
+    obj_size: usize,   // size of an object including padding for alignment
+    slab_pages: usize, // pages of a slab
+    objs_per_slab: usize,
+    depot: Mutex<Depot>,
+    magazines: [UnsafeCell<Magazine>; MAX_NUM_CPU],
+}
+
+// Each magazine is only touched by its own cpu.
+unsafe impl Sync for ObjectCache {}
+
+/// Statistics of an object cache.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CacheStats {
+    pub(crate) name: &'static str,
+    pub(crate) obj_size: usize,
+    pub(crate) in_use: usize, // objects allocated and not freed yet
+    pub(crate) cached: usize, // free objects in the depot and magazines
+    pub(crate) pages: usize,  // pages owned by the cache
+    pub(crate) allocs: usize,
+    pub(crate) frees: usize,
+}
+
+impl ObjectCache {
+    /// Create a cache for Box<T>.
+    const fn new<T>(name: &'static str) -> ObjectCache {
+        ObjectCache::with_layout(name, mem::size_of::<T>(), mem::align_of::<T>(), true)
+    }
+
+    /// Create a cache for any layout up to size bytes aligned to at most size,
+    /// which must be a power of two.
+    const fn with_size(name: &'static str, size: usize) -> ObjectCache {
+        ObjectCache::with_layout(name, size, size, false)
+    }
+
+    const fn with_layout(
+        name: &'static str,
+        size: usize,
+        align: usize,
+        is_exact: bool,
+    ) -> ObjectCache {
+        let pgsize = PGSIZE as usize;
+        let obj_size = (size + align - 1) / align * align;
+        let slab_pages = (obj_size + pgsize - 1) / pgsize;
+        ObjectCache {
+            name,
+            size,
+            align,
+            is_exact,
+            obj_size,
+            slab_pages,
+            objs_per_slab: slab_pages * pgsize / obj_size,
+            depot: Mutex::new(Depot {
+                free_list: null_mut(),
+                nfree: 0,
+                npages: 0,
+            }),
+            magazines: [UnsafeCell::new(Magazine::new()); MAX_NUM_CPU],
+        }
+    }
+
+    fn matches(&self, layout: &Layout) -> bool {
+        if self.is_exact {
+            self.size == layout.size() && self.align == layout.align()
+        } else {
+            layout.size() <= self.size && layout.align() <= self.align
+        }
+    }
+
+    fn magazine(&self) -> &mut Magazine {
+        unsafe { &mut *self.magazines[lapic::cpu_num() as usize].get() }
+    }
+
+    /// Return the slab containing p.
+    /// A slab is either a single page or holds a single object.
+    fn slab_of(&self, p: *mut u8) -> *mut u8 {
+        if self.slab_pages == 1 {
+            (p as usize & !(PGSIZE as usize - 1)) as *mut u8
+        } else {
+            p
+        }
+    }
+
+    /// Return the number of free objects of slab.
+    /// The depot lock must be held.
+    fn slab_nfree(&self, slab: *mut u8) -> &mut u16 {
+        let i = (slab as usize - KHEAP_BASE as usize) / PGSIZE as usize;
+        unsafe { &mut (*SLAB_COUNTS.0.get())[i] }
+    }
+
+    /// Add a new slab to the depot.
+    fn grow(&self, depot: &mut Depot) -> bool {
+        let slab = allocator::alloc_pages(self.slab_pages);
+        if slab.is_null() {
+            return false;
+        }
+        depot.npages += self.slab_pages;
+        for i in 0..self.objs_per_slab {
+            depot.push(unsafe { slab.add(i * self.obj_size) });
+        }
+        *self.slab_nfree(slab) = self.objs_per_slab as u16;
+        true
+    }
+
+    fn get(&self, depot: &mut Depot) -> *mut u8 {
+        let p = depot.pop();
+        if !p.is_null() {
+            *self.slab_nfree(self.slab_of(p)) -= 1;
+        }
+        p
+    }
+
+    /// Put an object back to the depot. If all the objects of its slab are free
+    /// and the depot has enough free objects, give back the slab.
+    fn put(&self, depot: &mut Depot, p: *mut u8) {
+        depot.push(p);
+        let slab = self.slab_of(p);
+        let nfree = self.slab_nfree(slab);
+        *nfree += 1;
+        if *nfree as usize == self.objs_per_slab
+            && depot.nfree >= self.objs_per_slab + DEPOT_RESERVE
+        {
+            *nfree = 0;
+            depot.remove_range(slab, self.slab_pages * PGSIZE as usize);
+            depot.npages -= self.slab_pages;
+            allocator::free_pages(slab, self.slab_pages);
+        }
+    }
+
+    /// Move half a magazine of objects from the depot.
+    fn refill(&self, mag: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        if depot.nfree < MAGAZINE_SIZE / 2 {
+            self.grow(&mut depot);
+        }
+        while mag.n < MAGAZINE_SIZE / 2 {
+            let p = self.get(&mut depot);
+            if p.is_null() {
+                break;
+            }
+            mag.objs[mag.n] = p;
+            mag.n += 1;
+        }
+    }
+
+    /// Move half a magazine of objects to the depot.
+    fn flush(&self, mag: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        while mag.n > MAGAZINE_SIZE / 2 {
+            mag.n -= 1;
+            self.put(&mut depot, mag.objs[mag.n]);
+        }
+    }
+
+    fn alloc(&self) -> *mut u8 {
+        let mag = self.magazine();
+        if mag.n == 0 {
+            self.refill(mag);
+            if mag.n == 0 {
+                return null_mut();
+            }
+        }
+        mag.n -= 1;
+        mag.allocs += 1;
+        mag.objs[mag.n]
+    }
+
+    fn free(&self, p: *mut u8) {
+        let mag = self.magazine();
+        if mag.n == MAGAZINE_SIZE {
+            self.flush(mag);
+        }
+        mag.objs[mag.n] = p;
+        mag.n += 1;
+        mag.frees += 1;
+    }
+
+    /// Counters of magazines on other cpus are read without synchronization,
+    /// so the result is only a snapshot.
+    fn stats(&self) -> CacheStats {
+        let depot = self.depot.lock();
+        let mut stats = CacheStats {
+            name: self.name,
+            obj_size: self.obj_size,
+            in_use: 0,
+            cached: depot.nfree,
+            pages: depot.npages,
+            allocs: 0,
+            frees: 0,
+        };
+        for mag in self.magazines.iter() {
+            let mag = unsafe { &*mag.get() };
+            stats.cached += mag.n;
+            stats.allocs += mag.allocs;
+            stats.frees += mag.frees;
+        }
+        stats.in_use = stats.allocs.wrapping_sub(stats.frees);
+        stats
+    }
+}
+
+/// Allocate an object if there is a cache for layout.
+pub(crate) fn alloc(layout: &Layout) -> Option<*mut u8> {
+    CACHES
+        .iter()
+        .find(|cache| cache.matches(layout))
+        .map(|cache| cache.alloc())
+}
+
+/// Free an object and return true if there is a cache for layout.
+pub(crate) fn free(p: *mut u8, layout: &Layout) -> bool {
+    match CACHES.iter().find(|cache| cache.matches(layout)) {
+        None => false,
+        Some(cache) => {
+            cache.free(p);
+            true
+        }
+    }
+}
+
+/// Return statistics of all caches.
+pub(crate) fn cache_stats() -> impl Iterator<Item = CacheStats> {
+    CACHES.iter().map(ObjectCache::stats)
+}
@@ -1,11 +1,17 @@
-use crate::buf;
+use crate::constants::*;
+use crate::fs::DInode;
+use crate::ide;
 use crate::once::Once;
+use core::mem;
 
 // Disk layout:
 // [ boot block | super block | log | inode blocks | free bit map | data blocks ]
 //
 // fs/fsformat computes the super block and builds an initial file system.
 // The super block describes the disk layout.
+// It is always stored at SUPER_BLOCK_SECTOR so that it can be read before
+// the block size is known. If a block is larger than a sector, the super block
+// is in the boot block and block 1 is left unused.
 #[repr(C)]
 pub(crate) struct SuperBlock {
     pub(crate) size: u32,        // size of file system image (blocks)
@@ -15,6 +21,7 @@ pub(crate) struct SuperBlock {
     pub(crate) log_start: u32,   // block number of the first log block
     pub(crate) inode_start: u32, // block number of the first inode block
     pub(crate) bmap_start: u32,  // block number of the first free bit map block
+    pub(crate) bsize: u32,       // size of a block (bytes)
 }
 
 impl SuperBlock {
@@ -29,6 +36,7 @@ impl SuperBlock {
             log_start: 0,
             inode_start: 0,
             bmap_start: 0,
+            bsize: 0,
         }
     }
 
@@ -41,8 +49,43 @@ impl SuperBlock {
             log_start: sb.log_start,
             inode_start: sb.inode_start,
             bmap_start: sb.bmap_start,
+            bsize: sb.bsize,
         };
     }
+
+    /// Size of a block in bytes.
+    pub(crate) fn block_size(&self) -> usize {
+        self.bsize as usize
+    }
+
+    /// How many inodes a block has.
+    pub(crate) fn inodes_per_block(&self) -> usize {
+        self.block_size() / mem::size_of::<DInode>()
+    }
+
+    /// How many bits a bitmap block has.
+    pub(crate) fn bits_per_block(&self) -> usize {
+        self.block_size() * 8
+    }
+
+    /// How many block addresses an indirect block has.
+    pub(crate) fn nindirect(&self) -> usize {
+        self.block_size() / mem::size_of::<u32>()
+    }
+
+    /// Maximum size of a file in bytes.
+    /// The tree of indirect blocks can address more than u32 bytes with larger blocks,
+    /// so it is limited by the size field of the inode.
+    pub(crate) fn max_file_size(&self) -> u32 {
+        let nindirect = self.nindirect() as u64;
+        let mut nblocks = NDIRECT as u64;
+        let mut span = 1;
+        for _ in 0..NINDIRECT_LEVELS {
+            span *= nindirect;
+            nblocks += span;
+        }
+        core::cmp::min(nblocks * self.bsize as u64, u32::MAX as u64) as u32
+    }
 }
 
 static SUPER_BLOCK: Once<SuperBlock> = Once::new();
@@ -50,24 +93,26 @@ static SUPER_BLOCK: Once<SuperBlock> = Once::new();
 fn read_sb(dev: u32) -> SuperBlock {
     let mut sb = SuperBlock::empty();
 
-    let mut bcache = buf::buf_cache();
-    let mut b = bcache.get(dev, 1);
-    b.read();
-    let data = b.data();
+    // This bypasses the buffer cache because the size of buffers depends on the super block.
+    let mut data = [0u8; SECTOR_SIZE];
+    ide::ide_read_sectors(dev, SUPER_BLOCK_SECTOR, data.as_mut_ptr(), 1);
 
     let disk_sb = unsafe { &*data.as_ptr().cast::<SuperBlock>() };
     sb.init(disk_sb);
     println!(
-        "log_start: {}, inode_start: {}, bmap_start: {}",
-        sb.log_start, sb.inode_start, sb.bmap_start
+        "size: {}, bsize: {}, ninodes: {}, nlog: {}, log_start: {}, inode_start: {}, bmap_start: {}",
+        sb.size, sb.bsize, sb.ninodes, sb.nlog, sb.log_start, sb.inode_start, sb.bmap_start
     );
 
-    bcache.release(b);
+    let bsize = sb.block_size();
+    if bsize < MIN_BLK_SIZE || bsize > MAX_BLK_SIZE || !bsize.is_power_of_two() {
+        panic!("read_sb: unsupported block size {}", bsize);
+    }
 
     sb
 }
 
-/// Should be called only after ide_init and buf_init
+/// Should be called only after ide_init
 pub(crate) fn get() -> &'static SuperBlock {
     SUPER_BLOCK.call_once(|| read_sb(1))
 }
@@ -0,0 +1,320 @@
+// Swapping user pages out to the swap disk.
+//
+// When free pages run short, pages of envs which are not running are written
+// to slots of the swap disk. The victims are chosen by the clock (second chance)
+// algorithm: the hand sweeps over the user pages of the envs, and a page
+// referenced since the last sweep gets another chance.
+// A PTE of a swapped-out page keeps its slot number (see pmap::PTE::set_swapped),
+// and the page is read back by the page fault handler.
+//
+// Only envs which are not running are scanned because other cpus may cache
+// their mappings in TLBs. Such envs reload cr3 (and flush TLBs) in env_run.
+//
+// EnvTable is locked only while a victim is chosen and unmapped. The page is written
+// after the lock is released, so the slot is registered as a writeback of the cpu
+// until the write completes. A page fault on the slot in the meantime maps the page again.
+
+use crate::constants::*;
+use crate::env;
+use crate::ide;
+use crate::lapic;
+use crate::mpconfig::consts::MAX_NUM_CPU;
+use crate::pmap::{self, PhysAddr, VirtAddr};
+use crate::spinlock::Mutex;
+use crate::util;
+
+const SECTORS_PER_PAGE: usize = PGSIZE as usize / SECTOR_SIZE;
+const BITMAP_WORDS: usize = SWAP_PAGES / 32;
+
+/// Statistics of swapping.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SwapStats {
+    pub(crate) total_slots: usize,
+    pub(crate) used_slots: usize,
+    pub(crate) swap_ins: usize,  // number of pages read from the swap disk
+    pub(crate) swap_outs: usize, // number of pages written to the swap disk
+}
+
+/// A page being written to a slot. It holds a reference to the page.
+#[derive(Clone, Copy)]
+struct Writeback {
+    slot: usize,
+    pa: PhysAddr,
+    is_slot_freed: bool, // the slot is freed when the write completes
+    is_remapped: bool,   // the reference to the page was passed to the PTE again
+}
+
+struct SwapArea {
+    enabled: bool,
+    used: [u32; BITMAP_WORDS], // slots in use
+    nused: usize,
+    hand_env: usize,  // index of EnvTable the clock hand points
+    hand_va: VirtAddr, // user address the clock hand points
+    writebacks: [Option<Writeback>; MAX_NUM_CPU], // a page being written by each cpu
+    swap_ins: usize,
+    swap_outs: usize,
+}
+
+impl SwapArea {
+    fn alloc_slot(&mut self) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+        let w = (0..BITMAP_WORDS).find(|&w| self.used[w] != 0xffffffff)?;
+        let b = (!self.used[w]).trailing_zeros() as usize;
+        self.used[w] |= 1 << b;
+        self.nused += 1;
+        Some(w * 32 + b)
+    }
+
+    fn free_slot(&mut self, slot: usize) {
+        assert!(
+            self.used[slot / 32] & (1 << (slot % 32)) != 0,
+            "free_slot: slot {} is not used",
+            slot
+        );
+        self.used[slot / 32] &= !(1 << (slot % 32));
+        self.nused -= 1;
+    }
+
+    fn writeback(&mut self, slot: usize) -> Option<&mut Writeback> {
+        self.writebacks
+            .iter_mut()
+            .filter_map(|wb| wb.as_mut())
+            .find(|wb| wb.slot == slot)
+    }
+}
+
+static SWAP: Mutex<SwapArea> = Mutex::new(SwapArea {
+    enabled: false,
+    used: [0; BITMAP_WORDS],
+    nused: 0,
+    hand_env: 0,
+    hand_va: VirtAddr(0),
+    writebacks: [None; MAX_NUM_CPU],
+    swap_ins: 0,
+    swap_outs: 0,
+});
+
+/// Enable swapping if the swap disk exists.
+pub(crate) fn swap_init() {
+    if ide::ide_probe_swap() {
+        let mut swap = SWAP.lock();
+        swap.enabled = true;
+    }
+}
+
+fn slot_sector(slot: usize) -> u32 {
+    assert!(slot < SWAP_PAGES, "illegal swap slot");
+    (slot * SECTORS_PER_PAGE) as u32
+}
+
+/// Read the page in the slot into va (a kernel address).
+fn read_slot(slot: usize, va: VirtAddr) {
+    ide::ide_read_sectors(SWAP_DEV, slot_sector(slot), va.as_mut_ptr(), SECTORS_PER_PAGE);
+}
+
+fn write_slot(slot: usize, va: VirtAddr) {
+    ide::ide_write_sectors(SWAP_DEV, slot_sector(slot), va.as_ptr(), SECTORS_PER_PAGE);
+}
+
+/// Read the page in the slot into va (a kernel address) like read_slot,
+/// but copy the page instead if it is still being written.
+pub(crate) fn read_page(slot: usize, va: VirtAddr) {
+    {
+        let mut swap = SWAP.lock();
+        if let Some(wb) = swap.writeback(slot) {
+            let page = pmap::kmap(wb.pa);
+            unsafe { util::memmove(va, page.va(), PGSIZE as usize) };
+            return;
+        }
+    }
+    read_slot(slot, va);
+}
+
+pub(crate) fn free_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    // A slot being written is freed when the write completes.
+    match swap.writeback(slot) {
+        Some(wb) => wb.is_slot_freed = true,
+        None => swap.free_slot(slot),
+    }
+}
+
+/// Swap out up to npages pages of envs which are not running.
+/// Return the number of pages actually released.
+///
+/// If can_block is false, give up instead of waiting for EnvTable,
+/// which may be locked by this cpu.
+pub(crate) fn reclaim(npages: usize, can_block: bool) -> usize {
+    let mut reclaimed = 0;
+    while reclaimed < npages {
+        let (slot, pa) = match start_writeback(can_block) {
+            None => break,
+            Some(res) => res,
+        };
+
+        // The page is not mapped by any env now, so its content is stable.
+        let page = pmap::kmap(pa);
+        write_slot(slot, page.va());
+        drop(page);
+
+        finish_writeback();
+        reclaimed += 1;
+    }
+    reclaimed
+}
+
+/// Choose a victim page by the clock algorithm, unmap it, and register it
+/// as the writeback of this cpu. Return the slot and the page.
+fn start_writeback(can_block: bool) -> Option<(usize, PhysAddr)> {
+    let mut env_table = if can_block {
+        env::env_table()
+    } else {
+        env::try_env_table()?
+    };
+    let (mut hand_env, mut hand_va) = {
+        let swap = SWAP.lock();
+        if !swap.enabled {
+            return None;
+        }
+        (swap.hand_env, swap.hand_va)
+    };
+
+    // Two rounds are enough to find pages whose accessed bits are cleared in the first round.
+    let mut visited = 0;
+    while visited <= 2 * env::NENV as usize {
+        if let Some(pgdir) = env_table.swappable_pgdir(hand_env) {
+            if let Some(va) = pgdir.clock_scan(&mut hand_va) {
+                let slot = match SWAP.lock().alloc_slot() {
+                    None => {
+                        // The swap disk is full.
+                        save_hand(hand_env, hand_va);
+                        return None;
+                    }
+                    Some(slot) => slot,
+                };
+                let pa = pgdir.start_swap_out(va, slot);
+
+                // The env cannot run until EnvTable is unlocked, so no one sees
+                // the slot before it is registered.
+                let mut swap = SWAP.lock();
+                swap.writebacks[lapic::cpu_num() as usize] = Some(Writeback {
+                    slot,
+                    pa,
+                    is_slot_freed: false,
+                    is_remapped: false,
+                });
+                swap.hand_env = hand_env;
+                swap.hand_va = hand_va;
+                return Some((slot, pa));
+            }
+        }
+        hand_env = (hand_env + 1) % env::NENV as usize;
+        hand_va = VirtAddr(0);
+        visited += 1;
+    }
+
+    save_hand(hand_env, hand_va);
+    None
+}
+
+/// Unregister the writeback of this cpu and release the page.
+fn finish_writeback() {
+    let wb = {
+        let mut swap = SWAP.lock();
+        let wb = swap.writebacks[lapic::cpu_num() as usize]
+            .take()
+            .expect("finish_writeback: no writeback");
+        if wb.is_slot_freed {
+            swap.free_slot(wb.slot);
+        }
+        swap.swap_outs += 1;
+        wb
+    };
+    if !wb.is_remapped {
+        pmap::put_user_page(wb.pa);
+    }
+}
+
+fn save_hand(hand_env: usize, hand_va: VirtAddr) {
+    let mut swap = SWAP.lock();
+    swap.hand_env = hand_env;
+    swap.hand_va = hand_va;
+}
+
+/// Make sure that npages pages (in addition to the reserve) can be allocated
+/// by swapping out pages if necessary.
+/// EnvTable must not be locked by this cpu.
+pub(crate) fn ensure_free_pages(npages: usize) {
+    let required = npages + SWAP_RESERVE_PAGES;
+    let free = pmap::free_page_count();
+    if free < required {
+        reclaim(required - free, true);
+    }
+}
+
+/// Handle a page fault of the current env at va.
+/// Return true if the page was swapped out and is now brought back.
+///
+/// from_user is false if the kernel touched the user page (e.g. a syscall argument).
+/// Then EnvTable may be locked by this cpu, so we do not wait for the lock
+/// to swap out other pages.
+pub(crate) fn page_fault(va: VirtAddr, from_user: bool) -> bool {
+    if va >= VirtAddr(UTOP) {
+        return false;
+    }
+    let env = match env::cur_env_mut() {
+        None => return false,
+        Some(env) => env,
+    };
+    let pgdir = env.get_pgdir_mut();
+    let va = va.round_down(PGSIZE as usize);
+    let slot = match pgdir.swapped_slot(va) {
+        None => return false,
+        Some(slot) => slot,
+    };
+
+    // The page may still be being written by another cpu. Then take it back.
+    let remapped = SWAP.lock().writeback(slot).map(|wb| {
+        wb.is_slot_freed = true;
+        wb.is_remapped = true;
+        wb.pa
+    });
+    if let Some(pa) = remapped {
+        pgdir.swap_in(va, pa);
+        // swap_in took a reference for the PTE, so drop the one of the writeback.
+        pmap::put_user_page(pa);
+        return true;
+    }
+
+    let mut pa = pmap::alloc_user_page();
+    if pa.is_none() && from_user {
+        ensure_free_pages(1);
+        pa = pmap::alloc_user_page();
+    }
+    let pa = match pa {
+        None => return false,
+        Some(pa) => pa,
+    };
+
+    let page = pmap::kmap(pa);
+    read_slot(slot, page.va());
+    drop(page);
+    pgdir.swap_in(va, pa);
+
+    let mut swap = SWAP.lock();
+    swap.free_slot(slot);
+    swap.swap_ins += 1;
+    true
+}
+
+pub(crate) fn swap_stats() -> SwapStats {
+    let swap = SWAP.lock();
+    SwapStats {
+        total_slots: if swap.enabled { SWAP_PAGES } else { 0 },
+        used_slots: swap.nused,
+        swap_ins: swap.swap_ins,
+        swap_outs: swap.swap_outs,
+    }
+}
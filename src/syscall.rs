@@ -1,17 +1,22 @@
 // This file comes from kern/syscall.c in jos. See COPYRIGHT for copyright information.
 
-use crate::constants::{SysError, MAX_PATH_LEN, PTE_W};
-use crate::env::EnvId;
-use crate::file::FileDescriptor;
+use crate::constants::{SysError, MAX_CMD_ARGS_SIZE, MAX_CMD_ARG_LEN, MAX_PATH_LEN};
+use crate::env::{EnvId, EnvInfo, Rusage};
+use crate::file::{FileDescriptor, FileTableEntry};
 use crate::fs::Stat;
-use crate::pmap::VirtAddr;
-use crate::{env, sysfile};
-use crate::{sched, util};
+use crate::rlimit::Rlimit;
+use crate::sched;
+use crate::sysinfo::Sysinfo;
+use crate::{env, sysfile, sysinfo, uaccess};
+use alloc::vec;
 use alloc::vec::Vec;
 use consts::*;
-use core::mem;
-use core::ptr::null;
-use core::str;
+use core::cmp;
+
+// Size of kernel buffers on the stack for read, write and getdents.
+// A larger buffer on the heap would come from the large object area and
+// map and unmap pages on every call.
+const KBUF_SIZE: usize = 1024;
 
 // FIXME: the same definition is in user/lib/syscall.c
 mod consts {
@@ -36,6 +41,23 @@ mod consts {
     pub(crate) static SYS_MKDIR: u32 = 18;
     pub(crate) static SYS_CHDIR: u32 = 19;
     pub(crate) static SYS_PIPE: u32 = 20;
+    pub(crate) static SYS_GETRUSAGE: u32 = 21;
+    pub(crate) static SYS_ENV_LIST: u32 = 22;
+    pub(crate) static SYS_GETRLIMIT: u32 = 23;
+    pub(crate) static SYS_SETRLIMIT: u32 = 24;
+    pub(crate) static SYS_SYSINFO: u32 = 25;
+    pub(crate) static SYS_LINK: u32 = 26;
+    pub(crate) static SYS_UNLINK: u32 = 27;
+    pub(crate) static SYS_RMDIR: u32 = 28;
+    pub(crate) static SYS_RENAME: u32 = 29;
+    pub(crate) static SYS_SYMLINK: u32 = 30;
+    pub(crate) static SYS_READLINK: u32 = 31;
+    pub(crate) static SYS_LSTAT: u32 = 32;
+    pub(crate) static SYS_LSEEK: u32 = 33;
+    pub(crate) static SYS_PREAD: u32 = 34;
+    pub(crate) static SYS_PWRITE: u32 = 35;
+    pub(crate) static SYS_FTRUNCATE: u32 = 36;
+    pub(crate) static SYS_GETDENTS: u32 = 37;
 }
 
 pub(crate) fn str_error(err: SysError) -> &'static str {
@@ -51,13 +73,19 @@ pub(crate) fn str_error(err: SysError) -> &'static str {
         SysError::TryAgain => "try again",
         SysError::BrokenPipe => "broken pipe",
         SysError::NotChild => "not child process",
+        SysError::LimitExceeded => "resource limit exceeded",
+        SysError::NoMem => "out of memory",
+        SysError::NoFreeEnv => "no free env",
+        SysError::Fault => "bad address",
+        SysError::NotEmpty => "directory not empty",
+        SysError::Exists => "file exists",
+        SysError::Loop => "too many levels of symbolic links",
+        SysError::TooLarge => "file too large",
+        SysError::NameTooLong => "file name too long",
+        SysError::Busy => "device or resource busy",
     }
 }
 
-fn sys_cputs(s: &str) {
-    print!("{}", s);
-}
-
 fn sys_yield() {
     sched::sched_yield();
 }
@@ -67,35 +95,191 @@ fn sys_get_env_id() -> EnvId {
     cur_env.get_env_id()
 }
 
-fn sys_fork() -> EnvId {
+fn sys_fork() -> Result<EnvId, SysError> {
     let cur_env = env::cur_env_mut().unwrap();
     env::fork(cur_env)
 }
 
-fn sys_write(fd: FileDescriptor, buf: *const u8, len: usize) -> i32 {
-    match env::cur_env_mut().unwrap().fd_get(fd) {
-        None => SysError::IllegalFileDescriptor.err_no(),
-        Some(ent) => {
-            let mut f = ent.file.write();
-            match f.write(buf, len) {
-                Err(err) => err.err_no(),
-                Ok(cnt) => cnt as i32,
-            }
+/// Read from fd into the user buffer through a kernel buffer.
+/// If off is given, read there without moving the offset of fd.
+fn sys_read(
+    fd: FileDescriptor,
+    buf: *mut u8,
+    count: usize,
+    off: Option<u32>,
+) -> Result<usize, SysError> {
+    let ent = env::cur_env_mut()
+        .unwrap()
+        .fd_get(fd)
+        .ok_or(SysError::IllegalFileDescriptor)?;
+    uaccess::check_out(buf, count)?;
+    let mut kbuf = [0 as u8; KBUF_SIZE];
+
+    let mut total = 0;
+    while total < count {
+        let n = cmp::min(count - total, kbuf.len());
+        let res = match off {
+            None => ent.file.write().read(kbuf.as_mut_ptr(), n),
+            Some(off) => ent
+                .file
+                .read()
+                .pread(kbuf.as_mut_ptr(), n, off + total as u32),
+        };
+        let cnt = match res {
+            Err(err) if total == 0 => return Err(err),
+            Err(_) => break,
+            Ok(cnt) => cnt,
+        };
+        // The data read so far is returned even if the rest cannot be copied.
+        match uaccess::copy_out(buf.wrapping_add(total), &kbuf[..cnt]) {
+            Err(err) if total == 0 => return Err(err),
+            Err(_) => break,
+            Ok(()) => (),
+        }
+        total += cnt;
+        if cnt < n {
+            break;
         }
     }
+    Ok(total)
 }
 
-/// Check a system call argument for path.
-/// It should be in user space and less than MAX_CMD_ARG_LEN.
-/// If check fails, the functino doesn't return.
-fn path_check(arg: *const u8) {
-    let curenv = env::cur_env_mut().expect("curenv should be exist");
-    let len = util::strnlen(arg, MAX_PATH_LEN + 1);
-    if len > MAX_PATH_LEN {
-        let env_table = env::env_table();
-        env::env_destroy(curenv.get_env_id(), env_table);
+/// Write the user buffer to fd through a kernel buffer.
+/// If off is given, write there without moving the offset of fd.
+fn sys_write(
+    fd: FileDescriptor,
+    buf: *const u8,
+    count: usize,
+    off: Option<u32>,
+) -> Result<usize, SysError> {
+    let ent = env::cur_env_mut()
+        .unwrap()
+        .fd_get(fd)
+        .ok_or(SysError::IllegalFileDescriptor)?;
+
+    // The chunks of an append must not be interleaved with other appends.
+    if off.is_none() {
+        ent.file.read().begin_write();
+    }
+    let res = write_chunks(ent, buf, count, off);
+    if off.is_none() {
+        ent.file.read().end_write();
     }
-    env::user_mem_assert(curenv, VirtAddr(arg as u32), len, 0);
+    res
+}
+
+/// Write count bytes from buf to the file of ent a chunk at a time
+/// through a kernel buffer.
+fn write_chunks(
+    ent: &FileTableEntry,
+    buf: *const u8,
+    count: usize,
+    off: Option<u32>,
+) -> Result<usize, SysError> {
+    let mut kbuf = [0 as u8; KBUF_SIZE];
+
+    let mut total = 0;
+    while total < count {
+        let n = cmp::min(count - total, kbuf.len());
+        match uaccess::copy_in(&mut kbuf[..n], buf.wrapping_add(total)) {
+            Err(err) if total == 0 => return Err(err),
+            Err(_) => break,
+            Ok(()) => (),
+        }
+        let res = match off {
+            None => ent.file.write().write(kbuf.as_ptr(), n),
+            Some(off) => ent.file.read().pwrite(kbuf.as_ptr(), n, off + total as u32),
+        };
+        let cnt = match res {
+            Err(err) if total == 0 => return Err(err),
+            Err(_) => break,
+            Ok(cnt) => cnt,
+        };
+        total += cnt;
+        if cnt < n {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// Copy a path from user space.
+/// It should be shorter than MAX_PATH_LEN, or SysError::NameTooLong is returned.
+/// The buffer is on the heap because it is too large for the kernel stack,
+/// but only as large as the path.
+fn copy_path(arg: *const u8) -> Result<Vec<u8>, SysError> {
+    let len = uaccess::strnlen_user(arg, MAX_PATH_LEN + 1).map_err(|err| match err {
+        SysError::InvalidArg => SysError::NameTooLong,
+        err => err,
+    })?;
+    let mut path = vec![0; len + 1];
+    uaccess::copy_in(&mut path[..len], arg)?;
+    Ok(path)
+}
+
+fn sys_exec(path: *const u8, args: &[*const u8]) -> Result<(), SysError> {
+    let path = copy_path(path)?;
+    let mut argv_bufs = Vec::new();
+    let mut total = 0;
+    for arg in args.iter().filter(|arg| !arg.is_null()) {
+        let mut buf = vec![0 as u8; MAX_CMD_ARG_LEN + 1];
+        let len = uaccess::strncpy_from_user(&mut buf, *arg)?;
+        // All arguments must fit in the user stack with room left for the program.
+        total += len + 1;
+        if total > MAX_CMD_ARGS_SIZE {
+            return Err(SysError::LimitExceeded);
+        }
+        argv_bufs.push(buf);
+    }
+    let argv: Vec<*const u8> = argv_bufs.iter().map(|buf| buf.as_ptr()).collect();
+    sysfile::exec(path.as_ptr(), &argv[..])
+}
+
+fn sys_getcwd(buf: *mut u8, size: usize) -> Result<usize, SysError> {
+    let mut kbuf = vec![0 as u8; MAX_PATH_LEN + 1];
+    let ksize = cmp::min(size, kbuf.len());
+    let len = sysfile::getcwd(kbuf.as_mut_ptr(), ksize)?;
+    uaccess::copy_out(buf, &kbuf[..(len + 1)])?;
+    Ok(len)
+}
+
+fn sys_readlink(path: *const u8, buf: *mut u8, size: usize) -> Result<usize, SysError> {
+    let path = copy_path(path)?;
+    let target = sysfile::readlink(path.as_ptr())?;
+    let len = cmp::min(size, target.len());
+    uaccess::copy_out(buf, &target[..len])?;
+    Ok(len)
+}
+
+fn sys_getdents(fd: FileDescriptor, buf: *mut u8, size: usize) -> Result<usize, SysError> {
+    let ent = env::cur_env_mut()
+        .unwrap()
+        .fd_get(fd)
+        .ok_or(SysError::IllegalFileDescriptor)?;
+    uaccess::check_out(buf, size)?;
+    let mut kbuf = [0 as u8; KBUF_SIZE];
+    let kbuf = &mut kbuf[..cmp::min(size, KBUF_SIZE)];
+    let len = ent.file.write().getdents(kbuf)?;
+    uaccess::copy_out(buf, &kbuf[..len])?;
+    Ok(len)
+}
+
+fn sys_pipe(fds: *mut [FileDescriptor; 2]) -> Result<(), SysError> {
+    let (fd0, fd1) = sysfile::pipe()?;
+    if let Err(err) = uaccess::copy_to_user(fds, &[fd0, fd1]) {
+        sysfile::close(fd0)?;
+        sysfile::close(fd1)?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn sys_env_list(buf: *mut EnvInfo, n: usize) -> Result<usize, SysError> {
+    let infos = env::env_list(n);
+    for (i, info) in infos.iter().enumerate() {
+        uaccess::copy_to_user(buf.wrapping_add(i), info)?;
+    }
+    Ok(infos.len())
 }
 
 /// Dispatched to the correct kernel function, passing the arguments.
@@ -104,9 +288,10 @@ pub(crate) unsafe fn syscall(syscall_no: u32, a1: u32, a2: u32, a3: u32, a4: u32
         // SYS_CPUTS is deprecated, use SYS_WRITE instead.
         let raw_s = a1 as *const u8;
         let len = a2 as usize;
-        let curenv = env::cur_env_mut().expect("curenv should exist");
-        env::user_mem_assert(curenv, VirtAddr(raw_s as u32), len, 0);
-        sys_write(FileDescriptor(1), raw_s, len)
+        match sys_write(FileDescriptor(1), raw_s, len, None) {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
+        }
     } else if syscall_no == SYS_EXIT {
         let _status = a1 as i32;
         let curenv = env::cur_env_mut().expect("curenv should exist");
@@ -124,8 +309,10 @@ pub(crate) unsafe fn syscall(syscall_no: u32, a1: u32, a2: u32, a3: u32, a4: u32
     } else if syscall_no == SYS_FORK {
         #[cfg(feature = "debug")]
         println!("free_page_count before fork: {}", pmap::free_page_count());
-        let env_id = sys_fork();
-        env_id.0 as i32
+        match sys_fork() {
+            Err(err) => err.err_no(),
+            Ok(env_id) => env_id.0 as i32,
+        }
     } else if syscall_no == SYS_KILL {
         let env_id = EnvId(a1);
         let env_table = env::env_table();
@@ -135,28 +322,20 @@ pub(crate) unsafe fn syscall(syscall_no: u32, a1: u32, a2: u32, a3: u32, a4: u32
         #[cfg(feature = "debug")]
         println!("free_page_count before fork: {}", pmap::free_page_count());
         let path = a1 as *const u8;
-        path_check(path);
         let arg_arr = [
             a2 as *const u8,
             a3 as *const u8,
             a4 as *const u8,
             a5 as *const u8,
         ];
-        let mut arg_vec = Vec::new();
-        for v in arg_arr.iter() {
-            if *v != null() {
-                arg_vec.push(*v);
-            }
-        }
-        match sysfile::exec(path, &arg_vec[..]) {
+        match sys_exec(path, &arg_arr) {
             Err(err) => err.err_no(),
             Ok(_) => 0,
         }
     } else if syscall_no == SYS_OPEN {
-        let path = a1 as *const u8;
-        path_check(path);
         let mode = a2 as u32;
-        sysfile::open(path, mode)
+        copy_path(a1 as *const u8)
+            .and_then(|path| sysfile::open(path.as_ptr(), mode))
             .map(|fd| fd.0 as i32)
             .unwrap_or_else(|err| err.err_no())
     } else if syscall_no == SYS_CLOSE {
@@ -169,35 +348,24 @@ pub(crate) unsafe fn syscall(syscall_no: u32, a1: u32, a2: u32, a3: u32, a4: u32
         let fd = FileDescriptor(a1 as u32);
         let buf = a2 as *mut u8;
         let count = a3 as usize;
-
-        let curenv = env::cur_env_mut().expect("curenv should exist");
-        env::user_mem_assert(curenv, VirtAddr(buf as u32), count, 0);
-
-        match env::cur_env_mut().unwrap().fd_get(fd) {
-            None => SysError::IllegalFileDescriptor.err_no(),
-            Some(ent) => {
-                let mut f = ent.file.write();
-                match f.read(buf, count) {
-                    Err(err) => err.err_no(),
-                    Ok(cnt) => cnt as i32,
-                }
-            }
+        match sys_read(fd, buf, count, None) {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
         }
     } else if syscall_no == SYS_WRITE {
         let fd = FileDescriptor(a1 as u32);
-        let buf = a2 as *mut u8;
+        let buf = a2 as *const u8;
         let count = a3 as usize;
-
-        let curenv = env::cur_env_mut().expect("curenv should exist");
-        env::user_mem_assert(curenv, VirtAddr(buf as u32), count, PTE_W);
-
-        sys_write(fd, buf, count)
+        match sys_write(fd, buf, count, None) {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
+        }
     } else if syscall_no == SYS_MKNOD {
-        let path = a1 as *const u8;
-        path_check(path);
         let major = a2 as u16;
         let minor = a2 as u16;
-        match sysfile::mknod(path, major, minor) {
+        match copy_path(a1 as *const u8)
+            .and_then(|path| sysfile::mknod(path.as_ptr(), major, minor))
+        {
             Err(err) => err.err_no(),
             Ok(_) => 0,
         }
@@ -214,62 +382,180 @@ pub(crate) unsafe fn syscall(syscall_no: u32, a1: u32, a2: u32, a3: u32, a4: u32
         }
     } else if syscall_no == SYS_SBRK {
         let nbytes = a1 as usize;
-        let p = env::sbrk(nbytes);
-        if p.is_null() {
-            SysError::Unspecified.err_no()
-        } else {
-            p as i32
+        match env::sbrk(nbytes) {
+            Err(err) => err.err_no(),
+            Ok(p) => p as i32,
         }
     } else if syscall_no == SYS_FSTAT {
         let fd = FileDescriptor(a1);
-        let statbuf = {
-            let p = a2 as *mut Stat;
-            let curenv = env::cur_env_mut().expect("curenv should exist");
-            let len = mem::size_of::<Stat>();
-            env::user_mem_assert(curenv, VirtAddr(p as u32), len, PTE_W);
-            &mut *p
-        };
-        match sysfile::stat(fd) {
+        let statbuf = a2 as *mut Stat;
+        match sysfile::stat(fd).and_then(|stat| uaccess::copy_to_user(statbuf, &stat)) {
             Err(err) => err.err_no(),
-            Ok(stat) => {
-                *statbuf = stat;
-                0
-            }
+            Ok(_) => 0,
         }
     } else if syscall_no == SYS_GETCWD {
         let buf = a1 as *mut u8;
         let size = a2 as usize;
-
-        let curenv = env::cur_env_mut().expect("curenv should exist");
-        env::user_mem_assert(curenv, VirtAddr(buf as u32), size, PTE_W);
-
-        match sysfile::getcwd(buf, size) {
+        match sys_getcwd(buf, size) {
             Err(err) => err.err_no(),
             Ok(len) => len as i32,
         }
     } else if syscall_no == SYS_MKDIR {
-        let path = a1 as *const u8;
-        path_check(path);
-        match sysfile::mkdir(path) {
+        match copy_path(a1 as *const u8).and_then(|path| sysfile::mkdir(path.as_ptr())) {
             Err(err) => err.err_no(),
             Ok(_) => 0,
         }
     } else if syscall_no == SYS_CHDIR {
-        let path = a1 as *const u8;
-        path_check(path);
-        match sysfile::chdir(path) {
+        match copy_path(a1 as *const u8).and_then(|path| sysfile::chdir(path.as_ptr())) {
             Err(err) => err.err_no(),
             Ok(_) => 0,
         }
     } else if syscall_no == SYS_PIPE {
-        let fds = &mut *(a1 as *mut [FileDescriptor; 2]);
-        match sysfile::pipe() {
+        let fds = a1 as *mut [FileDescriptor; 2];
+        match sys_pipe(fds) {
             Err(err) => err.err_no(),
-            Ok((fd0, fd1)) => {
-                fds[0] = fd0;
-                fds[1] = fd1;
-                0
-            }
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_GETRUSAGE {
+        let who = a1 as i32;
+        let usage = a2 as *mut Rusage;
+        match env::getrusage(who).and_then(|res| uaccess::copy_to_user(usage, &res)) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_ENV_LIST {
+        let buf = a1 as *mut EnvInfo;
+        let n = a2 as usize;
+        match sys_env_list(buf, n) {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
+        }
+    } else if syscall_no == SYS_GETRLIMIT {
+        let resource = a1 as usize;
+        let limit = a2 as *mut Rlimit;
+        match env::getrlimit(resource).and_then(|res| uaccess::copy_to_user(limit, &res)) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_SETRLIMIT {
+        let resource = a1 as usize;
+        let limit = a2 as *const Rlimit;
+        match uaccess::copy_from_user(limit).and_then(|limit| env::setrlimit(resource, limit)) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_SYSINFO {
+        let info = a1 as *mut Sysinfo;
+        match uaccess::copy_to_user(info, &sysinfo::sysinfo()) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_LINK {
+        let res = copy_path(a1 as *const u8).and_then(|old| {
+            let new = copy_path(a2 as *const u8)?;
+            sysfile::link(new.as_ptr(), old.as_ptr())
+        });
+        match res {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_UNLINK {
+        match copy_path(a1 as *const u8).and_then(|path| sysfile::unlink(path.as_ptr())) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_RMDIR {
+        match copy_path(a1 as *const u8).and_then(|path| sysfile::rmdir(path.as_ptr())) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_RENAME {
+        let res = copy_path(a1 as *const u8).and_then(|old| {
+            let new = copy_path(a2 as *const u8)?;
+            sysfile::rename(old.as_ptr(), new.as_ptr())
+        });
+        match res {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_SYMLINK {
+        let res = copy_path(a1 as *const u8).and_then(|target| {
+            let path = copy_path(a2 as *const u8)?;
+            sysfile::symlink(target.as_ptr(), path.as_ptr())
+        });
+        match res {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_READLINK {
+        let buf = a2 as *mut u8;
+        let size = a3 as usize;
+        match sys_readlink(a1 as *const u8, buf, size) {
+            Err(err) => err.err_no(),
+            Ok(len) => len as i32,
+        }
+    } else if syscall_no == SYS_LSEEK {
+        let fd = FileDescriptor(a1);
+        let offset = a2 as i32;
+        let whence = a3;
+        match sysfile::lseek(fd, offset, whence) {
+            Err(err) => err.err_no(),
+            Ok(off) => off as i32,
+        }
+    } else if syscall_no == SYS_PREAD {
+        let fd = FileDescriptor(a1);
+        let buf = a2 as *mut u8;
+        let count = a3 as usize;
+        let off = a4 as i32;
+        let res = if off < 0 {
+            Err(SysError::InvalidArg)
+        } else {
+            sys_read(fd, buf, count, Some(off as u32))
+        };
+        match res {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
+        }
+    } else if syscall_no == SYS_PWRITE {
+        let fd = FileDescriptor(a1);
+        let buf = a2 as *const u8;
+        let count = a3 as usize;
+        let off = a4 as i32;
+        let res = if off < 0 {
+            Err(SysError::InvalidArg)
+        } else {
+            sys_write(fd, buf, count, Some(off as u32))
+        };
+        match res {
+            Err(err) => err.err_no(),
+            Ok(cnt) => cnt as i32,
+        }
+    } else if syscall_no == SYS_FTRUNCATE {
+        let fd = FileDescriptor(a1);
+        let len = a2 as i32;
+        let res = if len < 0 {
+            Err(SysError::InvalidArg)
+        } else {
+            sysfile::ftruncate(fd, len as u32)
+        };
+        match res {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
+        }
+    } else if syscall_no == SYS_GETDENTS {
+        let fd = FileDescriptor(a1);
+        let buf = a2 as *mut u8;
+        let size = a3 as usize;
+        match sys_getdents(fd, buf, size) {
+            Err(err) => err.err_no(),
+            Ok(len) => len as i32,
+        }
+    } else if syscall_no == SYS_LSTAT {
+        let statbuf = a2 as *mut Stat;
+        let res = copy_path(a1 as *const u8).and_then(|path| sysfile::lstat(path.as_ptr()));
+        match res.and_then(|stat| uaccess::copy_to_user(statbuf, &stat)) {
+            Err(err) => err.err_no(),
+            Ok(_) => 0,
         }
     } else {
         panic!("unknown syscall");
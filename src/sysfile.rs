@@ -1,37 +1,51 @@
 use crate::constants::*;
 use crate::file::{FileDescriptor, FileTableEntry};
 use crate::fs::{DirEnt, Inode, InodeType, Stat};
-use crate::pmap::VirtAddr;
 use crate::rwlock::RwLock;
-use crate::{env, file, fs, log, pipe, util};
+use crate::spinlock::Mutex;
+use crate::{env, file, fs, log, pipe, superblock, util};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use consts::*;
+use core::cmp;
 use core::ops::Try;
-use core::ptr::{null, null_mut};
-use core::{cmp, mem};
+use core::ptr::null_mut;
+use core::slice;
 
 pub(crate) mod consts {
     pub(crate) const O_RDONLY: u32 = 0x000;
     pub(crate) const O_WRONLY: u32 = 0x001;
     pub(crate) const O_RDWR: u32 = 0x002;
+    pub(crate) const O_APPEND: u32 = 0x004;
     pub(crate) const O_CREATE: u32 = 0x200;
+    pub(crate) const O_TRUNC: u32 = 0x400;
+    pub(crate) const O_NOFOLLOW: u32 = 0x800;
+    pub(crate) const O_EXCL: u32 = 0x1000;
+
+    // whence of lseek
+    pub(crate) const SEEK_SET: u32 = 0;
+    pub(crate) const SEEK_CUR: u32 = 1;
+    pub(crate) const SEEK_END: u32 = 2;
 }
 
 // Create the path new as a link to the same inode as old.
 pub(crate) fn link(new: *const u8, old: *const u8) -> Result<(), SysError> {
     log::begin_op();
 
-    let ip = fs::namei(old).into_result().map_err(|_| {
+    // A symbolic link itself is linked as on Linux.
+    let ip = fs::namei_nofollow(old).map_err(|err| {
         log::end_op();
-        SysError::NoEnt
+        err
     })?;
 
     let mut inode = fs::ilock(&ip);
     let inode_dev = inode.get_dev();
     let inode_inum = inode.get_inum();
+    let inode_type = inode.get_type();
 
     if inode.is_dir() {
         fs::iunlock(inode);
+        fs::iput(ip);
         log::end_op();
         return Err(SysError::IsDir);
     }
@@ -49,24 +63,20 @@ pub(crate) fn link(new: *const u8, old: *const u8) -> Result<(), SysError> {
         log::end_op();
     }
 
-    let mut name = [0; DIR_SIZ];
-    let res = fs::nameiparent(new, name.as_mut_ptr())
-        .into_result()
-        .map_err(|_| SysError::InvalidArg)
-        .and_then(|dp| {
-            let mut dir_inode = fs::ilock(&dp);
-            if dir_inode.get_dev() == inode_dev
-                && fs::dir_link(&mut dir_inode, name.as_ptr(), inode_inum)
-            {
-                fs::iunlock(dir_inode);
-                fs::iput(dp);
-                Ok(())
-            } else {
-                fs::iunlock(dir_inode);
-                fs::iput(dp);
-                Err(SysError::InvalidArg)
-            }
-        });
+    let mut name = [0; DIR_SIZ + 1];
+    let res = fs::nameiparent(new, name.as_mut_ptr()).and_then(|dp| {
+        let mut dir_inode = fs::ilock(&dp);
+        let res = if dir_inode.get_dev() != inode_dev {
+            Err(SysError::InvalidArg)
+        } else if !fs::dir_link(&mut dir_inode, name.as_ptr(), inode_inum, inode_type) {
+            Err(SysError::Exists)
+        } else {
+            Ok(())
+        };
+        fs::iunlock(dir_inode);
+        fs::iput(dp);
+        res
+    });
 
     match res {
         Ok(_) => {
@@ -81,18 +91,29 @@ pub(crate) fn link(new: *const u8, old: *const u8) -> Result<(), SysError> {
     }
 }
 
+/// Remove the file path.
 pub(crate) fn unlink(path: *const u8) -> Result<(), SysError> {
+    remove(path, false)
+}
+
+/// Remove the empty directory path.
+pub(crate) fn rmdir(path: *const u8) -> Result<(), SysError> {
+    remove(path, true)
+}
+
+/// Remove the directory entry of path.
+/// If is_dir is true, path must be an empty directory. Otherwise it must not be a directory.
+/// The inode and its blocks are freed by iput when the last reference is dropped.
+fn remove(path: *const u8, is_dir: bool) -> Result<(), SysError> {
     log::begin_op();
 
-    let mut name = [0; DIR_SIZ];
+    let mut name = [0; DIR_SIZ + 1];
 
     // get inode for the directory
-    let dp = fs::nameiparent(path, name.as_mut_ptr())
-        .into_result()
-        .map_err(|_| {
-            log::end_op();
-            SysError::InvalidArg
-        })?;
+    let dp = fs::nameiparent(path, name.as_mut_ptr()).map_err(|err| {
+        log::end_op();
+        err
+    })?;
 
     let mut dir_inode = fs::ilock(&dp);
 
@@ -101,6 +122,7 @@ pub(crate) fn unlink(path: *const u8) -> Result<(), SysError> {
         || util::strncmp(name.as_ptr(), "..".as_ptr(), DIR_SIZ) == 0
     {
         fs::iunlock(dir_inode);
+        fs::iput(dp);
         log::end_op();
         return Err(SysError::InvalidArg);
     }
@@ -108,17 +130,13 @@ pub(crate) fn unlink(path: *const u8) -> Result<(), SysError> {
     let mut off = 0;
 
     // get the target inode in the directory
-    let ip = fs::dir_lookup_with_name(&mut dir_inode, name.as_ptr(), &mut off)
-        .into_result()
-        .map_err(|_| SysError::NoEnt);
-
-    let ip = match ip {
-        Ok(inode) => inode,
-        Err(err) => {
+    let ip = match fs::dir_lookup_with_name(&mut dir_inode, name.as_ptr(), &mut off) {
+        Some(ip) => ip,
+        None => {
             fs::iunlock(dir_inode);
             fs::iput(dp);
             log::end_op();
-            return Err(err);
+            return Err(SysError::NoEnt);
         }
     };
 
@@ -127,24 +145,30 @@ pub(crate) fn unlink(path: *const u8) -> Result<(), SysError> {
     if inode.get_nlink() < 1 {
         panic!("unlink: nlink < 1");
     }
-    if inode.is_dir() && fs::is_dir_empty(&mut inode) {
+
+    let res = if is_dir && !inode.is_dir() {
+        Err(SysError::NotDir)
+    } else if !is_dir && inode.is_dir() {
+        Err(SysError::IsDir)
+    } else if inode.is_dir() && !fs::is_dir_empty(&mut inode) {
+        Err(SysError::NotEmpty)
+    } else {
+        Ok(())
+    };
+    if let Err(err) = res {
         fs::iunlock(inode);
+        fs::iput(ip);
         fs::iunlock(dir_inode);
         fs::iput(dp);
         log::end_op();
-        return Err(SysError::InvalidArg);
+        return Err(err);
     }
 
     // Remove the inode from the dir
-    let ent = DirEnt::empty();
-    let ent_p = &ent as *const _ as *const u8;
-    let dir_ent_size = mem::size_of::<DirEnt>() as u32;
-    let n = fs::writei(&mut dir_inode, ent_p, off, dir_ent_size);
-    if n != dir_ent_size {
-        panic!("unlink: failed to writei");
-    }
+    fs::dir_unlink(&mut dir_inode, off);
 
     if inode.is_dir() {
+        // for ".." of the removed directory
         dir_inode.decr_nlink();
         fs::iupdate(&dir_inode);
     }
@@ -160,20 +184,256 @@ pub(crate) fn unlink(path: *const u8) -> Result<(), SysError> {
     Ok(())
 }
 
+// Serializes renames, so that the ancestors of directories don't change while rename
+// looks at them. It also makes the order of locking two directories consistent.
+static RENAME_LOCK: Mutex<()> = Mutex::new(());
+
+/// Return dp and its ancestors up to the root directory.
+/// The caller must iput them.
+fn ancestors(dp: &Arc<RwLock<Inode>>) -> Vec<Arc<RwLock<Inode>>> {
+    let dotdot = ['.' as u8, '.' as u8, 0];
+    let mut res = Vec::new();
+    let mut ip = fs::idup(dp);
+    loop {
+        let mut inode = fs::ilock(&ip);
+        let parent = fs::dir_lookup_with_name(&mut inode, dotdot.as_ptr(), null_mut())
+            .expect("ancestors: no \"..\" entry");
+        fs::iunlock(inode);
+        let is_root = Arc::ptr_eq(&parent, &ip);
+        res.push(ip);
+        if is_root {
+            // ".." of the root is itself
+            fs::iput(parent);
+            return res;
+        }
+        ip = parent;
+    }
+}
+
+fn contains(ips: &[Arc<RwLock<Inode>>], ip: &Arc<RwLock<Inode>>) -> bool {
+    ips.iter().any(|p| Arc::ptr_eq(p, ip))
+}
+
+fn is_dot_or_dotdot(name: &[u8; DIR_SIZ + 1]) -> bool {
+    let name = &name[..util::strnlen(name.as_ptr(), DIR_SIZ)];
+    name == b"." || name == b".."
+}
+
+/// Rename old to new. If new exists, it is replaced atomically.
+/// The names are changed in a single transaction, so a crash leaves exactly one of them.
+/// A replaced target which has no links left is freed in transactions of its own
+/// after it (see iput).
+/// "." and ".." can't be renamed (Busy), and a directory can't be moved
+/// into its own subtree (InvalidArg).
+pub(crate) fn rename(old: *const u8, new: *const u8) -> Result<(), SysError> {
+    log::begin_op();
+    let rename_lock = RENAME_LOCK.lock();
+
+    let mut old_name = [0; DIR_SIZ + 1];
+    let mut new_name = [0; DIR_SIZ + 1];
+    let odp = match fs::nameiparent(old, old_name.as_mut_ptr()) {
+        Err(err) => {
+            drop(rename_lock);
+            log::end_op();
+            return Err(err);
+        }
+        Ok(dp) => dp,
+    };
+    let ndp = match fs::nameiparent(new, new_name.as_mut_ptr()) {
+        Err(err) => {
+            fs::iput(odp);
+            drop(rename_lock);
+            log::end_op();
+            return Err(err);
+        }
+        Ok(dp) => dp,
+    };
+
+    let res = if is_dot_or_dotdot(&old_name) || is_dot_or_dotdot(&new_name) {
+        Err(SysError::Busy)
+    } else {
+        let old_ancestors = ancestors(&odp);
+        let new_ancestors = ancestors(&ndp);
+
+        let res = if Arc::ptr_eq(&odp, &ndp) {
+            let mut dir_inode = fs::ilock(&odp);
+            let res = rename_locked(
+                &mut dir_inode,
+                &old_name,
+                None,
+                &new_name,
+                &old_ancestors,
+                &new_ancestors,
+            );
+            fs::iunlock(dir_inode);
+            res
+        } else {
+            // Lock an ancestor first as the other operations do.
+            let ndp_first = contains(&old_ancestors, &ndp);
+            let (mut odir_inode, mut ndir_inode) = if ndp_first {
+                let ndir_inode = fs::ilock(&ndp);
+                (fs::ilock(&odp), ndir_inode)
+            } else {
+                let odir_inode = fs::ilock(&odp);
+                (odir_inode, fs::ilock(&ndp))
+            };
+            let res = rename_locked(
+                &mut odir_inode,
+                &old_name,
+                Some(&mut ndir_inode),
+                &new_name,
+                &old_ancestors,
+                &new_ancestors,
+            );
+            fs::iunlock(ndir_inode);
+            fs::iunlock(odir_inode);
+            res
+        };
+
+        for ip in old_ancestors.into_iter().chain(new_ancestors.into_iter()) {
+            fs::iput(ip);
+        }
+        res
+    };
+
+    fs::iput(ndp);
+    fs::iput(odp);
+    drop(rename_lock);
+    log::end_op();
+    res
+}
+
+/// Move the entry old_name in odir to new_name in ndir.
+/// ndir is None if both are in odir.
+fn rename_locked(
+    odir: &mut Inode,
+    old_name: &[u8; DIR_SIZ + 1],
+    ndir: Option<&mut Inode>,
+    new_name: &[u8; DIR_SIZ + 1],
+    old_ancestors: &[Arc<RwLock<Inode>>],
+    new_ancestors: &[Arc<RwLock<Inode>>],
+) -> Result<(), SysError> {
+    let mut old_off = 0;
+    let ip = fs::dir_lookup_with_name(odir, old_name.as_ptr(), &mut old_off)
+        .into_result()
+        .map_err(|_| SysError::NoEnt)?;
+
+    // A directory can't be moved into itself or its subtree.
+    // This is checked before locking ip because ip may be ndir.
+    // (Locked inodes are released by drop instead of iput, which locks the inode.
+    // They are still referenced by the caller, so iput has nothing to free.)
+    if contains(new_ancestors, &ip) {
+        drop(ip);
+        return Err(SysError::InvalidArg);
+    }
+
+    let same_dir = ndir.is_none();
+    let mut inode = fs::ilock(&ip);
+    let inum = inode.get_inum();
+    let typ = inode.get_type();
+    let is_dir = inode.is_dir();
+
+    // Add the new entry.
+    {
+        let ndir: &mut Inode = match ndir {
+            Some(ndir) => ndir,
+            None => &mut *odir,
+        };
+
+        let mut new_off = 0;
+        match fs::dir_lookup_with_name(ndir, new_name.as_ptr(), &mut new_off) {
+            None => {
+                if !fs::dir_link(ndir, new_name.as_ptr(), inum, typ) {
+                    panic!("rename: failed to dir_link");
+                }
+            }
+            Some(target) if Arc::ptr_eq(&target, &ip) => {
+                // old and new are links to the same file. Nothing to do.
+                drop(target);
+                fs::iunlock(inode);
+                fs::iput(ip);
+                return Ok(());
+            }
+            Some(target) if contains(old_ancestors, &target) => {
+                // The target contains old, so it is not empty (and it is already locked).
+                drop(target);
+                fs::iunlock(inode);
+                fs::iput(ip);
+                return Err(SysError::NotEmpty);
+            }
+            Some(target) => {
+                let mut target_inode = fs::ilock(&target);
+                let res = if target_inode.is_dir() && !is_dir {
+                    Err(SysError::IsDir)
+                } else if !target_inode.is_dir() && is_dir {
+                    Err(SysError::NotDir)
+                } else if target_inode.is_dir() && !fs::is_dir_empty(&mut target_inode) {
+                    Err(SysError::NotEmpty)
+                } else {
+                    Ok(())
+                };
+                if let Err(err) = res {
+                    fs::iunlock(target_inode);
+                    fs::iput(target);
+                    fs::iunlock(inode);
+                    fs::iput(ip);
+                    return Err(err);
+                }
+
+                // Replace the target. It is freed by iput if this was the last link.
+                fs::dir_set_inum(ndir, new_off, inum, typ);
+                if target_inode.is_dir() {
+                    // for ".." of the replaced directory
+                    ndir.decr_nlink();
+                    fs::iupdate(ndir);
+                }
+                target_inode.decr_nlink();
+                fs::iupdate(&target_inode);
+                fs::iunlock(target_inode);
+                fs::iput(target);
+            }
+        }
+
+        if is_dir && !same_dir {
+            // Rewrite ".." of the moved directory.
+            let dotdot = ['.' as u8, '.' as u8, 0];
+            let mut off = 0;
+            let parent = fs::dir_lookup_with_name(&mut inode, dotdot.as_ptr(), &mut off)
+                .expect("rename: no \"..\" entry");
+            drop(parent); // odir
+            fs::dir_set_inum(&mut inode, off, ndir.get_inum(), InodeType::Dir);
+            ndir.incr_nlink();
+            fs::iupdate(ndir);
+        }
+    }
+
+    // Remove the old entry.
+    fs::dir_unlink(odir, old_off);
+    if is_dir && !same_dir {
+        odir.decr_nlink();
+        fs::iupdate(odir);
+    }
+
+    fs::iunlock(inode);
+    fs::iput(ip);
+    Ok(())
+}
+
+/// Create path as a new inode of typ.
+/// An existing file is returned as it is if typ is File, unless is_excl == true.
+/// data is written to a new inode before it is linked to the directory,
+/// so no one sees the inode without it.
 fn create(
     path: *const u8,
     typ: InodeType,
     major: u16,
     minor: u16,
+    is_excl: bool,
+    data: &[u8],
 ) -> Result<Arc<RwLock<Inode>>, SysError> {
-    let mut name = [0; DIR_SIZ];
+    let mut name = [0; DIR_SIZ + 1];
 
-    let dp = fs::nameiparent(path, name.as_mut_ptr())
-        .into_result()
-        .map_err(|_| {
-            log::end_op();
-            SysError::InvalidArg
-        })?;
+    let dp = fs::nameiparent(path, name.as_mut_ptr())?;
 
     let mut dir_inode = fs::ilock(&dp);
 
@@ -183,13 +443,19 @@ fn create(
             fs::iunlock(dir_inode);
             fs::iput(dp);
             let inode = fs::ilock(&p);
-            if typ == InodeType::File && inode.is_file() {
-                fs::iunlock(inode);
-                return Ok(p);
+            let res = if typ == InodeType::File && inode.is_file() && !is_excl {
+                Ok(())
+            } else if inode.is_dir() && !is_excl {
+                Err(SysError::IsDir)
             } else {
-                fs::iunlock(inode);
-                return Err(SysError::IsDir);
+                Err(SysError::Exists)
+            };
+            fs::iunlock(inode);
+            if let Err(err) = res {
+                fs::iput(p);
+                return Err(err);
             }
+            return Ok(p);
         }
         None => fs::ialloc(dir_inode.get_dev(), typ, major, minor),
     };
@@ -197,6 +463,12 @@ fn create(
     let mut inode = fs::ilock(&ip);
     fs::iupdate(&inode);
 
+    if !data.is_empty()
+        && fs::writei(&mut inode, data.as_ptr(), 0, data.len() as u32) != data.len() as u32
+    {
+        panic!("create: short write");
+    }
+
     if typ == InodeType::Dir {
         // create "." and ".." entries
         dir_inode.incr_nlink();
@@ -206,14 +478,14 @@ fn create(
         let inum2 = dir_inode.get_inum();
         let dot1 = ['.' as u8, 0];
         let dot2 = ['.' as u8, '.' as u8, 0];
-        if !fs::dir_link(&mut inode, dot1.as_ptr(), inum1)
-            || !fs::dir_link(&mut inode, dot2.as_ptr(), inum2)
+        if !fs::dir_link(&mut inode, dot1.as_ptr(), inum1, InodeType::Dir)
+            || !fs::dir_link(&mut inode, dot2.as_ptr(), inum2, InodeType::Dir)
         {
             panic!("create: failed to create dots");
         }
     }
 
-    if !fs::dir_link(&mut dir_inode, name.as_ptr(), inode.get_inum()) {
+    if !fs::dir_link(&mut dir_inode, name.as_ptr(), inode.get_inum(), typ) {
         panic!("create: failed to dir_link");
     }
 
@@ -233,84 +505,155 @@ fn fd_alloc(ent: FileTableEntry) -> Result<FileDescriptor, FileTableEntry> {
 pub(crate) fn open(path: *const u8, mode: u32) -> Result<FileDescriptor, SysError> {
     log::begin_op();
 
-    let ip = if mode & O_CREATE != 0 {
-        match create(path, InodeType::File, 0, 0) {
-            Ok(ip) => Ok(ip),
-            Err(err) => {
-                log::end_op();
-                Err(err)
-            }
-        }
+    // O_EXCL is only meaningful with O_CREATE, and then path itself must not exist.
+    let is_excl = mode & O_CREATE != 0 && mode & O_EXCL != 0;
+    let found = if mode & O_NOFOLLOW != 0 || is_excl {
+        fs::namei_nofollow(path)
     } else {
-        match fs::namei(path) {
-            Some(ip) => Ok(ip),
-            None => {
-                log::end_op();
-                Err(SysError::NoEnt)
-            }
+        fs::namei(path)
+    };
+    let ip = match found {
+        Ok(ip) if is_excl => {
+            fs::iput(ip);
+            Err(SysError::Exists)
+        }
+        // create fails on a dangling symbolic link instead of creating its target.
+        Err(SysError::NoEnt) if mode & O_CREATE != 0 => {
+            create(path, InodeType::File, 0, 0, is_excl, &[])
         }
-    }?;
+        res => res,
+    };
+    let ip = ip.map_err(|err| {
+        log::end_op();
+        err
+    })?;
 
     let inode = fs::ilock(&ip);
+    let readable = mode & O_WRONLY == 0;
+    let writable = (mode & O_WRONLY != 0) || (mode & O_RDWR != 0);
+
+    if inode.is_symlink() {
+        // only with O_NOFOLLOW
+        fs::iunlock(inode);
+        fs::iput(ip);
+        log::end_op();
+        return Err(SysError::Loop);
+    }
 
-    if inode.is_dir() && mode != O_RDONLY {
+    if inode.is_dir() && (writable || mode & O_TRUNC != 0) {
         fs::iunlock(inode);
         fs::iput(ip);
         log::end_op();
         return Err(SysError::IsDir);
     }
 
-    let mut ft = file::file_table();
-    let readable = mode & O_WRONLY == 0;
-    let writable = (mode & O_WRONLY != 0) || (mode & O_RDWR != 0);
+    let is_trunc = mode & O_TRUNC != 0 && writable && inode.is_file();
+    fs::iunlock(inode);
 
-    match ft.alloc_as_inode(readable, writable, &ip) {
+    let is_append = mode & O_APPEND != 0;
+    let ent = file::file_table().alloc_as_inode(readable, writable, is_append, &ip);
+    let ent = match ent {
         None => {
-            fs::iunlock(inode);
             fs::iput(ip);
             log::end_op();
-            Err(SysError::TooManyFiles)
-        }
-        Some(ent) => {
-            let fd_opt = fd_alloc(ent);
-            match fd_opt {
-                Err(ent) => {
-                    ft.close(ent);
-                    fs::iunlock(inode);
-                    fs::iput(ip);
-                    log::end_op();
-                    Err(SysError::TooManyFileDescriptors)
-                }
-                Ok(fd) => {
-                    fs::iunlock(inode);
-                    log::end_op();
-                    Ok(fd)
-                }
-            }
+            return Err(SysError::TooManyFiles);
         }
+        Some(ent) => ent,
+    };
+    log::end_op();
+
+    // The file is truncated after the transaction since a large one needs several,
+    // but before the fd is visible so that nothing written through it is lost.
+    // A write by another env while the file is truncated may still be lost
+    // as if it were done before the open, and the file stays truncated
+    // if no fd is left.
+    if is_trunc {
+        fs::itrunc(&ip, 0);
     }
+
+    fd_alloc(ent).map_err(|ent| {
+        file::close(ent);
+        SysError::TooManyFileDescriptors
+    })
 }
 
 pub(crate) fn close(fd: FileDescriptor) -> Result<(), SysError> {
     let ent = env::cur_env_mut().unwrap().fd_close(fd);
-    file::file_table().close(ent);
+    file::close(ent);
     Ok(())
 }
 
 pub(crate) fn mkdir(path: *const u8) -> Result<(), SysError> {
     log::begin_op();
-    let res = create(path, InodeType::Dir, 0, 0).map(|_| ());
+    let res = create(path, InodeType::Dir, 0, 0, false, &[]).map(|_| ());
     log::end_op();
     res
 }
 
 pub(crate) fn mknod(path: *const u8, major: u16, minor: u16) -> Result<(), SysError> {
     log::begin_op();
-    let res = create(path, InodeType::Dev, major, minor).map(|_| ());
+    let res = create(path, InodeType::Dev, major, minor, false, &[]).map(|_| ());
+    log::end_op();
+    res
+}
+
+/// Create a symbolic link path whose content is target.
+/// The target is written in the transaction creating the link,
+/// so it must fit in one block (NameTooLong otherwise).
+pub(crate) fn symlink(target: *const u8, path: *const u8) -> Result<(), SysError> {
+    let len = util::strnlen(target, MAX_PATH_LEN);
+    if len == 0 {
+        return Err(SysError::NoEnt);
+    }
+    // The target is written in the transaction creating the link,
+    // which has room for one data block and its bitmap block.
+    if len > superblock::get().block_size() {
+        return Err(SysError::NameTooLong);
+    }
+
+    log::begin_op();
+    let target = unsafe { slice::from_raw_parts(target, len) };
+    let res = create(path, InodeType::Symlink, 0, 0, true, target).map(fs::iput);
+    log::end_op();
+    res
+}
+
+/// Return the content of the symbolic link path.
+pub(crate) fn readlink(path: *const u8) -> Result<Vec<u8>, SysError> {
+    log::begin_op();
+    let ip = fs::namei_nofollow(path).map_err(|err| {
+        log::end_op();
+        err
+    })?;
+
+    let mut inode = fs::ilock(&ip);
+    let res = if inode.is_symlink() {
+        Ok(fs::read_link(&mut inode))
+    } else {
+        Err(SysError::InvalidArg)
+    };
+    fs::iunlock(inode);
+    fs::iput(ip);
     log::end_op();
     res
 }
 
+/// Same as stat, but a symbolic link itself is described instead of its target.
+pub(crate) fn lstat(path: *const u8) -> Result<Stat, SysError> {
+    log::begin_op();
+    let ip = fs::namei_nofollow(path).map_err(|err| {
+        log::end_op();
+        err
+    })?;
+
+    let mut inode = fs::ilock(&ip);
+    let stat = fs::stati(&mut inode);
+    fs::iunlock(inode);
+    fs::iput(ip);
+    log::end_op();
+    Ok(stat)
+}
+
 pub(crate) fn stat(fd: FileDescriptor) -> Result<Stat, SysError> {
     match env::cur_env_mut().unwrap().fd_get(fd) {
         None => Err(SysError::IllegalFileDescriptor),
@@ -321,6 +664,20 @@ pub(crate) fn stat(fd: FileDescriptor) -> Result<Stat, SysError> {
     }
 }
 
+pub(crate) fn ftruncate(fd: FileDescriptor, len: u32) -> Result<(), SysError> {
+    match env::cur_env_mut().unwrap().fd_get(fd) {
+        None => Err(SysError::IllegalFileDescriptor),
+        Some(ent) => ent.file.read().truncate(len),
+    }
+}
+
+pub(crate) fn lseek(fd: FileDescriptor, offset: i32, whence: u32) -> Result<u32, SysError> {
+    match env::cur_env_mut().unwrap().fd_get(fd) {
+        None => Err(SysError::IllegalFileDescriptor),
+        Some(ent) => ent.file.write().seek(offset, whence),
+    }
+}
+
 pub(crate) fn dup(fd: FileDescriptor) -> Result<FileDescriptor, SysError> {
     let env = env::cur_env_mut().unwrap();
     env.fd_get(fd)
@@ -338,10 +695,10 @@ pub(crate) fn chdir(path: *const u8) -> Result<(), SysError> {
     log::begin_op();
 
     let ip = match fs::namei(path) {
-        Some(ip) => ip,
-        None => {
+        Ok(ip) => ip,
+        Err(err) => {
             log::end_op();
-            return Err(SysError::NoEnt);
+            return Err(err);
         }
     };
 
@@ -355,106 +712,88 @@ pub(crate) fn chdir(path: *const u8) -> Result<(), SysError> {
 
     fs::iunlock(inode);
 
-    cur_env.change_cwd(&ip);
+    cur_env.change_cwd(ip);
     log::end_op();
 
     Ok(())
 }
 
-pub(crate) fn exec(orig_path: *const u8, orig_argv: &[*const u8]) -> Result<(), SysError> {
+/// path and argv must be copied into the kernel
+/// because the old address space is released by exec.
+pub(crate) fn exec(path: *const u8, argv: &[*const u8]) -> Result<(), SysError> {
     let env = env::cur_env_mut().unwrap();
-
-    unsafe {
-        // copy path and argv because they are in user space.
-        let path = [0 as u8; DIR_SIZ];
-        let dst = VirtAddr(&path as *const _ as *const u8 as u32);
-        let src = VirtAddr(orig_path as *const u8 as u32);
-        util::memcpy(dst, src, DIR_SIZ);
-
-        let mut argv_stack = [[0 as u8; MAX_CMD_ARG_LEN]; MAX_CMD_ARGS];
-        for (i, s) in orig_argv.iter().enumerate() {
-            let len = util::strnlen(*s, MAX_CMD_ARG_LEN);
-            util::strncpy(argv_stack[i].as_mut_ptr(), *s, len + 1);
-        }
-
-        let mut argv = [null() as *const u8; MAX_CMD_ARGS];
-        for i in 0..orig_argv.len() {
-            argv[i] = argv_stack[i].as_ptr() as *const u8;
-        }
-
-        env::exec(path.as_ptr(), &argv[0..orig_argv.len()], env)
-    }
+    env::exec(path, argv, env)
 }
 
-/// Return the length of name (exclusive '\0' at the end)
+/// Write the absolute path of the current directory into buf.
+/// Return the length of it (exclusive '\0' at the end)
 pub(crate) fn getcwd(buf: *mut u8, size: usize) -> Result<usize, SysError> {
-    // Call f recursively to create an absolute path for cwd
-    fn f(
-        cur: Arc<RwLock<Inode>>,
-        mut len: usize,
-        buf: *mut u8,
-        buf_size: usize,
-    ) -> Result<usize, SysError> {
-        let mut cur_ip = cur.write();
-        let cur_inum = cur_ip.get_inum();
-
-        if cur_ip.get_dev() == ROOT_DEV && cur_ip.get_inum() == ROOT_INUM {
-            return Ok(len);
-        }
-
-        let path_parent = [b'.', b'.', b'\0'];
-        let parent = fs::dir_lookup_with_name(&mut cur_ip, path_parent.as_ptr(), null_mut())
-            .into_result()
-            .map_err(|_| SysError::NoEnt)?;
-
-        len = f(parent.clone(), len, buf, buf_size)?;
-
-        let mut parent_ip = parent.write();
+    // Append name to the last of buf.
+    // Return new len.
+    fn append(name: &[u8], len: usize, buf: *mut u8, buf_size: usize) -> usize {
+        let added_len = cmp::min(buf_size - len - 1, name.len());
+        unsafe { util::strncpy(buf.add(len), name.as_ptr(), added_len) };
+        len + added_len
+    }
 
-        let mut off: u32 = 0;
-        fs::dir_lookup_with_inum(&mut parent_ip, cur_inum, &mut off);
+    if size == 0 {
+        return Err(SysError::InvalidArg);
+    }
 
-        let mut ent = DirEnt::empty();
-        let dir_ent_size = mem::size_of::<DirEnt>();
-        let cnt = fs::readi(
-            &mut parent_ip,
-            &mut ent as *mut _ as *mut u8,
-            off,
-            dir_ent_size as u32,
-        );
+    // Collect names from cwd up to the root directory.
+    // This walks the tree iteratively because paths can be deep.
+    log::begin_op();
+    let dotdot = [b'.', b'.', b'\0'];
+    let mut names: Vec<Vec<u8>> = Vec::new();
+    let mut ip = fs::idup(env::cur_env().unwrap().get_cwd());
+    let res = loop {
+        let mut inode = fs::ilock(&ip);
+        let inum = inode.get_inum();
+        if inode.get_dev() == ROOT_DEV && inum == ROOT_INUM {
+            fs::iunlock(inode);
+            break Ok(());
+        }
 
-        if cnt != Some(dir_ent_size as u32) {
-            Err(SysError::Unspecified)
+        let parent = fs::dir_lookup_with_name(&mut inode, dotdot.as_ptr(), null_mut());
+        fs::iunlock(inode);
+        let parent = match parent {
+            Some(parent) => parent,
+            None => break Err(SysError::NoEnt),
+        };
+
+        let mut parent_inode = fs::ilock(&parent);
+        let mut off = 0;
+        let found = fs::dir_lookup_with_inum(&mut parent_inode, inum, &mut off);
+        if let Some(found) = found {
+            let ent = DirEnt::read(&mut parent_inode, off);
+            names.push(ent.name().to_vec());
+            fs::iunlock(parent_inode);
+            fs::iput(found);
         } else {
-            // add '/'
-            let slash = [b'/', b'\0'];
-            len = append(slash.as_ptr(), len, buf, buf_size);
-            // add dir name
-            len = append(ent.get_name(), len, buf, buf_size);
-            Ok(len)
+            // cwd has been removed.
+            fs::iunlock(parent_inode);
+            fs::iput(parent);
+            break Err(SysError::NoEnt);
         }
-    }
 
-    // Append name to the last of buf.
-    // Return new len.
-    fn append(name: *const u8, len: usize, buf: *mut u8, buf_size: usize) -> usize {
-        let name_len = util::strnlen(name, DIR_SIZ);
-        let added_len = cmp::max(0, cmp::min(buf_size - len - 1, name_len));
-        unsafe { util::strncpy(buf.add(len), name, added_len) };
-        len + added_len
-    }
+        fs::iput(ip);
+        ip = parent;
+    };
+    fs::iput(ip);
+    log::end_op();
+    res?;
 
-    let env = env::cur_env().unwrap();
-    let res = f(env.get_cwd().clone(), 0, buf, size);
-    res.map(|mut len| {
-        if len == 0 {
-            // this is root
-            let slash = [b'/', b'\0'];
-            len = append(slash.as_ptr(), len, buf, size);
-        }
-        unsafe { *buf.add(len) = 0 };
-        len
-    })
+    let mut len = 0;
+    if names.is_empty() {
+        // this is root
+        len = append(b"/", len, buf, size);
+    }
+    for name in names.iter().rev() {
+        len = append(b"/", len, buf, size);
+        len = append(name, len, buf, size);
+    }
+    unsafe { *buf.add(len) = 0 };
+    Ok(len)
 }
 
 pub(crate) fn pipe() -> Result<(FileDescriptor, FileDescriptor), SysError> {
@@ -464,23 +803,22 @@ pub(crate) fn pipe() -> Result<(FileDescriptor, FileDescriptor), SysError> {
             let env = env::cur_env_mut().unwrap();
             let fd0_opt = env.fd_alloc(ent0);
             let fd1_opt = env.fd_alloc(ent1);
-            let mut ft = file::file_table();
             match (fd0_opt, fd1_opt) {
                 (Err(ent0), Err(ent1)) => {
-                    ft.close(ent0);
-                    ft.close(ent1);
+                    file::close(ent0);
+                    file::close(ent1);
                     Err(SysError::TooManyFileDescriptors)
                 }
                 (Err(ent0), Ok(fd1)) => {
-                    ft.close(ent0);
+                    file::close(ent0);
                     let ent1 = env.fd_close(fd1);
-                    ft.close(ent1);
+                    file::close(ent1);
                     Err(SysError::TooManyFileDescriptors)
                 }
                 (Ok(fd0), Err(ent1)) => {
-                    ft.close(ent1);
+                    file::close(ent1);
                     let ent0 = env.fd_close(fd0);
-                    ft.close(ent0);
+                    file::close(ent0);
                     Err(SysError::TooManyFileDescriptors)
                 }
                 (Ok(fd0), Ok(fd1)) => Ok((fd0, fd1)),
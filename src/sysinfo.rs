@@ -0,0 +1,87 @@
+use crate::allocator;
+use crate::buf;
+use crate::buf::consts::NBUF;
+use crate::constants::*;
+use crate::env;
+use crate::pmap;
+use crate::slab::{self, CACHE_NAME_LEN, NCACHES};
+use crate::swap;
+
+pub(crate) const NSTATUS: usize = 6; // number of variants of env::EnvStatus
+
+/// Snapshot of memory usage of the system.
+/// Sizes are in pages unless otherwise noted.
+// FIXME: the same definition is in user/user.h
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct Sysinfo {
+    pub(crate) total_pages: u32, // physical pages managed by the kernel
+    pub(crate) free_pages: u32,
+    pub(crate) high_pages: u32, // physical pages in high memory
+    pub(crate) free_high_pages: u32,
+    pub(crate) kheap_size: u32, // bytes mapped for the kernel heap
+    pub(crate) kheap_used: u32, // bytes used in the kernel heap
+    pub(crate) bcache_used: u32, // buffers in the buffer cache
+    pub(crate) bcache_size: u32, // maximum number of buffers
+    pub(crate) swap_total: u32,
+    pub(crate) swap_used: u32,
+    pub(crate) swap_ins: u32,
+    pub(crate) swap_outs: u32,
+    pub(crate) envs: [u32; NSTATUS], // number of envs in each EnvStatus
+    pub(crate) caches: [CacheInfo; NCACHES], // object caches of the kernel heap (see slab.rs)
+}
+
+/// Usage of an object cache.
+// FIXME: the same definition is in user/user.h
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct CacheInfo {
+    pub(crate) name: [u8; CACHE_NAME_LEN], // null terminated
+    pub(crate) obj_size: u32,              // in bytes
+    pub(crate) in_use: u32,                // objects allocated and not freed yet
+    pub(crate) cached: u32,                // free objects
+    pub(crate) pages: u32,                 // pages owned by the cache
+}
+
+fn cache_infos() -> [CacheInfo; NCACHES] {
+    let mut infos = [CacheInfo {
+        name: [0; CACHE_NAME_LEN],
+        obj_size: 0,
+        in_use: 0,
+        cached: 0,
+        pages: 0,
+    }; NCACHES];
+    for (info, stats) in infos.iter_mut().zip(slab::cache_stats()) {
+        let n = stats.name.len().min(CACHE_NAME_LEN - 1);
+        info.name[..n].copy_from_slice(&stats.name.as_bytes()[..n]);
+        info.obj_size = stats.obj_size as u32;
+        info.in_use = stats.in_use as u32;
+        info.cached = stats.cached as u32;
+        info.pages = stats.pages as u32;
+    }
+    infos
+}
+
+pub(crate) fn sysinfo() -> Sysinfo {
+    let mem = pmap::phys_mem_stats();
+    let heap = allocator::heap_stats();
+    let swap = swap::swap_stats();
+    let bcache_used = buf::buf_cache().count();
+
+    Sysinfo {
+        total_pages: mem.total_pages as u32,
+        free_pages: mem.free_pages as u32,
+        high_pages: mem.high_pages as u32,
+        free_high_pages: mem.free_high_pages as u32,
+        kheap_size: (heap.mapped_pages() * PGSIZE as usize) as u32,
+        kheap_used: (heap.small_used + heap.large_pages * PGSIZE as usize) as u32,
+        bcache_used: bcache_used as u32,
+        bcache_size: NBUF as u32,
+        swap_total: swap.total_slots as u32,
+        swap_used: swap.used_slots as u32,
+        swap_ins: swap.swap_ins as u32,
+        swap_outs: swap.swap_outs as u32,
+        envs: env::env_count_by_status(),
+        caches: cache_infos(),
+    }
+}
@@ -3,7 +3,7 @@ use crate::gdt::consts::*;
 use crate::gdt::TaskState;
 use crate::pmap::VirtAddr;
 use crate::{console, env, gdt, sched, x86};
-use crate::{lapic, mpconfig, syscall};
+use crate::{lapic, mpconfig, random, swap, syscall, uaccess};
 use consts::*;
 use core::mem;
 use core::slice;
@@ -56,6 +56,7 @@ pub(crate) mod consts {
     pub(crate) const IRQ_SERIAL: u8 = 4;
     pub(crate) const IRQ_SPURIOUS: u8 = 7;
     pub(crate) const IRQ_IDE: u8 = 14;
+    pub(crate) const IRQ_WAKEUP: u8 = 17; // IPI to wake up an idle cpu
     pub(crate) const IRQ_ERROR: u8 = 19;
 }
 
@@ -254,6 +255,8 @@ pub(crate) unsafe fn trap_init() {
 
     IDT.0[48] = GateDesc::new(false, GDT_KERNEL_CODE, vs[48], 3);
 
+    IDT.0[49] = GateDesc::new(false, GDT_KERNEL_CODE, vs[49], 0);
+
     trap_init_percpu();
 }
 
@@ -364,6 +367,10 @@ unsafe fn print_regs(regs: &PushRegs) {
 fn trap_dispatch(tf: &mut Trapframe) {
     // Handle processor exceptions.
     if tf.tf_trapno == (IRQ_OFFSET + IRQ_TIMER) as u32 {
+        random::add_timer_entropy();
+        lapic::eoi();
+    } else if tf.tf_trapno == (IRQ_OFFSET + IRQ_WAKEUP) as u32 {
+        // Just acknowledge it. An idle cpu goes back to the scheduler in trap().
         lapic::eoi();
     } else if tf.tf_trapno == (IRQ_OFFSET + IRQ_KBD) as u32 {
         console::console_intr();
@@ -381,7 +388,21 @@ fn trap_dispatch(tf: &mut Trapframe) {
             );
             tf.tf_regs.reg_eax = ret as u32;
         }
+    } else if tf.tf_trapno == T_PGFLT
+        && tf.tf_cs & 3 == 3
+        && swap::page_fault(VirtAddr(x86::rcr2()), true)
+    {
+        // The page was swapped out and is now brought back.
+        if let Some(curenv) = env::cur_env_mut() {
+            curenv.count_page_fault();
+        }
     } else {
+        if tf.tf_trapno == T_PGFLT && tf.tf_cs & 3 == 3 {
+            if let Some(curenv) = env::cur_env_mut() {
+                curenv.count_page_fault();
+            }
+        }
+
         // Unexpected trap: The user process or the kernel has a bug.
         unsafe {
             print_trapframe(tf);
@@ -403,6 +424,9 @@ extern "C" fn trap(orig_tf: *mut Trapframe) {
     // The environment may have set DF and some versions
     // of GCC rely on DF being clear
     x86::cld();
+    // The environment may have set AC, which opens user pages to the kernel under SMAP.
+    // The saved eflags is restored by iret.
+    uaccess::clac();
 
     // Check that interrupts are disabled.  If this assertion
     // fails, DO NOT be tempted to fix it by inserting a "cli" in
@@ -415,6 +439,15 @@ extern "C" fn trap(orig_tf: *mut Trapframe) {
 
     // println!("Incoming TRAP frame at {:?}", tf as *const Trapframe);
 
+    // The kernel touched a swapped-out user page (e.g. an argument of a syscall).
+    // Bring it back and resume the kernel.
+    if tf.tf_cs & 3 == 0
+        && tf.tf_trapno == T_PGFLT
+        && swap::page_fault(VirtAddr(x86::rcr2()), false)
+    {
+        return;
+    }
+
     // Trapped from user mode
     if tf.tf_cs & 3 == 3 {
         let curenv = env::cur_env_mut().expect("there is no running Env");
@@ -424,6 +457,15 @@ extern "C" fn trap(orig_tf: *mut Trapframe) {
             env::env_destroy(curenv.get_env_id(), env_table);
         }
 
+        // The time until now is spent in user mode.
+        curenv.account_user_time();
+
+        if curenv.exceeds_cpu_limit() {
+            println!("[{:08x}] cpu time limit exceeded", curenv.get_env_id());
+            let env_table = env::env_table();
+            env::env_destroy(curenv.get_env_id(), env_table);
+        }
+
         // Copy trap frame (which is currently on the stack)
         // into 'curenv->env_tf', so that running the environment
         // will restart at the trap point.
@@ -455,6 +497,13 @@ extern "C" fn trap(orig_tf: *mut Trapframe) {
             let table = env::env_table();
             env::env_run(env_id, table);
         }
+    } else if mpconfig::this_cpu().is_halted() {
+        // The cpu was woken up from sched_halt (typically by IPI).
+        // Re-arm the timer and look for a runnable env again.
+        let cpu = mpconfig::this_cpu_mut();
+        cpu.started();
+        lapic::timer_start();
+        sched::sched_yield();
     } else {
         // assume that it is in kernel initialization
     }
@@ -0,0 +1,151 @@
+// Copying data between the kernel and user space.
+//
+// Syscalls must access user memory only through these helpers.
+// They check the range against the page directory of the current env before
+// touching it, so a bad pointer results in SysError::Fault instead of a page fault
+// in the kernel (or the destruction of the env).
+// A swapped-out page passes the check and is brought back by the page fault handler.
+//
+// If SMAP is enabled, the kernel can touch user pages only while a UserAccess
+// is alive, so an accidental dereference of a user pointer faults immediately.
+
+use crate::constants::*;
+use crate::env;
+use crate::pmap::{self, VirtAddr};
+use crate::{util, x86};
+use core::mem::{self, MaybeUninit};
+use core::slice;
+
+/// A window in which the kernel can access user pages (STAC ... CLAC).
+/// Windows must not be nested.
+pub(crate) struct UserAccess {
+    _private: (),
+}
+
+impl UserAccess {
+    pub(crate) fn begin() -> UserAccess {
+        if pmap::smap_enabled() {
+            x86::stac();
+        }
+        UserAccess { _private: () }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        clac();
+    }
+}
+
+/// Close the window if any.
+/// The trap handler calls this because a user env can set EFLAGS.AC.
+pub(crate) fn clac() {
+    if pmap::smap_enabled() {
+        x86::clac();
+    }
+}
+
+/// Check that the current env can access [va, va+len).
+fn check(va: *const u8, len: usize, perm: u32) -> Result<(), SysError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = (va as usize).checked_add(len).ok_or(SysError::Fault)?;
+    if end > UTOP as usize {
+        return Err(SysError::Fault);
+    }
+    let env = env::cur_env_mut().ok_or(SysError::Fault)?;
+    env.get_pgdir_mut()
+        .user_mem_check(VirtAddr(va as u32), len, perm | PTE_U | PTE_P)
+        .map_err(|_| SysError::Fault)
+}
+
+/// Copy dst.len() bytes from user address src.
+pub(crate) fn copy_in(dst: &mut [u8], src: *const u8) -> Result<(), SysError> {
+    check(src, dst.len(), 0)?;
+    let _ua = UserAccess::begin();
+    unsafe {
+        util::memcpy(
+            VirtAddr(dst.as_mut_ptr() as u32),
+            VirtAddr(src as u32),
+            dst.len(),
+        )
+    };
+    Ok(())
+}
+
+/// Copy src to user address dst.
+pub(crate) fn copy_out(dst: *mut u8, src: &[u8]) -> Result<(), SysError> {
+    check(dst, src.len(), PTE_W)?;
+    let _ua = UserAccess::begin();
+    unsafe {
+        util::memcpy(
+            VirtAddr(dst as u32),
+            VirtAddr(src.as_ptr() as u32),
+            src.len(),
+        )
+    };
+    Ok(())
+}
+
+/// Check that the current env can write [dst, dst+len).
+/// Syscalls which consume data before copying it out (e.g. read) call this first
+/// so that the data is not lost by a bad buffer.
+pub(crate) fn check_out(dst: *mut u8, len: usize) -> Result<(), SysError> {
+    check(dst, len, PTE_W)
+}
+
+/// Read a value of T from user address src.
+pub(crate) fn copy_from_user<T: Copy>(src: *const T) -> Result<T, SysError> {
+    let mut val = MaybeUninit::<T>::uninit();
+    let dst = unsafe { slice::from_raw_parts_mut(val.as_mut_ptr().cast::<u8>(), mem::size_of::<T>()) };
+    copy_in(dst, src.cast::<u8>())?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Write val to user address dst.
+pub(crate) fn copy_to_user<T: Copy>(dst: *mut T, val: &T) -> Result<(), SysError> {
+    let src = unsafe { slice::from_raw_parts((val as *const T).cast::<u8>(), mem::size_of::<T>()) };
+    copy_out(dst.cast::<u8>(), src)
+}
+
+/// Return the length of the null-terminated string at user address src.
+/// Return SysError::InvalidArg if the string (with '\0') is longer than max.
+pub(crate) fn strnlen_user(src: *const u8, max: usize) -> Result<usize, SysError> {
+    let pgsize = PGSIZE as usize;
+    for i in 0..max {
+        let p = unsafe { src.add(i) };
+        // Check each page once when the string reaches it.
+        if i == 0 || (p as usize) % pgsize == 0 {
+            check(p, 1, 0)?;
+        }
+        let _ua = UserAccess::begin();
+        if unsafe { *p } == 0 {
+            return Ok(i);
+        }
+    }
+    Err(SysError::InvalidArg)
+}
+
+/// Copy a null-terminated string from user address src into dst.
+/// Return the length of the string (exclusive '\0').
+/// Return SysError::InvalidArg if the string (with '\0') doesn't fit in dst.
+pub(crate) fn strncpy_from_user(dst: &mut [u8], src: *const u8) -> Result<usize, SysError> {
+    let pgsize = PGSIZE as usize;
+    for i in 0..dst.len() {
+        let p = unsafe { src.add(i) };
+        // Check each page once when the string reaches it.
+        if i == 0 || (p as usize) % pgsize == 0 {
+            check(p, 1, 0)?;
+        }
+        let c = {
+            let _ua = UserAccess::begin();
+            unsafe { *p }
+        };
+        dst[i] = c;
+        if c == 0 {
+            return Ok(i);
+        }
+    }
+    Err(SysError::InvalidArg)
+}
@@ -34,6 +34,13 @@ pub(crate) fn outb(port: u16, value: u8) {
     }
 }
 
+#[inline]
+pub(crate) fn outw(port: u16, value: u16) {
+    unsafe {
+        llvm_asm!("outw $1, $0" :: "N{dx}"(port), "{ax}"(value) :: "volatile");
+    }
+}
+
 #[inline]
 pub(crate) fn outsl(port: u16, addr: *const u32, cnt: usize) {
     unsafe {
@@ -75,6 +82,34 @@ pub(crate) fn lcr0(value: u32) {
     unsafe { llvm_asm!("mov $0, %cr0" :: "r"(value) : "memory" : "volatile") }
 }
 
+#[inline]
+pub(crate) fn rcr4() -> u32 {
+    let value: u32;
+    unsafe { llvm_asm!("mov %cr4, $0" : "=r"(value) ::: "volatile") }
+    value
+}
+
+#[inline]
+pub(crate) fn lcr4(value: u32) {
+    unsafe { llvm_asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile") }
+}
+
+/// Execute cpuid and return (eax, ebx, ecx, edx).
+#[inline]
+pub(crate) fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u32;
+    let ecx: u32;
+    let edx: u32;
+    unsafe {
+        llvm_asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(0)
+            :: "volatile")
+    }
+    (eax, ebx, ecx, edx)
+}
+
 #[inline]
 pub(crate) fn invlpg(va: VirtAddr) {
     unsafe { llvm_asm!("invlpg ($0)" :: "r"(va.0) : "memory" : "volatile") }
@@ -133,6 +168,15 @@ pub(crate) fn xchg<T: Copy>(p: *mut T, v: T) -> T {
     // res
 }
 
+/// Read the time-stamp counter.
+#[inline]
+pub(crate) fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe { llvm_asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile") }
+    ((high as u64) << 32) | (low as u64)
+}
+
 #[inline]
 pub(crate) fn cli() {
     unsafe { llvm_asm!("cli" :::: "volatile") };
@@ -143,6 +187,20 @@ pub(crate) fn sti() {
     unsafe { llvm_asm!("sti" :::: "volatile") };
 }
 
+/// Allow supervisor access to user pages (set EFLAGS.AC).
+/// This is an invalid instruction if SMAP is not supported.
+#[inline]
+pub(crate) fn stac() {
+    unsafe { llvm_asm!("stac" ::: "memory" : "volatile") };
+}
+
+/// Disallow supervisor access to user pages (clear EFLAGS.AC).
+/// This is an invalid instruction if SMAP is not supported.
+#[inline]
+pub(crate) fn clac() {
+    unsafe { llvm_asm!("clac" ::: "memory" : "volatile") };
+}
+
 #[inline]
 pub(crate) fn pause() {
     unsafe { llvm_asm!("pause" :::: "volatile") };
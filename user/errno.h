@@ -9,5 +9,16 @@ enum errno {
     E_TOO_MANY_FILE_DESCRIPTORS,
     E_ILLEGAL_FILE_DESCRIPTOR,
     E_TRY_AGAIN,
+    E_BROKEN_PIPE,
     E_NOT_CHILD,
+    E_LIMIT_EXCEEDED,
+    E_NO_MEM,
+    E_NO_FREE_ENV,
+    E_FAULT,
+    E_NOT_EMPTY,
+    E_EXISTS,
+    E_LOOP,
+    E_TOO_LARGE,
+    E_NAME_TOO_LONG,
+    E_BUSY,
 };
@@ -0,0 +1,35 @@
+#include "user.h"
+
+#define KB_PER_PAGE 4
+
+void umain(int argc, char **argv) {
+    struct sysinfo info;
+    if (sys_sysinfo(&info) < 0) {
+        printf("free: cannot get system information\n");
+        return;
+    }
+
+    unsigned int used = info.total_pages - info.free_pages;
+    unsigned int swap_free = info.swap_total - info.swap_used;
+
+    printf("            total       used       free\n");
+    printf("Mem:   %8uK  %8uK  %8uK\n", info.total_pages * KB_PER_PAGE, used * KB_PER_PAGE,
+            info.free_pages * KB_PER_PAGE);
+    printf("High:  %8uK  %8uK  %8uK\n", info.high_pages * KB_PER_PAGE,
+            (info.high_pages - info.free_high_pages) * KB_PER_PAGE, info.free_high_pages * KB_PER_PAGE);
+    printf("Swap:  %8uK  %8uK  %8uK\n", info.swap_total * KB_PER_PAGE, info.swap_used * KB_PER_PAGE,
+            swap_free * KB_PER_PAGE);
+    printf("\n");
+    printf("kernel heap: %u / %u bytes\n", info.kheap_used, info.kheap_size);
+    printf("buffer cache: %u / %u buffers\n", info.bcache_used, info.bcache_size);
+    printf("swap: %u in, %u out\n", info.swap_ins, info.swap_outs);
+    printf("envs: %u runnable, %u running, %u not-runnable, %u dying, %u zombie\n",
+            info.envs[ENV_RUNNABLE], info.envs[ENV_RUNNING], info.envs[ENV_NOT_RUNNABLE],
+            info.envs[ENV_DYING], info.envs[ENV_ZOMBIE]);
+    printf("\n");
+    printf("cache        objsize     in use     cached      pages\n");
+    for (int i = 0; i < NCACHES; i++) {
+        struct cache_info *c = &info.caches[i];
+        printf("%-10s %9u  %9u  %9u  %9u\n", c->name, c->obj_size, c->in_use, c->cached, c->pages);
+    }
+}
@@ -0,0 +1,88 @@
+#include "user.h"
+
+// Tests of getdents. They run in a new directory, which is removed at the end.
+
+#define TEST_DIR "getdentstest.d"
+
+static char buf[1024];
+static int nfailed;
+
+#define CHECK(cond) check((cond), #cond, __LINE__)
+
+static void check(int ok, const char *expr, int line) {
+    if (!ok) {
+        printf("getdentstest: line %d: %s failed\n", line, expr);
+        nfailed++;
+    }
+}
+
+// Create path with the content s.
+static void create_file(char *path, char *s) {
+    int fd = open(path, O_CREAT | O_WRONLY | O_TRUNC);
+    CHECK(fd >= 0);
+    if (fd < 0) {
+        return;
+    }
+    CHECK(write(fd, s, strlen(s)) == strlen(s));
+    close(fd);
+}
+
+static void test_getdents(void) {
+    static char dents[64];
+    int fd, n, off;
+    int seen_file = 0, seen_dir = 0, seen_link = 0, nents = 0;
+    struct dirent *de;
+
+    CHECK(sys_mkdir("d") == 0);
+    create_file("d/file", "");
+    CHECK(sys_mkdir("d/dir") == 0);
+    CHECK(sys_symlink("file", "d/link") == 0);
+
+    fd = open("d", O_RDONLY);
+    CHECK(fd >= 0);
+    // The buffer is small so that the entries are read by several calls.
+    while ((n = sys_getdents(fd, dents, sizeof(dents))) > 0) {
+        for (off = 0; off < n; off += de->rec_len) {
+            de = (struct dirent *) (dents + off);
+            nents++;
+            if (strcmp(de->name, "file") == 0) {
+                seen_file = de->typ == T_FILE;
+            } else if (strcmp(de->name, "dir") == 0) {
+                seen_dir = de->typ == T_DIR;
+            } else if (strcmp(de->name, "link") == 0) {
+                seen_link = de->typ == T_SYMLINK;
+            }
+        }
+    }
+    CHECK(n == 0);
+    CHECK(nents == 5); // with "." and ".."
+    CHECK(seen_file && seen_dir && seen_link);
+    close(fd);
+
+    fd = open("d/file", O_RDONLY);
+    CHECK(sys_getdents(fd, dents, sizeof(dents)) == -E_NOT_DIR);
+    close(fd);
+
+    CHECK(sys_unlink("d/link") == 0);
+    CHECK(sys_rmdir("d/dir") == 0);
+    CHECK(sys_unlink("d/file") == 0);
+    CHECK(sys_rmdir("d") == 0);
+}
+
+void umain(int argc, char **argv) {
+    if (sys_mkdir(TEST_DIR) < 0 || sys_chdir(TEST_DIR) < 0) {
+        printf("getdentstest: cannot make %s\n", TEST_DIR);
+        return;
+    }
+
+    test_getdents();
+
+    sys_chdir("..");
+    CHECK(sys_rmdir(TEST_DIR) == 0);
+
+    if (nfailed > 0) {
+        printf("getdentstest: %d checks failed\n", nfailed);
+    } else {
+        printf("getdentstest: OK\n");
+    }
+}
@@ -8,6 +8,7 @@ USER_LIB_SRCS := \
 	user/lib/strlen.c \
 	user/lib/strnlen.c \
 	user/lib/strcpy.c \
+	user/lib/strcmp.c \
 	user/lib/syscall.c \
 	user/lib/exit.c \
 	user/lib/open.c \
@@ -0,0 +1,9 @@
+#include "../user.h"
+
+int strcmp(const char *s1, const char *s2) {
+    while (*s1 != '\0' && *s1 == *s2) {
+        s1++;
+        s2++;
+    }
+    return (unsigned char) *s1 - (unsigned char) *s2;
+}
@@ -27,6 +27,23 @@
 #define SYS_MKDIR 18
 #define SYS_CHDIR 19
 #define SYS_PIPE 20
+#define SYS_GETRUSAGE 21
+#define SYS_ENV_LIST 22
+#define SYS_GETRLIMIT 23
+#define SYS_SETRLIMIT 24
+#define SYS_SYSINFO 25
+#define SYS_LINK 26
+#define SYS_UNLINK 27
+#define SYS_RMDIR 28
+#define SYS_RENAME 29
+#define SYS_SYMLINK 30
+#define SYS_READLINK 31
+#define SYS_LSTAT 32
+#define SYS_LSEEK 33
+#define SYS_PREAD 34
+#define SYS_PWRITE 35
+#define SYS_FTRUNCATE 36
+#define SYS_GETDENTS 37
 
 static inline int syscall(int num, int a1, int a2, int a3, int a4, int a5) {
     int ret;
@@ -128,7 +145,12 @@ int sys_wait_env_id(int pid) {
 }
 
 void *sys_sbrk(unsigned int nbytes) {
-    return (void *) syscall(SYS_SBRK, (int) nbytes, 0, 0, 0, 0);
+    int r = syscall(SYS_SBRK, (int) nbytes, 0, 0, 0, 0);
+    // the heap is page aligned, so an error number never collides with it.
+    if (r < 0 && r > -4096) {
+        return (void *) -1;
+    }
+    return (void *) r;
 }
 
 int sys_fstat(int fd, struct stat *statbuf) {
@@ -155,3 +177,71 @@ int sys_chdir(char *path) {
 int sys_pipe(int pipefd[2]) {
     return syscall(SYS_PIPE, (int) pipefd, 0, 0, 0, 0);
 }
+
+int sys_getrusage(int who, struct rusage *usage) {
+    return syscall(SYS_GETRUSAGE, who, (int) usage, 0, 0, 0);
+}
+
+int sys_env_list(struct env_info *buf, int n) {
+    return syscall(SYS_ENV_LIST, (int) buf, n, 0, 0, 0);
+}
+
+int sys_getrlimit(int resource, struct rlimit *rlim) {
+    return syscall(SYS_GETRLIMIT, resource, (int) rlim, 0, 0, 0);
+}
+
+int sys_setrlimit(int resource, const struct rlimit *rlim) {
+    return syscall(SYS_SETRLIMIT, resource, (int) rlim, 0, 0, 0);
+}
+
+int sys_sysinfo(struct sysinfo *info) {
+    return syscall(SYS_SYSINFO, (int) info, 0, 0, 0, 0);
+}
+
+int sys_link(char *oldpath, char *newpath) {
+    return syscall(SYS_LINK, (int) oldpath, (int) newpath, 0, 0, 0);
+}
+
+int sys_unlink(char *path) {
+    return syscall(SYS_UNLINK, (int) path, 0, 0, 0, 0);
+}
+
+int sys_rmdir(char *path) {
+    return syscall(SYS_RMDIR, (int) path, 0, 0, 0, 0);
+}
+
+int sys_rename(char *oldpath, char *newpath) {
+    return syscall(SYS_RENAME, (int) oldpath, (int) newpath, 0, 0, 0);
+}
+
+int sys_symlink(char *target, char *linkpath) {
+    return syscall(SYS_SYMLINK, (int) target, (int) linkpath, 0, 0, 0);
+}
+
+int sys_readlink(char *path, char *buf, unsigned int size) {
+    return syscall(SYS_READLINK, (int) path, (int) buf, (int) size, 0, 0);
+}
+
+int sys_lstat(char *path, struct stat *statbuf) {
+    return syscall(SYS_LSTAT, (int) path, (int) statbuf, 0, 0, 0);
+}
+
+int sys_lseek(int fd, int offset, int whence) {
+    return syscall(SYS_LSEEK, fd, offset, whence, 0, 0);
+}
+
+int sys_pread(int fd, char *buf, int count, int offset) {
+    return syscall(SYS_PREAD, fd, (int) buf, count, offset, 0);
+}
+
+int sys_pwrite(int fd, char *buf, int count, int offset) {
+    return syscall(SYS_PWRITE, fd, (int) buf, count, offset, 0);
+}
+
+int sys_ftruncate(int fd, int length) {
+    return syscall(SYS_FTRUNCATE, fd, length, 0, 0, 0);
+}
+
+int sys_getdents(int fd, char *buf, int size) {
+    return syscall(SYS_GETDENTS, fd, (int) buf, size, 0, 0);
+}
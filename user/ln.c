@@ -0,0 +1,22 @@
+#include "user.h"
+
+void umain(int argc, char **argv) {
+    if (argc >= 2 && strcmp(argv[1], "-s") == 0) {
+        if (argc < 4) {
+            printf("ln: missing operand\n");
+            return;
+        }
+        if (sys_symlink(argv[2], argv[3]) < 0) {
+            printf("ln: cannot create symbolic link %s to %s\n", argv[3], argv[2]);
+        }
+        return;
+    }
+
+    if (argc < 3) {
+        printf("ln: missing operand\n");
+        return;
+    }
+    if (sys_link(argv[1], argv[2]) < 0) {
+        printf("ln: cannot link %s to %s\n", argv[2], argv[1]);
+    }
+}
@@ -1,7 +1,9 @@
 #include "user.h"
 
+#define NAME_WIDTH 14 // names are padded to this width
+
 char *fmtname(char *path) {
-    static char buf[DIR_SIZ + 1];
+    static char buf[NAME_WIDTH + 1];
     char *p;
 
     // Find first character after last slash.
@@ -9,19 +11,21 @@ char *fmtname(char *path) {
     p++;
 
     // Return blank-padded name.
-    if (strlen(p) >= DIR_SIZ) {
+    if (strlen(p) >= NAME_WIDTH) {
         return p;
     }
     memmove(buf, p, strlen(p));
-    memset(buf + strlen(p), ' ', DIR_SIZ - strlen(p));
+    memset(buf + strlen(p), ' ', NAME_WIDTH - strlen(p));
     return buf;
 }
 
 void ls(char *path) {
-    char buf[512], *p;
-    int fd;
-    unsigned int parent_sz, sz;
-    struct dirent de;
+    // These are too large for the user stack.
+    static char buf[MAX_PATH_LEN + 1];
+    static char dents[1024];
+    char *p;
+    int fd, n, off;
+    struct dirent *de;
     struct stat st;
 
     if ((fd = open(path, O_RDONLY)) < 0) {
@@ -34,7 +38,6 @@ void ls(char *path) {
         close(fd);
         return;
     }
-    parent_sz = st.size;
 
     switch (st.typ) {
         case T_FILE:
@@ -49,20 +52,19 @@ void ls(char *path) {
             p = buf + strlen(path);
             *p++ = '/';
 
-            sz = 0;
-            while (sz < parent_sz) {
-                read(fd, (char *) &de, sizeof(de));
-                sz += sizeof(de);
-                if (de.inum == 0) {
-                    continue;
+            while ((n = sys_getdents(fd, dents, sizeof(dents))) > 0) {
+                for (off = 0; off < n; off += de->rec_len) {
+                    de = (struct dirent *) (dents + off);
+                    memmove(p, de->name, strlen(de->name) + 1);
+                    if (sys_lstat(buf, &st) < 0) {
+                        printf("ls: cannot stat %s\n", buf);
+                        continue;
+                    }
+                    printf("%s %d %d %d\n", fmtname(buf), de->typ, de->inum, st.size);
                 }
-                strcpy(p, de.name);
-                *(p + strlen(de.name)) = 0;
-                if (stat(buf, &st) < 0) {
-                    printf("ls: cannot stat %s\n", buf);
-                    continue;
-                }
-                printf("%s %d %d %d\n", fmtname(buf), st.typ, st.inum, st.size);
+            }
+            if (n < 0) {
+                printf("ls: cannot read %s\n", path);
             }
             break;
     }
@@ -17,6 +17,18 @@ UPROGS += \
 	$(OBJDIR)/user/cat \
 	$(OBJDIR)/user/pipetest \
 	$(OBJDIR)/user/wc \
+	$(OBJDIR)/user/ps \
+	$(OBJDIR)/user/free \
+	$(OBJDIR)/user/rm \
+	$(OBJDIR)/user/rmdir \
+	$(OBJDIR)/user/ln \
+	$(OBJDIR)/user/mv \
+	$(OBJDIR)/user/renametest \
+	$(OBJDIR)/user/symlinktest \
+	$(OBJDIR)/user/seektest \
+	$(OBJDIR)/user/trunctest \
+	$(OBJDIR)/user/getdentstest \
+	$(OBJDIR)/user/swaptest \
 
 include user/lib/module.mk
 
@@ -0,0 +1,18 @@
+#include "user.h"
+
+void umain(int argc, char **argv) {
+    if (argc < 3) {
+        printf("mv: missing operand\n");
+        return;
+    }
+    int r = sys_rename(argv[1], argv[2]);
+    if (r == -E_NOT_EMPTY) {
+        printf("mv: cannot move %s to %s: directory not empty\n", argv[1], argv[2]);
+    } else if (r == -E_BUSY) {
+        printf("mv: cannot move %s to %s: '.' and '..' cannot be renamed\n", argv[1], argv[2]);
+    } else if (r == -E_INVALID_ARG) {
+        printf("mv: cannot move %s to a subdirectory of itself\n", argv[1]);
+    } else if (r < 0) {
+        printf("mv: cannot move %s to %s\n", argv[1], argv[2]);
+    }
+}
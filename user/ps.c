@@ -0,0 +1,46 @@
+#include "user.h"
+
+#define MAX_ENVS 64
+
+static char *status_name(unsigned int status) {
+    switch (status) {
+        case ENV_FREE:
+            return "free";
+        case ENV_DYING:
+            return "dying";
+        case ENV_RUNNABLE:
+            return "runnable";
+        case ENV_RUNNING:
+            return "running";
+        case ENV_ZOMBIE:
+            return "zombie";
+        case ENV_NOT_RUNNABLE:
+            return "not-runnable";
+        default:
+            return "unknown";
+    }
+}
+
+void umain(int argc, char **argv) {
+    struct env_info *envs = (struct env_info *) malloc(sizeof(struct env_info) * MAX_ENVS);
+    if (envs == NULL) {
+        printf("ps: cannot allocate buffer\n");
+        return;
+    }
+
+    int n = sys_env_list(envs, MAX_ENVS);
+    if (n < 0) {
+        printf("ps: cannot list envs\n");
+        free(envs);
+        return;
+    }
+
+    printf("ID       PARENT   STATUS       RUNS     HEAP     RSS  CWD  NAME\n");
+    for (int i = 0; i < n; i++) {
+        struct env_info *e = &envs[i];
+        printf("%08x %08x %12s %8d %8d %6d %4d %s\n", e->env_id, e->parent_id, status_name(e->status),
+                e->runs, e->heap_size, e->rss, e->cwd_inum, e->name);
+    }
+
+    free(envs);
+}
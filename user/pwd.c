@@ -1,8 +1,10 @@
 #include "user.h"
 
+// This is too large for the user stack.
+static char buf[MAX_PATH_LEN + 1];
+
 void umain(int argc, char **argv) {
-    char buf[DIR_SIZ + 1];
-    if (sys_getcwd(buf, DIR_SIZ + 1) == NULL) {
+    if (sys_getcwd(buf, sizeof(buf)) == NULL) {
         printf("pwd: cannot getcwd\n");
     } else {
         printf("%s\n", buf);
@@ -0,0 +1,96 @@
+#include "user.h"
+
+// Tests of rename. They run in a new directory, which is removed at the end.
+
+#define TEST_DIR "renametest.d"
+
+static char buf[1024];
+static int nfailed;
+
+#define CHECK(cond) check((cond), #cond, __LINE__)
+
+static void check(int ok, const char *expr, int line) {
+    if (!ok) {
+        printf("renametest: line %d: %s failed\n", line, expr);
+        nfailed++;
+    }
+}
+
+// Create path with the content s.
+static void create_file(char *path, char *s) {
+    int fd = open(path, O_CREAT | O_WRONLY | O_TRUNC);
+    CHECK(fd >= 0);
+    if (fd < 0) {
+        return;
+    }
+    CHECK(write(fd, s, strlen(s)) == strlen(s));
+    close(fd);
+}
+
+// Return 1 if path has the content s.
+static int has_content(char *path, char *s) {
+    int fd = open(path, O_RDONLY);
+    if (fd < 0) {
+        return 0;
+    }
+    int n = read(fd, buf, sizeof(buf) - 1);
+    close(fd);
+    if (n < 0) {
+        return 0;
+    }
+    buf[n] = '\0';
+    return strcmp(buf, s) == 0;
+}
+
+static void test_rename(void) {
+    struct stat st;
+
+    // Replace an existing file.
+    create_file("x", "old");
+    create_file("y", "new");
+    CHECK(sys_rename("y", "x") == 0);
+    CHECK(sys_lstat("y", &st) == -E_NO_ENT);
+    CHECK(has_content("x", "new"));
+
+    // Move a directory into another one.
+    CHECK(sys_mkdir("a") == 0);
+    CHECK(sys_mkdir("b") == 0);
+    create_file("a/f", "f");
+    CHECK(sys_rename("a", "b/a") == 0);
+    CHECK(sys_lstat("a", &st) == -E_NO_ENT);
+    CHECK(has_content("b/a/f", "f"));
+    CHECK(has_content("b/a/../a/f", "f"));
+
+    // A directory can't be moved into itself, and "." and ".." can't be moved.
+    CHECK(sys_rename("b", "b/a/c") == -E_INVALID_ARG);
+    CHECK(sys_rename("b/.", "c") == -E_BUSY);
+    CHECK(sys_rename("b/a", "b/..") == -E_BUSY);
+
+    // A non-empty directory can't be replaced.
+    CHECK(sys_mkdir("c") == 0);
+    CHECK(sys_rename("c", "b") == -E_NOT_EMPTY);
+    CHECK(sys_rmdir("c") == 0);
+
+    CHECK(sys_unlink("x") == 0);
+    CHECK(sys_unlink("b/a/f") == 0);
+    CHECK(sys_rmdir("b/a") == 0);
+    CHECK(sys_rmdir("b") == 0);
+}
+
+void umain(int argc, char **argv) {
+    if (sys_mkdir(TEST_DIR) < 0 || sys_chdir(TEST_DIR) < 0) {
+        printf("renametest: cannot make %s\n", TEST_DIR);
+        return;
+    }
+
+    test_rename();
+
+    sys_chdir("..");
+    CHECK(sys_rmdir(TEST_DIR) == 0);
+
+    if (nfailed > 0) {
+        printf("renametest: %d checks failed\n", nfailed);
+    } else {
+        printf("renametest: OK\n");
+    }
+}
@@ -0,0 +1,17 @@
+#include "user.h"
+
+void umain(int argc, char **argv) {
+    if (argc < 2) {
+        printf("rm: missing operand\n");
+        return;
+    }
+    for (int i = 1; i < argc; i++) {
+        char *path = argv[i];
+        int r = sys_unlink(path);
+        if (r == -E_ID_DIR) {
+            printf("rm: cannot remove %s: is a directory\n", path);
+        } else if (r < 0) {
+            printf("rm: cannot remove %s\n", path);
+        }
+    }
+}
@@ -0,0 +1,19 @@
+#include "user.h"
+
+void umain(int argc, char **argv) {
+    if (argc < 2) {
+        printf("rmdir: missing operand\n");
+        return;
+    }
+    for (int i = 1; i < argc; i++) {
+        char *path = argv[i];
+        int r = sys_rmdir(path);
+        if (r == -E_NOT_EMPTY) {
+            printf("rmdir: cannot remove %s: directory not empty\n", path);
+        } else if (r == -E_NOT_DIR) {
+            printf("rmdir: cannot remove %s: not a directory\n", path);
+        } else if (r < 0) {
+            printf("rmdir: cannot remove %s\n", path);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+#include "user.h"
+
+// Tests of lseek, pread and pwrite on a file with a hole. They run in a new
+// directory, which is removed at the end.
+
+#define TEST_DIR "seektest.d"
+
+static char buf[1024];
+static int nfailed;
+
+#define CHECK(cond) check((cond), #cond, __LINE__)
+
+static void check(int ok, const char *expr, int line) {
+    if (!ok) {
+        printf("seektest: line %d: %s failed\n", line, expr);
+        nfailed++;
+    }
+}
+
+static int file_size(int fd) {
+    struct stat st;
+    if (sys_fstat(fd, &st) < 0) {
+        return -1;
+    }
+    return st.size;
+}
+
+static void test_lseek(void) {
+    int fd = open("sparse", O_CREAT | O_RDWR);
+    CHECK(fd >= 0);
+
+    // Writing past the end leaves a hole which reads as zeros.
+    CHECK(sys_lseek(fd, 3 * 4096, SEEK_SET) == 3 * 4096);
+    CHECK(write(fd, "x", 1) == 1);
+    CHECK(file_size(fd) == 3 * 4096 + 1);
+    memset(buf, 0xff, sizeof(buf));
+    CHECK(sys_pread(fd, buf, 16, 100) == 16);
+    int zeros = 1;
+    for (int i = 0; i < 16; i++) {
+        zeros = zeros && buf[i] == 0;
+    }
+    CHECK(zeros);
+    CHECK(sys_pread(fd, buf, 16, 3 * 4096) == 1 && buf[0] == 'x');
+
+    CHECK(sys_lseek(fd, -1, SEEK_END) == 3 * 4096);
+    CHECK(sys_lseek(fd, 1, SEEK_CUR) == 3 * 4096 + 1);
+    CHECK(read(fd, buf, 1) == 0);
+    CHECK(sys_lseek(fd, -1, SEEK_SET) == -E_INVALID_ARG);
+
+    // pwrite doesn't move the offset.
+    CHECK(sys_pwrite(fd, "y", 1, 10) == 1);
+    CHECK(sys_lseek(fd, 0, SEEK_CUR) == 3 * 4096 + 1);
+
+    close(fd);
+    CHECK(sys_unlink("sparse") == 0);
+}
+
+void umain(int argc, char **argv) {
+    if (sys_mkdir(TEST_DIR) < 0 || sys_chdir(TEST_DIR) < 0) {
+        printf("seektest: cannot make %s\n", TEST_DIR);
+        return;
+    }
+
+    test_lseek();
+
+    sys_chdir("..");
+    CHECK(sys_rmdir(TEST_DIR) == 0);
+
+    if (nfailed > 0) {
+        printf("seektest: %d checks failed\n", nfailed);
+    } else {
+        printf("seektest: OK\n");
+    }
+}
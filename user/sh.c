@@ -295,10 +295,10 @@ struct cmd *parseredirs(struct cmd *cmd, char **ps, char *es) {
                 cmd = redircmd(cmd, q, eq, O_RDONLY, 0);
                 break;
             case '>':
-                cmd = redircmd(cmd, q, eq, O_WRONLY | O_CREAT, 1);
+                cmd = redircmd(cmd, q, eq, O_WRONLY | O_CREAT | O_TRUNC, 1);
                 break;
             case '+':
-                cmd = redircmd(cmd, q, eq, O_WRONLY | O_CREAT, 1);
+                cmd = redircmd(cmd, q, eq, O_WRONLY | O_CREAT | O_APPEND, 1);
                 break;
         }
     }
@@ -1,12 +1,20 @@
-#define DIR_SIZ 12
+#define DIR_SIZ 255 // maximum length of file name
+// FIXME: the same definition is in src/constants.rs
+#define MAX_PATH_LEN 4096 // maximum length of an absolute path
 
 #define T_DIR 1
 #define T_FILE 2
+#define T_DEV 3
+#define T_SYMLINK 4
 
 // FIXME: the same definition is in src/fs.rs
+// Record of a directory entry returned by sys_getdents.
+// The name ends with '\0', and the next record starts rec_len bytes after this one.
 struct dirent {
     unsigned int inum;
-    char name[DIR_SIZ];
+    unsigned short rec_len;
+    unsigned short typ;
+    char name[];
 };
 
 // FIXME: the same definition is in src/fs.rs
@@ -0,0 +1,129 @@
+#include "user.h"
+
+// Allocate more memory than is free among several children so that the clock
+// algorithm swaps their pages out, and check the contents of every page after
+// it is read back. The children check their pages at the same time, so pages
+// are faulted in while others are being written back.
+
+#define PGSIZE 4096
+#define WORDS_PER_PAGE (PGSIZE / sizeof(unsigned int))
+#define MAX_CHILDREN 16
+
+static unsigned int pattern(int id, unsigned int page, unsigned int word) {
+    return ((unsigned int) id << 22) ^ (page << 10) ^ word;
+}
+
+// Fill npages pages of the heap, tell the parent through ready_fd,
+// wait for it through go_fd and report the check through result_fd.
+static void hog(int id, unsigned int npages, int ready_fd, int go_fd, int result_fd) {
+    char c;
+    unsigned int *p = sbrk(npages * PGSIZE);
+    if (p == (unsigned int *) -1) {
+        printf("swaptest: child %d cannot allocate %u pages\n", id, npages);
+        write(ready_fd, "x", 1);
+        exit(1);
+    }
+
+    for (unsigned int i = 0; i < npages; i++) {
+        for (unsigned int j = 0; j < WORDS_PER_PAGE; j++) {
+            p[i * WORDS_PER_PAGE + j] = pattern(id, i, j);
+        }
+    }
+    write(ready_fd, "r", 1);
+    read(go_fd, &c, 1);
+
+    unsigned int nbad = 0;
+    for (unsigned int i = 0; i < npages; i++) {
+        for (unsigned int j = 0; j < WORDS_PER_PAGE; j++) {
+            if (p[i * WORDS_PER_PAGE + j] != pattern(id, i, j)) {
+                nbad++;
+                break;
+            }
+        }
+    }
+    if (nbad > 0) {
+        printf("swaptest: child %d has %u broken pages\n", id, nbad);
+    }
+    write(result_fd, nbad == 0 ? "o" : "x", 1);
+    exit(0);
+}
+
+void umain(int argc, char **argv) {
+    struct sysinfo before, after;
+    struct rlimit rlim;
+    int ready[2], go[2], result[2];
+    int children[MAX_CHILDREN];
+    int nchildren, nready = 0, nok = 0;
+    char c;
+
+    if (sys_sysinfo(&before) < 0 || sys_getrlimit(RLIMIT_HEAP, &rlim) < 0) {
+        printf("swaptest: cannot get system information\n");
+        return;
+    }
+    if (before.swap_total == 0) {
+        printf("swaptest: no swap\n");
+        return;
+    }
+
+    // Use half of the free swap beyond the free memory.
+    unsigned int total = before.free_pages + (before.swap_total - before.swap_used) / 2;
+    unsigned long long limit = rlim.cur / PGSIZE - 1;
+    unsigned int per_child = limit < total ? (unsigned int) limit : total;
+    nchildren = (total + per_child - 1) / per_child;
+    if (nchildren > MAX_CHILDREN) {
+        printf("swaptest: %d children are needed, but use %d\n", nchildren, MAX_CHILDREN);
+        nchildren = MAX_CHILDREN;
+    }
+    printf("swaptest: %u pages free, %d children allocate %u pages each\n", before.free_pages,
+            nchildren, per_child);
+
+    if (sys_pipe(ready) < 0 || sys_pipe(go) < 0 || sys_pipe(result) < 0) {
+        printf("swaptest: cannot create pipes\n");
+        return;
+    }
+
+    for (int i = 0; i < nchildren; i++) {
+        children[i] = sys_fork();
+        if (children[i] < 0) {
+            printf("swaptest: cannot fork\n");
+            nchildren = i;
+            break;
+        } else if (children[i] == 0) {
+            hog(i, per_child, ready[1], go[0], result[1]);
+        }
+    }
+
+    for (int i = 0; i < nchildren; i++) {
+        read(ready[0], &c, 1);
+        if (c == 'r') {
+            nready++;
+        }
+    }
+    sys_sysinfo(&after);
+    if (after.swap_outs == before.swap_outs) {
+        printf("swaptest: no page was swapped out\n");
+    }
+
+    // Let the ready children check their pages together.
+    for (int i = 0; i < nready; i++) {
+        write(go[1], "g", 1);
+    }
+    for (int i = 0; i < nready; i++) {
+        read(result[0], &c, 1);
+        if (c == 'o') {
+            nok++;
+        }
+    }
+    for (int i = 0; i < nchildren; i++) {
+        wait_env_id(children[i]);
+    }
+
+    sys_sysinfo(&after);
+    printf("swaptest: %u pages swapped out, %u swapped in\n", after.swap_outs - before.swap_outs,
+            after.swap_ins - before.swap_ins);
+    if (nready < nchildren || nok < nready || after.swap_ins == before.swap_ins) {
+        printf("swaptest: failed\n");
+    } else {
+        printf("swaptest: OK\n");
+    }
+}
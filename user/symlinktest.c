@@ -0,0 +1,104 @@
+#include "user.h"
+
+// Tests of symbolic links. They run in a new directory, which is removed at
+// the end.
+
+#define TEST_DIR "symlinktest.d"
+
+static char buf[1024];
+static int nfailed;
+
+#define CHECK(cond) check((cond), #cond, __LINE__)
+
+static void check(int ok, const char *expr, int line) {
+    if (!ok) {
+        printf("symlinktest: line %d: %s failed\n", line, expr);
+        nfailed++;
+    }
+}
+
+// Return 1 if the first n bytes of a and b are the same.
+static int same_bytes(char *a, char *b, int n) {
+    for (int i = 0; i < n; i++) {
+        if (a[i] != b[i]) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+// Create path with the content s.
+static void create_file(char *path, char *s) {
+    int fd = open(path, O_CREAT | O_WRONLY | O_TRUNC);
+    CHECK(fd >= 0);
+    if (fd < 0) {
+        return;
+    }
+    CHECK(write(fd, s, strlen(s)) == strlen(s));
+    close(fd);
+}
+
+// Return 1 if path has the content s.
+static int has_content(char *path, char *s) {
+    int fd = open(path, O_RDONLY);
+    if (fd < 0) {
+        return 0;
+    }
+    int n = read(fd, buf, sizeof(buf) - 1);
+    close(fd);
+    if (n < 0) {
+        return 0;
+    }
+    buf[n] = '\0';
+    return strcmp(buf, s) == 0;
+}
+
+static void test_symlink(void) {
+    struct stat st;
+    int n;
+
+    create_file("target", "linked");
+    CHECK(sys_symlink("target", "link") == 0);
+    CHECK(has_content("link", "linked"));
+    n = sys_readlink("link", buf, sizeof(buf));
+    CHECK(n == 6 && same_bytes(buf, "target", 6));
+    CHECK(sys_lstat("link", &st) == 0 && st.typ == T_SYMLINK);
+    CHECK(open("link", O_RDONLY | O_NOFOLLOW) == -E_LOOP);
+
+    // Loops are detected.
+    CHECK(sys_symlink("loop2", "loop1") == 0);
+    CHECK(sys_symlink("loop1", "loop2") == 0);
+    CHECK(open("loop1", O_RDONLY) == -E_LOOP);
+    CHECK(sys_symlink("self", "self") == 0);
+    CHECK(open("self/x", O_RDONLY) == -E_LOOP);
+
+    // A dangling link.
+    CHECK(sys_symlink("nowhere", "dangling") == 0);
+    CHECK(open("dangling", O_RDONLY) == -E_NO_ENT);
+
+    CHECK(sys_unlink("link") == 0);
+    CHECK(has_content("target", "linked"));
+    CHECK(sys_unlink("target") == 0);
+    CHECK(sys_unlink("loop1") == 0);
+    CHECK(sys_unlink("loop2") == 0);
+    CHECK(sys_unlink("self") == 0);
+    CHECK(sys_unlink("dangling") == 0);
+}
+
+void umain(int argc, char **argv) {
+    if (sys_mkdir(TEST_DIR) < 0 || sys_chdir(TEST_DIR) < 0) {
+        printf("symlinktest: cannot make %s\n", TEST_DIR);
+        return;
+    }
+
+    test_symlink();
+
+    sys_chdir("..");
+    CHECK(sys_rmdir(TEST_DIR) == 0);
+
+    if (nfailed > 0) {
+        printf("symlinktest: %d checks failed\n", nfailed);
+    } else {
+        printf("symlinktest: OK\n");
+    }
+}
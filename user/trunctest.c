@@ -0,0 +1,184 @@
+#include "user.h"
+
+// Tests of O_APPEND, O_TRUNC, O_EXCL, ftruncate and of unlinking an open
+// file. They run in a new directory, which is removed at the end.
+
+#define TEST_DIR "trunctest.d"
+
+static char buf[1024];
+static int nfailed;
+
+#define CHECK(cond) check((cond), #cond, __LINE__)
+
+static void check(int ok, const char *expr, int line) {
+    if (!ok) {
+        printf("trunctest: line %d: %s failed\n", line, expr);
+        nfailed++;
+    }
+}
+
+// Return 1 if the first n bytes of a and b are the same.
+static int same_bytes(char *a, char *b, int n) {
+    for (int i = 0; i < n; i++) {
+        if (a[i] != b[i]) {
+            return 0;
+        }
+    }
+    return 1;
+}
+
+// Create path with the content s.
+static void create_file(char *path, char *s) {
+    int fd = open(path, O_CREAT | O_WRONLY | O_TRUNC);
+    CHECK(fd >= 0);
+    if (fd < 0) {
+        return;
+    }
+    CHECK(write(fd, s, strlen(s)) == strlen(s));
+    close(fd);
+}
+
+// Return 1 if path has the content s.
+static int has_content(char *path, char *s) {
+    int fd = open(path, O_RDONLY);
+    if (fd < 0) {
+        return 0;
+    }
+    int n = read(fd, buf, sizeof(buf) - 1);
+    close(fd);
+    if (n < 0) {
+        return 0;
+    }
+    buf[n] = '\0';
+    return strcmp(buf, s) == 0;
+}
+
+static int file_size(int fd) {
+    struct stat st;
+    if (sys_fstat(fd, &st) < 0) {
+        return -1;
+    }
+    return st.size;
+}
+
+static void test_unlink_open(void) {
+    // An unlinked file can still be used until it is closed.
+    create_file("u", "unlinked");
+    int fd = open("u", O_RDWR);
+    CHECK(fd >= 0);
+    CHECK(sys_unlink("u") == 0);
+    CHECK(open("u", O_RDONLY) == -E_NO_ENT);
+    CHECK(sys_pread(fd, buf, 8, 0) == 8);
+    CHECK(same_bytes(buf, "unlinked", 8));
+    CHECK(write(fd, "!", 1) == 1);
+    close(fd);
+}
+
+static void test_append_trunc(void) {
+    int fd;
+
+    create_file("log", "abc");
+    fd = open("log", O_WRONLY | O_APPEND);
+    CHECK(fd >= 0);
+    CHECK(sys_lseek(fd, 0, SEEK_SET) == 0);
+    CHECK(write(fd, "def", 3) == 3);
+    close(fd);
+    CHECK(has_content("log", "abcdef"));
+
+    fd = open("log", O_WRONLY | O_TRUNC);
+    CHECK(fd >= 0);
+    CHECK(file_size(fd) == 0);
+    close(fd);
+
+    CHECK(open("log", O_CREAT | O_EXCL | O_WRONLY) == -E_EXISTS);
+
+    fd = open("log", O_RDWR);
+    CHECK(write(fd, "0123456789", 10) == 10);
+    CHECK(sys_ftruncate(fd, 4) == 0);
+    CHECK(file_size(fd) == 4);
+    CHECK(sys_ftruncate(fd, 8) == 0);
+    CHECK(file_size(fd) == 8);
+    CHECK(sys_pread(fd, buf, 8, 0) == 8);
+    CHECK(same_bytes(buf, "0123\0\0\0\0", 8));
+    close(fd);
+
+    fd = open("log", O_RDONLY);
+    CHECK(sys_ftruncate(fd, 0) == -E_ILLEGAL_FILE_DESCRIPTOR);
+    close(fd);
+
+    CHECK(sys_unlink("log") == 0);
+}
+
+#define NRECORDS 8
+#define RECORD_SIZE 3000
+
+static char record[RECORD_SIZE];
+
+// Append NRECORDS records of c. A record is larger than what the kernel writes
+// at a time, so the records of two writers interleave if an append isn't atomic.
+static void append_records(char c) {
+    int fd = open("log", O_WRONLY | O_APPEND);
+    if (fd < 0) {
+        exit(1);
+    }
+    memset(record, c, RECORD_SIZE);
+    for (int i = 0; i < NRECORDS; i++) {
+        if (write(fd, record, RECORD_SIZE) != RECORD_SIZE) {
+            exit(1);
+        }
+    }
+    close(fd);
+    exit(0);
+}
+
+static void test_append_concurrent(void) {
+    int pids[2], fd, whole = 1;
+
+    create_file("log", "");
+    for (int i = 0; i < 2; i++) {
+        pids[i] = sys_fork();
+        CHECK(pids[i] >= 0);
+        if (pids[i] == 0) {
+            append_records(i == 0 ? 'a' : 'b');
+        }
+    }
+    for (int i = 0; i < 2; i++) {
+        if (pids[i] > 0) {
+            wait_env_id(pids[i]);
+        }
+    }
+
+    fd = open("log", O_RDONLY);
+    CHECK(fd >= 0);
+    CHECK(file_size(fd) == 2 * NRECORDS * RECORD_SIZE);
+    for (int i = 0; i < 2 * NRECORDS; i++) {
+        CHECK(read(fd, record, RECORD_SIZE) == RECORD_SIZE);
+        for (int j = 1; j < RECORD_SIZE; j++) {
+            whole = whole && record[j] == record[0];
+        }
+    }
+    CHECK(whole);
+    close(fd);
+
+    CHECK(sys_unlink("log") == 0);
+}
+
+void umain(int argc, char **argv) {
+    if (sys_mkdir(TEST_DIR) < 0 || sys_chdir(TEST_DIR) < 0) {
+        printf("trunctest: cannot make %s\n", TEST_DIR);
+        return;
+    }
+
+    test_unlink_open();
+    test_append_trunc();
+    test_append_concurrent();
+
+    sys_chdir("..");
+    CHECK(sys_rmdir(TEST_DIR) == 0);
+
+    if (nfailed > 0) {
+        printf("trunctest: %d checks failed\n", nfailed);
+    } else {
+        printf("trunctest: OK\n");
+    }
+}
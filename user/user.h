@@ -11,7 +11,102 @@
 #define O_RDONLY 0x000
 #define O_WRONLY 0x001
 #define O_RDWR   0x002
+#define O_APPEND 0x004
 #define O_CREAT  0x200
+#define O_TRUNC  0x400
+#define O_NOFOLLOW 0x800
+#define O_EXCL   0x1000
+
+// for sys_lseek
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+// for sys_getrusage
+#define RUSAGE_SELF 0
+#define RUSAGE_CHILDREN (-1)
+
+// FIXME: the same definition is in src/env.rs
+struct rusage {
+    unsigned long long utime; // time spent in user mode (tsc ticks)
+    unsigned long long stime; // time spent in kernel mode (tsc ticks)
+    unsigned int minflt; // number of page faults
+    unsigned int nvcsw; // number of context switches
+    unsigned int inblock; // number of blocks read from disk
+    unsigned int oublock; // number of blocks written to disk
+};
+
+// for sys_getrlimit and sys_setrlimit
+// FIXME: the same definition is in src/rlimit.rs
+#define RLIMIT_NOFILE 0 // number of open file descriptors
+#define RLIMIT_HEAP 1 // heap size in bytes
+#define RLIMIT_STACK 2 // stack size in bytes
+#define RLIMIT_NCHILD 3 // number of children
+#define RLIMIT_CPU 4 // cpu time (tsc ticks)
+#define RLIM_INFINITY (~0ULL)
+
+struct rlimit {
+    unsigned long long cur; // soft limit
+    unsigned long long max; // hard limit
+};
+
+// status of env
+// FIXME: the same definition is in src/env.rs
+#define ENV_FREE 0
+#define ENV_DYING 1
+#define ENV_RUNNABLE 2
+#define ENV_RUNNING 3
+#define ENV_ZOMBIE 4
+#define ENV_NOT_RUNNABLE 5
+#define ENV_NSTATUS 6
+
+#define ENV_NAME_LEN 16
+
+// for sys_env_list
+// FIXME: the same definition is in src/env.rs
+struct env_info {
+    unsigned int env_id;
+    unsigned int parent_id;
+    unsigned int status;
+    unsigned int runs;
+    unsigned int heap_size;
+    unsigned int cwd_inum;
+    unsigned int rss; // resident pages
+    char name[ENV_NAME_LEN];
+};
+
+// for sys_sysinfo
+// FIXME: the same definition is in src/slab.rs
+#define NCACHES 9
+#define CACHE_NAME_LEN 12
+
+// FIXME: the same definition is in src/sysinfo.rs
+struct cache_info {
+    char name[CACHE_NAME_LEN];
+    unsigned int obj_size; // in bytes
+    unsigned int in_use; // objects allocated and not freed yet
+    unsigned int cached; // free objects
+    unsigned int pages; // pages owned by the cache
+};
+
+// sizes are in pages unless otherwise noted
+// FIXME: the same definition is in src/sysinfo.rs
+struct sysinfo {
+    unsigned int total_pages; // physical pages managed by the kernel
+    unsigned int free_pages;
+    unsigned int high_pages; // physical pages in high memory
+    unsigned int free_high_pages;
+    unsigned int kheap_size; // bytes mapped for the kernel heap
+    unsigned int kheap_used; // bytes used in the kernel heap
+    unsigned int bcache_used; // buffers in the buffer cache
+    unsigned int bcache_size; // maximum number of buffers
+    unsigned int swap_total;
+    unsigned int swap_used;
+    unsigned int swap_ins;
+    unsigned int swap_outs;
+    unsigned int envs[ENV_NSTATUS]; // number of envs in each status
+    struct cache_info caches[NCACHES]; // object caches of the kernel heap
+};
 
 // file descriptors
 #define STDIN_FILENO 0
@@ -42,11 +137,29 @@ char *sys_getcwd(char *buf, unsigned int usize);
 int sys_mkdir(char *path);
 int sys_chdir(char *path);
 int sys_pipe(int pipefd[2]);
+int sys_getrusage(int who, struct rusage *usage);
+int sys_env_list(struct env_info *buf, int n);
+int sys_getrlimit(int resource, struct rlimit *rlim);
+int sys_setrlimit(int resource, const struct rlimit *rlim);
+int sys_sysinfo(struct sysinfo *info);
+int sys_link(char *oldpath, char *newpath);
+int sys_unlink(char *path);
+int sys_rmdir(char *path);
+int sys_rename(char *oldpath, char *newpath);
+int sys_symlink(char *target, char *linkpath);
+int sys_readlink(char *path, char *buf, unsigned int size);
+int sys_lstat(char *path, struct stat *statbuf);
+int sys_lseek(int fd, int offset, int whence);
+int sys_pread(int fd, char *buf, int count, int offset);
+int sys_pwrite(int fd, char *buf, int count, int offset);
+int sys_ftruncate(int fd, int length);
+int sys_getdents(int fd, char *buf, int size);
 
 size_t strlen(const char *s);
 size_t strnlen(const char *s, size_t maxlen);
 char *strchr(const char *s, char c);
 char *strcpy(char *dest, const char *src);
+int strcmp(const char *s1, const char *s2);
 void exit(int status);
 void *memset(void *s, int c, size_t n);
 void *memmove(void *dest, const void *src, size_t n);